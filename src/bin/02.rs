@@ -1,6 +1,7 @@
 use aoc::intcode::*;
+use aoc::intcode::symbolic::{Goal, Symbolic};
 
-fn part1(input: &str) -> i64 {
+fn part1(input: &str) -> Number {
     let mut program = Program::parse(input);
     program.mem[1] = 12;
     program.mem[2] = 2;
@@ -8,23 +9,13 @@ fn part1(input: &str) -> i64 {
     program.mem[0]
 }
 
-fn part2(input: &str) -> i64 {
-    let program = Program::parse(input);
-    let mut max = 1;
-    loop {
-        for noun in 0..max {
-            for verb in 0..max {
-                let mut p = program.clone();
-                p.mem[1] = noun;
-                p.mem[2] = verb;
-                p = p.run_without_io();
-                if p.mem[0] == 19690720 {
-                    return 100 * noun + verb;
-                }
-            }
-        }
-        max *= 10;
-    }
+fn part2(input: &str) -> Number {
+    let solution = Symbolic::new(Program::parse(input))
+        .with_cell("noun", 1, 0..=99)
+        .with_cell("verb", 2, 0..=99)
+        .solve(Goal::Cell(0), 19690720);
+    let values = solution.values.expect("No noun and verb found");
+    100 * values[0] + values[1]
 }
 
 fn main() {
@@ -1,19 +1,8 @@
 use aoc::intcode::*;
-use aoc::permute::*;
+use aoc::intcode::pipeline::*;
 
 fn part1(input: &str) -> Number {
-    let program = Program::parse(input);
-    (0..=4)
-        .permute()
-        .map(|p| {
-            let mut output = 0;
-            for i in 0..5 {
-                output = program.clone().run_with_io(vec![p[i], output])[0];
-            }
-            output
-        })
-        .max()
-        .unwrap()
+    search_phases(&Program::parse(input), (0..=4).collect(), false).0
 }
 
 #[test]
@@ -24,34 +13,7 @@ fn test_part1() {
 }
 
 fn part2(input: &str) -> Number {
-    let program = Program::parse(input);
-    (5..=9)
-        .permute()
-        .map(|p| {
-            let mut programs = p.iter().map(|&i| {
-                let mut p = program.clone();
-                p.give_input(i);
-                p
-            }).collect::<Vec<_>>();
-
-            let mut output = 0;
-            let mut i = 0;
-            loop {
-                let program = &mut programs[i];
-                if program.is_halted() {
-                    break;
-                }
-                program.give_input(output);
-                if program.is_halted() {
-                    break;
-                }
-                output = program.take_output();
-                i = (i + 1) % 5;
-            }
-            output
-        })
-        .max()
-        .unwrap()
+    search_phases(&Program::parse(input), (5..=9).collect(), true).0
 }
 
 #[test]
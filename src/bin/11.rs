@@ -1,4 +1,5 @@
 use aoc::intcode::*;
+use aoc::intcode::device::{Control, IntcodeInput, IntcodeOutput};
 use euclid;
 use itertools::Itertools;
 use std::collections::HashMap;
@@ -10,30 +11,50 @@ type Hull = HashMap<Point, Number>;
 const BLACK: Number = 0;
 const WHITE: Number = 1;
 
-fn paint(input: &str, hull: &mut Hull) {
-    let mut program = Program::parse(input);
-    let mut pos = Point::new(0, 0);
-    let mut dir = 0;
-    while !program.is_halted() {
-        program.give_input(*hull.get(&pos).unwrap_or(&BLACK));
-        let paint_color = program.take_output();
-        hull.insert(pos, paint_color);
-        let rotation = program.take_output();
-        dir = match rotation {
-            0 => (dir + 4 - 1) % 4,
-            1 => (dir + 1) % 4,
-            _ => panic!("Invalid rotation direction {}", rotation),
+/// The painting robot: its camera reads the hull below it, and each pair of outputs paints a
+/// panel and turns the robot.
+struct Robot<'a> {
+    hull: &'a mut Hull,
+    pos: Point,
+    dir: i32,
+    painted: bool,
+}
+
+impl IntcodeInput for Robot<'_> {
+    fn next_input(&mut self) -> Option<Number> {
+        Some(*self.hull.get(&self.pos).unwrap_or(&BLACK))
+    }
+}
+
+impl IntcodeOutput for Robot<'_> {
+    fn output(&mut self, val: Number) -> Control {
+        if !self.painted {
+            self.hull.insert(self.pos, val);
+            self.painted = true;
+            return Control::Continue;
+        }
+        self.painted = false;
+        self.dir = match val {
+            0 => (self.dir + 4 - 1) % 4,
+            1 => (self.dir + 1) % 4,
+            _ => panic!("Invalid rotation direction {}", val),
         };
-        match dir {
-            0 => pos.y -= 1,
-            1 => pos.x += 1,
-            2 => pos.y += 1,
-            3 => pos.x -= 1,
-            _ => panic!("Invalid rotation {}", dir),
+        match self.dir {
+            0 => self.pos.y -= 1,
+            1 => self.pos.x += 1,
+            2 => self.pos.y += 1,
+            3 => self.pos.x -= 1,
+            _ => panic!("Invalid rotation {}", self.dir),
         }
+        Control::Continue
     }
 }
 
+fn paint(input: &str, hull: &mut Hull) {
+    let mut robot = Robot { hull, pos: Point::new(0, 0), dir: 0, painted: false };
+    Program::parse(input).run_with_device(&mut robot);
+}
+
 fn hull_to_string(hull: &Hull) -> String {
     let (x_min, x_max) = hull.keys().map(|p| p.x).minmax().into_option().unwrap();
     let (y_min, y_max) = hull.keys().map(|p| p.y).minmax().into_option().unwrap();
@@ -1,4 +1,5 @@
 use aoc::intcode::*;
+use aoc::intcode::device::{Control, IntcodeInput, IntcodeOutput};
 use euclid;
 use itertools::Itertools;
 use num_derive::FromPrimitive;
@@ -6,7 +7,7 @@ use num_traits::cast::FromPrimitive;
 use std::collections::HashMap;
 
 struct Grid;
-type Point = euclid::Point2D<i64, Grid>;
+type Point = euclid::Point2D<Number, Grid>;
 type Screen = HashMap::<Point, Tile>;
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, FromPrimitive)]
@@ -52,62 +53,80 @@ fn part1(input: &str) -> usize {
         let x = program.take_output();
         let y = program.take_output();
         let val = program.take_output();
-        screen.insert(Point::new(x, y), Tile::from_i64(val).expect("Invalid tile value"));
+        screen.insert(Point::new(x, y), Tile::from_i128(val.into()).expect("Invalid tile value"));
     }
     // println!("{}", screen_to_string(&screen));
     screen.values().filter(|&&cell| cell == Tile::Block).count()
 }
 
-#[allow(unused_assignments)]
-fn part2(input: &str) -> Number {
-    let mut program = Program::parse(input);
-    program.mem[0] = 2;
-    let mut screen = Screen::new();
-    let mut paddle_pos = Point::default();
-    let mut ball_pos = Point::default();
-    let mut score = 0;
-    let mut first_render = true;
-    loop {
-        let mut render = false;
-        match program.run_until_interrupt() {
-            Interrupt::Reading => {
-                render = true;
-                program.give_input((ball_pos.x - paddle_pos.x).signum());
-            },
-            Interrupt::Writing => {
-                let x = program.take_output();
-                let y = program.take_output();
-                let val = program.take_output();
-                if (x, y) == (-1, 0) {
-                    score = val;
-                } else {
-                    let pos = Point::new(x, y);
-                    let tile = Tile::from_i64(val).expect("Invalid tile value");
-                    screen.insert(pos, tile);
-                    if tile == Tile::HorizontalPaddle {
-                        paddle_pos = pos;
-                    } else if tile == Tile::Ball {
-                        ball_pos = pos;
-                    }
-                }
-            },
-            Interrupt::Halted => {
-                render = true;
-                break;
-            }
-        }
-        if cfg!(feature = "render") && render {
+/// The arcade cabinet: draws each triple of outputs, and moves the joystick towards the ball.
+struct Arcade {
+    screen: Screen,
+    paddle_pos: Point,
+    ball_pos: Point,
+    score: Number,
+    pending: Vec<Number>,
+    first_render: bool,
+}
+
+impl Arcade {
+    fn render(&mut self) {
+        if cfg!(feature = "render") {
             print!("{}[2J", 27 as char);
-            println!("{}\n", screen_to_string(&screen));
-            println!("  Score: {}\n", score);
+            println!("{}\n", screen_to_string(&self.screen));
+            println!("  Score: {}\n", self.score);
             std::thread::sleep(std::time::Duration::from_millis(33));
-            if first_render {
+            if self.first_render {
                 std::thread::sleep(std::time::Duration::from_millis(10000));
-                first_render = false;
+                self.first_render = false;
             }
         }
     }
-    score
+}
+
+impl IntcodeInput for Arcade {
+    fn next_input(&mut self) -> Option<Number> {
+        self.render();
+        Some((self.ball_pos.x - self.paddle_pos.x).signum())
+    }
+}
+
+impl IntcodeOutput for Arcade {
+    fn output(&mut self, val: Number) -> Control {
+        self.pending.push(val);
+        if let [x, y, val] = self.pending[..] {
+            self.pending.clear();
+            if (x, y) == (-1, 0) {
+                self.score = val;
+            } else {
+                let pos = Point::new(x, y);
+                let tile = Tile::from_i128(val.into()).expect("Invalid tile value");
+                self.screen.insert(pos, tile);
+                if tile == Tile::HorizontalPaddle {
+                    self.paddle_pos = pos;
+                } else if tile == Tile::Ball {
+                    self.ball_pos = pos;
+                }
+            }
+        }
+        Control::Continue
+    }
+}
+
+fn part2(input: &str) -> Number {
+    let mut program = Program::parse(input);
+    program.mem[0] = 2;
+    let mut arcade = Arcade {
+        screen: Screen::new(),
+        paddle_pos: Point::default(),
+        ball_pos: Point::default(),
+        score: 0,
+        pending: vec![],
+        first_render: true,
+    };
+    program.run_with_device(&mut arcade);
+    arcade.render();
+    arcade.score
 }
 
 fn main() {
@@ -1,4 +1,5 @@
 use aoc::intcode::*;
+use aoc::intcode::ascii::AsciiSession;
 use euclid;
 use itertools::Itertools;
 
@@ -163,8 +164,7 @@ fn test_alignment_parameters_sum() {
 }
 
 fn part1(input: &str) -> i64 {
-    let output = Program::parse(input).run_with_io(vec![]);
-    let ascii = String::from_utf8(output.iter().map(|&val| val as u8).collect()).unwrap();
+    let ascii = AsciiSession::new(Program::parse(input)).read_output();
     let state = State::parse(&ascii);
     alignment_parameters_sum(&state)
 }
@@ -323,16 +323,19 @@ fn test_path_to_functions() {
 
 fn part2(input: &str) -> Number {
     let mut program = Program::parse(input);
-    let output = program.clone().run_with_io(vec![]);
-    let ascii = String::from_utf8(output.iter().map(|&val| val as u8).collect()).unwrap();
+    let ascii = AsciiSession::new(program.clone()).read_output();
     let state = State::parse(&ascii);
     let path = trace_path(&state);
 
     let functions = path_to_functions(&path);
     program.mem[0] = 2;
-    let input = (functions + "n\n").as_bytes().iter().map(|&c| c as Number).collect();
-    let output = program.run_with_io(input);
-    *output.last().unwrap()
+    let mut session = AsciiSession::new(program);
+    for line in functions.lines() {
+        session.send_line(line);
+    }
+    session.send_line("n");
+    session.read_output();
+    session.result().expect("No dust collected")
 }
 
 fn main() {
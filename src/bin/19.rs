@@ -54,7 +54,7 @@ fn part1(input: &str) -> usize {
     BeamTracer::new(program).take(50).map(|slice| (slice.ys.end - slice.ys.start) as usize).sum()
 }
 
-fn part2(input: &str) -> i64 {
+fn part2(input: &str) -> Number {
     let program = Program::parse(input);
     let mut y_ends = Vec::new();
     for slice in BeamTracer::new(program) {
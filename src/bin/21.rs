@@ -1,19 +1,23 @@
 use aoc::intcode::*;
+use aoc::intcode::ascii::AsciiSession;
+
+/// Runs the springdroid program and returns the hull damage it reports.
+fn run_springdroid(input: &str, springcode: &str) -> Number {
+    let mut session = AsciiSession::new(Program::parse(input));
+    for line in springcode.lines() {
+        session.send_line(line);
+    }
+    let output = session.read_output();
+    session.result().unwrap_or_else(|| panic!("Fell into a hole:\n{}", output))
+}
 
 fn part1(input: &str) -> Number {
-    let mut program = Program::parse(input);
-    let springcode = "NOT A J
+    run_springdroid(input, "NOT A J
 NOT C T
 AND D T
 OR T J
 WALK
-".as_bytes().iter().map(|&c| c as Number).collect::<Vec<_>>();
-    let output = program.run_with_io(springcode);
-    if *output.last().unwrap() >= 128 {
-        return *output.last().unwrap()
-    } else {
-        panic!("Fell into a hole:\n{}", output.iter().map(|&val| val as u8 as char).collect::<String>());
-    }
+")
 }
 
 #[cfg(test)]
@@ -158,13 +162,7 @@ fn test_springcode() {
 }
 
 fn part2(input: &str) -> Number {
-    let mut program = Program::parse(input);
-    let output = program.run_with_io(SPRINGCODE_AD_HOC.as_bytes().iter().map(|&c| c as Number).collect::<Vec<_>>());
-    if *output.last().unwrap() >= 128 {
-        return *output.last().unwrap()
-    } else {
-        panic!("{}", output.iter().map(|&val| val as u8 as char).collect::<String>());
-    }
+    run_springdroid(input, SPRINGCODE_AD_HOC)
 }
 
 fn main() {
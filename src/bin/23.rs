@@ -1,97 +1,68 @@
 use aoc::intcode::*;
-use std::collections::VecDeque;
+use aoc::intcode::network::*;
 
 const N: usize = 50;
 
-#[derive(Debug, Default, Clone)]
-struct Packet {
-    x: Number,
-    y: Number,
+struct FirstPacketNat {
+    y: Option<Number>,
 }
 
-type PacketQueue = VecDeque<Packet>;
-
-fn tick_programs(programs: &mut Vec<Program>, input_queues: &mut Vec<PacketQueue>, nat: &mut Option<Packet>) -> bool {
-    let mut progress = false;
-    for i in 0..N {
-        let program = &mut programs[i];
-        match program.run_until_interrupt() {
-            Interrupt::Reading => {
-                if let Some(packet) = input_queues[i].pop_front() {
-                    progress = true;
-                    program.give_input(packet.x);
-                    program.give_input(packet.y);
-                } else {
-                    program.give_input(-1);
-                }
-            },
-            Interrupt::Writing => {
-                progress = true;
-                let dest = program.take_output();
-                let x = program.take_output();
-                let y = program.take_output();
-                let packet = Packet { x, y };
-                match dest {
-                    dest if dest >= 0 && dest < N as Number => {
-                        input_queues[dest as usize].push_back(packet);
-                    },
-                    255 => {
-                        *nat = Some(packet);
-                    },
-                    _ => { panic!("Unexpected destination address {}", dest); },
-                }
-            },
-            Interrupt::Halted => panic!("Program halted unexpectedly"),
+impl Nat for FirstPacketNat {
+    fn receive(&mut self, packet: Packet) -> NatAction {
+        if packet.dest != 255 {
+            panic!("Unexpected destination address {}", packet.dest);
         }
+        self.y = Some(packet.payload[1]);
+        NatAction::Stop
+    }
+
+    fn idle(&mut self) -> NatAction {
+        NatAction::Continue
     }
-    progress
 }
 
 fn part1(input: &str) -> Number {
-    let program = Program::parse(input);
-    let mut programs: Vec<Program> = (0..N)
-        .map(|i| {
-            let mut p = program.clone();
-            p.give_input(i as Number);
-            p
-        })
-        .collect();
-    let mut input_queues: Vec<PacketQueue> = (0..N).map(|_| PacketQueue::new()).collect();
-    let mut nat = None;
-    loop {
-        tick_programs(&mut programs, &mut input_queues, &mut nat);
-        if let Some(packet) = nat {
-            return packet.y;
+    let mut nat = FirstPacketNat { y: None };
+    Network::boot(&Program::parse(input), N).run(&mut nat);
+    nat.y.unwrap()
+}
+
+#[derive(Default)]
+struct WakingNat {
+    packet: Option<Packet>,
+    last_y_sent: Option<Number>,
+    repeated_y: Option<Number>,
+}
+
+impl Nat for WakingNat {
+    fn receive(&mut self, packet: Packet) -> NatAction {
+        if packet.dest != 255 {
+            panic!("Unexpected destination address {}", packet.dest);
         }
+        self.packet = Some(packet);
+        NatAction::Continue
     }
-}
 
-fn part2(input: &str) -> Number {
-    let program = Program::parse(input);
-    let mut programs: Vec<Program> = (0..N)
-        .map(|i| {
-            let mut p = program.clone();
-            p.give_input(i as Number);
-            p
-        })
-        .collect();
-    let mut input_queues: Vec<PacketQueue> = (0..N).map(|_| PacketQueue::new()).collect();
-    let mut nat = None;
-    let mut last_y_sent = None;
-    loop {
-        let progress = tick_programs(&mut programs, &mut input_queues, &mut nat);
-        if !progress {
-            if let Some(packet) = nat.as_ref() {
-                if Some(packet.y) == last_y_sent {
-                    return packet.y;
-                }
-                last_y_sent = Some(packet.y);
-                input_queues[0].push_back((*packet).clone());
+    fn idle(&mut self) -> NatAction {
+        if let Some(packet) = self.packet.as_ref() {
+            let y = packet.payload[1];
+            if Some(y) == self.last_y_sent {
+                self.repeated_y = Some(y);
+                return NatAction::Stop;
             }
+            self.last_y_sent = Some(y);
+            return NatAction::Send(Packet { dest: 0, payload: packet.payload.clone() });
         }
+        NatAction::Continue
     }
 }
 
+fn part2(input: &str) -> Number {
+    let mut nat = WakingNat::default();
+    Network::boot(&Program::parse(input), N).run(&mut nat);
+    nat.repeated_y.unwrap()
+}
+
 fn main() {
     aoc::main(part1, part2);
 }
@@ -1,4 +1,5 @@
 use aoc::intcode::*;
+use aoc::intcode::ascii::AsciiSession;
 use std::collections::HashSet;
 
 #[derive(Debug, PartialEq, Eq)]
@@ -10,9 +11,12 @@ enum State {
 
 use State::*;
 
+/// Number of instructions a command may take before we assume the game is stuck in a loop.
+const COMMAND_FUEL: u64 = 1_000_000;
+
 #[derive(Debug, Clone)]
 struct Adventure {
-    program: Program,
+    session: AsciiSession,
     print: bool,
     location: String,
     doors_here: Vec<String>,
@@ -24,7 +28,7 @@ struct Adventure {
 impl Adventure {
     fn new(program: Program, print: bool) -> Self {
         let mut adventure = Adventure {
-            program,
+            session: AsciiSession::new(program),
             print,
             location: "".to_string(),
             doors_here: Vec::new(),
@@ -32,12 +36,12 @@ impl Adventure {
             inventory: Vec::new(),
             code: None,
         };
-        adventure.parse_output();
+        let output = adventure.session.read_output();
+        adventure.parse_output(&output);
         adventure
     }
 
-    fn parse_output(&mut self) {
-        let output = self.program.take_output_ascii();
+    fn parse_output(&mut self, output: &str) {
         if self.print {
             print!("{}", output);
         }
@@ -78,12 +82,40 @@ impl Adventure {
     }
 
     fn run_command(&mut self, command: &str) {
-        let input = command.to_string() + "\n";
         if self.print {
-            print!("{}", input);
+            println!("{}", command);
+        }
+        self.session.send_line(command);
+        let output = self.session.read_output();
+        self.parse_output(&output);
+    }
+
+    /// Runs a command with a limited budget. Returns whether the game is waiting for the next
+    /// command afterwards, rather than having ended or being stuck in a loop.
+    fn try_command(&mut self, command: &str) -> bool {
+        self.session.program_mut().set_fuel(Some(COMMAND_FUEL));
+        if self.session.try_send_line(command).is_err() {
+            return false;
+        }
+        match self.session.try_read_output() {
+            Ok(output) => self.parse_output(&output),
+            Err(_) => return false,
+        }
+        self.session.program_mut().run_until_interrupt() == Interrupt::Reading
+    }
+
+    /// Tries taking the item on a copy of the game. It is safe if the game goes on afterwards, and
+    /// we can still move.
+    fn is_safe_to_take(&self, item: &str) -> bool {
+        let mut adventure = self.clone();
+        adventure.print = false;
+        if !adventure.try_command(&format!("take {}", item)) {
+            return false;
+        }
+        match self.doors_here.first() {
+            Some(door) => adventure.try_command(door) && adventure.location != self.location,
+            None => true,
         }
-        self.program.give_input_ascii(&input);
-        self.parse_output();
     }
 }
 
@@ -95,7 +127,7 @@ fn collect_items(adventure: &mut Adventure, visited: &mut HashSet<String>, stack
 
     let items = adventure.items_here.clone();
     for item in items {
-        if item != "infinite loop" && item != "molten lava" && item != "photons" && item != "escape pod" && item != "giant electromagnet" {
+        if adventure.is_safe_to_take(&item) {
             adventure.run_command(&format!("take {}", item));
         }
     }
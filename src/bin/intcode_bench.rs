@@ -0,0 +1,33 @@
+use aoc::intcode::*;
+use aoc::intcode::cached::CachedProgram;
+use std::time::{Duration, Instant};
+
+const RUNS: u32 = 10;
+
+fn time<F: FnMut() -> Vec<Number>>(mut f: F) -> (Vec<Number>, Duration) {
+    let output = f();
+    let start = Instant::now();
+    for _ in 0..RUNS {
+        f();
+    }
+    (output, start.elapsed() / RUNS)
+}
+
+fn main() {
+    let mut args = std::env::args();
+    args.next().unwrap();
+    let file_name = args.next().expect("Give file name of intcode program on the command line");
+    let input = args
+        .map(|arg| arg.parse::<Number>().expect("Inputs must be numbers"))
+        .collect::<Vec<_>>();
+    let program = Program::parse(&std::fs::read_to_string(&file_name).expect("Could not read intcode program"));
+
+    let (interpreted, interpreted_time) = time(|| program.clone().run_with_io(input.clone()));
+    let (cached, cached_time) = time(|| CachedProgram::new(program.clone()).run_with_io(input.clone()));
+    assert_eq!(cached, interpreted, "Engines disagree on the output");
+
+    println!("Output: {:?}", interpreted);
+    println!("Interpreter: {:>10.3} ms", interpreted_time.as_secs_f64() * 1000.0);
+    println!("Cached:      {:>10.3} ms ({:.2}x)", cached_time.as_secs_f64() * 1000.0,
+        interpreted_time.as_secs_f64() / cached_time.as_secs_f64());
+}
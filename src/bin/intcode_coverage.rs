@@ -0,0 +1,25 @@
+use aoc::intcode::*;
+use aoc::intcode::coverage::Coverage;
+
+fn main() {
+    let mut args = std::env::args().skip(1).peekable();
+    let lcov = args.peek().map(String::as_str) == Some("--lcov");
+    if lcov {
+        args.next();
+    }
+    let file_name = args.next().expect("Give file name of intcode program on the command line");
+    let input = args
+        .map(|arg| arg.parse::<Number>().expect("Inputs must be numbers"))
+        .collect::<Vec<_>>();
+    let mem = Memory::parse(&std::fs::read_to_string(&file_name).expect("Could not read intcode program"));
+
+    let mut program = Program::new(mem.clone()).with_tracer(Coverage::new());
+    if let Err(err) = program.try_run_with_io(input) {
+        eprintln!("{}", err);
+    }
+    if lcov {
+        print!("{}", program.tracer().lcov(&mem, &format!("{}.asm", file_name)));
+    } else {
+        print!("{}", program.tracer().annotate(&mem));
+    }
+}
@@ -0,0 +1,9 @@
+use aoc::intcode::*;
+
+fn main() {
+    let mut args = std::env::args();
+    args.next().unwrap();
+    let file_name = args.next().expect("Give file name of intcode program on the command line");
+    let mem = Memory::parse(&std::fs::read_to_string(&file_name).expect("Could not read intcode program"));
+    print!("{}", decompile::decompile(&mem));
+}
@@ -1,29 +1,600 @@
 use aoc::intcode::*;
+use aoc::intcode::disasm::Instruction;
+use aoc::intcode::history::History;
+use aoc::intcode::trace::Step;
+use std::collections::{BTreeSet, VecDeque};
 use std::io::{BufRead, Write};
 
+const HELP: &str = "\
+Lines not starting with ':' are sent to the program as input: as ASCII, or in numeric mode as
+integers separated by spaces or commas.
+Debugger commands:
+  :break ADDR | :break op OPCODE    set a breakpoint on an address or opcode
+  :clear ADDR | :clear op OPCODE    clear a breakpoint (or all, without argument)
+  :watch ADDR | :unwatch ADDR       stop when an address is written
+  :step [N]                         execute N instructions (default 1)
+  :continue                         run until a breakpoint, watchpoint or input
+  :input                            run until the next input instruction
+  :output                           run until the next output instruction
+  :record [N] | :record off         keep the last N instructions (default 100000) for going back
+  :back [N]                         undo N instructions (default 1)
+  :rcontinue                        go back to a breakpoint or watchpoint, or the start of the record
+  :whowrote ADDR                    go back to the instruction that last wrote an address
+  :regs                             print registers
+  :dump START [END]                 print memory from START up to and including END
+  :poke ADDR VALUE...               write values to memory starting at ADDR
+  :save FILE | :load FILE           save or restore a snapshot of the program
+  :io ascii|numeric|auto            select how input and output are converted
+  :help                             print this help";
+
+/// Number of instructions kept by `:record` without an argument.
+const DEFAULT_RECORD_CAPACITY: usize = 100_000;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum RunMode {
+    Step(usize),
+    Continue,
+    UntilInput,
+    UntilOutput,
+}
+
+/// How lines of input and output values are converted.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum IoMode {
+    /// Lines are sent as ASCII codes, and outputs printed as characters.
+    Ascii,
+    /// Lines are parsed as integers, and outputs printed as integers, one per line.
+    Numeric,
+    /// Like `Ascii`, but outputs outside the ASCII range are printed as integers.
+    Auto,
+}
+
+impl IoMode {
+    fn parse(name: &str) -> Option<IoMode> {
+        match name {
+            "ascii" => Some(IoMode::Ascii),
+            "numeric" => Some(IoMode::Numeric),
+            "auto" => Some(IoMode::Auto),
+            _ => None,
+        }
+    }
+
+    fn write_output(self, out: &mut impl Write, val: Number) {
+        match self {
+            IoMode::Ascii => write!(out, "{}", val as u8 as char),
+            IoMode::Auto if (0..=127).contains(&val) => write!(out, "{}", val as u8 as char),
+            IoMode::Numeric | IoMode::Auto => writeln!(out, "{}", val),
+        }.unwrap();
+    }
+}
+
+/// Parses integers separated by whitespace or commas.
+fn parse_numbers(text: &str) -> Result<Vec<Number>, String> {
+    text.split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|word| !word.is_empty())
+        .map(|word| word.parse::<Number>().map_err(|_| format!("Invalid number {}", word)))
+        .collect()
+}
+
+#[derive(Debug)]
+enum Stop {
+    Breakpoint,
+    Watchpoint(Addr),
+    Steps,
+    Input,
+    Output,
+    Halted,
+    Fault(IntcodeError),
+}
+
+struct Debugger {
+    program: Program<History>,
+    input: VecDeque<Number>,
+    io: IoMode,
+    breakpoints: BTreeSet<Addr>,
+    opcode_breakpoints: BTreeSet<Number>,
+    watchpoints: BTreeSet<Addr>,
+}
+
+impl Debugger {
+    fn new(program: Program) -> Self {
+        Debugger {
+            program: program.with_tracer(History::new(0)),
+            input: VecDeque::new(),
+            io: IoMode::Ascii,
+            breakpoints: BTreeSet::new(),
+            opcode_breakpoints: BTreeSet::new(),
+            watchpoints: BTreeSet::new(),
+        }
+    }
+
+    /// The address that the next instruction will write to, if any.
+    fn write_addr(&self) -> Option<Addr> {
+        let instr = Instruction::decode(&self.program.mem, self.program.ip())?;
+        if !instr.op.has_output() {
+            return None;
+        }
+        let dest = instr.operands.last().unwrap();
+        let addr = match dest.mode {
+            ArgMode::Position => Some(dest.value),
+            // The program faults with an overflow instead.
+            ArgMode::Relative => dest.value.checked_add(self.program.relative_base()),
+            ArgMode::Immediate => None,
+        }?;
+        if addr >= 0 { Some(to_addr(addr)) } else { None }
+    }
+
+    fn at_breakpoint(&self) -> bool {
+        let ip = self.program.ip();
+        self.breakpoints.contains(&ip) || self.opcode_breakpoints.contains(&(self.program.mem[ip] % 100))
+    }
+
+    fn run(&mut self, mode: RunMode, out: &mut impl Write) -> Stop {
+        let mut steps = 0;
+        loop {
+            if steps > 0 && self.at_breakpoint() {
+                return Stop::Breakpoint;
+            }
+            let watched = if self.watchpoints.is_empty() {
+                None
+            } else {
+                self.write_addr().filter(|addr| self.watchpoints.contains(addr))
+            };
+            match self.program.try_step() {
+                Err(err) => return Stop::Fault(err),
+                Ok(None) => {},
+                Ok(Some(Interrupt::Reading)) => {
+                    if mode == RunMode::UntilInput {
+                        return Stop::Input;
+                    }
+                    match self.input.pop_front() {
+                        Some(val) => if let Err(err) = self.program.try_give_input(val) {
+                            return Stop::Fault(err);
+                        },
+                        None => return Stop::Input,
+                    }
+                },
+                Ok(Some(Interrupt::Writing)) => {
+                    if mode == RunMode::UntilOutput {
+                        return Stop::Output;
+                    }
+                    match self.program.try_take_output() {
+                        Ok(val) => self.io.write_output(out, val),
+                        Err(err) => return Stop::Fault(err),
+                    }
+                },
+                Ok(Some(Interrupt::Halted)) => return Stop::Halted,
+                Ok(Some(Interrupt::OutOfFuel)) | Ok(Some(Interrupt::Timeout)) => unreachable!("No budget is set"),
+                Ok(Some(Interrupt::Custom(_))) => unreachable!("No custom opcodes are registered"),
+            }
+            steps += 1;
+            if let Some(addr) = watched {
+                return Stop::Watchpoint(addr);
+            }
+            if let RunMode::Step(n) = mode {
+                if steps >= n {
+                    return Stop::Steps;
+                }
+            }
+        }
+    }
+
+    fn recording(&self) -> bool {
+        self.program.tracer().capacity() > 0
+    }
+
+    /// Queues a line of input for the program.
+    fn send_line(&mut self, line: &str) -> Result<(), String> {
+        match self.io {
+            IoMode::Numeric => self.input.extend(parse_numbers(line)?),
+            IoMode::Ascii | IoMode::Auto => self.input.extend(line.bytes().chain(std::iter::once(b'\n')).map(|b| b as Number)),
+        }
+        Ok(())
+    }
+
+    /// Undoes instructions until `stop` returns true for one of them. Undone input is queued again.
+    fn run_back<P>(&mut self, mut stop: P) -> Option<Step>
+        where P: FnMut(&Step) -> bool
+    {
+        let input = &mut self.input;
+        self.program.run_back_until(|step| {
+            if let (3, Some(write)) = (step.opcode, step.write) {
+                input.push_front(write.new);
+            }
+            stop(step)
+        })
+    }
+
+    fn print_back(&self, step: Option<Step>) {
+        if step.is_none() {
+            eprintln!("[start of record]");
+        }
+        eprintln!("{}", self.current_instruction());
+    }
+
+    fn current_instruction(&self) -> String {
+        let ip = self.program.ip();
+        match Instruction::decode(&self.program.mem, ip) {
+            Some(instr) => format!("{:04}: {}", ip, instr),
+            None => format!("{:04}: DATA {}", ip, self.program.mem[ip]),
+        }
+    }
+
+    /// Executes a debugger command, returning how to resume the program, if at all.
+    fn command(&mut self, command: &str) -> Result<Option<RunMode>, String> {
+        let mut words = command.split_whitespace();
+        let name = words.next().unwrap_or("");
+        let args = words.collect::<Vec<_>>();
+        let num = |i: usize| -> Result<Number, String> {
+            let arg = args.get(i).ok_or_else(|| format!("Missing argument for :{}", name))?;
+            arg.parse::<Number>().map_err(|_| format!("Invalid number {}", arg))
+        };
+        let addr = |i: usize| -> Result<Addr, String> {
+            let n = num(i)?;
+            if n < 0 {
+                return Err(format!("Invalid address {}", n));
+            }
+            Ok(to_addr(n))
+        };
+        let count = |i: usize| -> Result<usize, String> {
+            let arg = args.get(i).ok_or_else(|| format!("Missing argument for :{}", name))?;
+            arg.parse::<usize>().map_err(|_| format!("Invalid count {}", arg))
+        };
+        match name {
+            "break" | "b" => {
+                if args.first() == Some(&"op") {
+                    self.opcode_breakpoints.insert(num(1)?);
+                } else {
+                    self.breakpoints.insert(addr(0)?);
+                }
+            },
+            "clear" => {
+                if args.is_empty() {
+                    self.breakpoints.clear();
+                    self.opcode_breakpoints.clear();
+                } else if args[0] == "op" {
+                    self.opcode_breakpoints.remove(&num(1)?);
+                } else {
+                    self.breakpoints.remove(&addr(0)?);
+                }
+            },
+            "watch" | "w" => {
+                self.watchpoints.insert(addr(0)?);
+            },
+            "unwatch" => {
+                self.watchpoints.remove(&addr(0)?);
+            },
+            "step" | "s" => {
+                let n = if args.is_empty() { 1 } else { count(0)? };
+                return Ok(Some(RunMode::Step(n)));
+            },
+            "continue" | "c" => return Ok(Some(RunMode::Continue)),
+            "input" => return Ok(Some(RunMode::UntilInput)),
+            "output" => return Ok(Some(RunMode::UntilOutput)),
+            "record" => {
+                let capacity = match args.first() {
+                    None => DEFAULT_RECORD_CAPACITY,
+                    Some(&"off") => 0,
+                    Some(_) => count(0)?,
+                };
+                self.program.tracer_mut().set_capacity(capacity);
+            },
+            "back" => {
+                if !self.recording() {
+                    return Err("Not recording, use :record first".to_string());
+                }
+                let mut n = if args.is_empty() { 1 } else { count(0)? };
+                let step = self.run_back(|_| {
+                    n = n.saturating_sub(1);
+                    n == 0
+                });
+                self.print_back(step);
+            },
+            "rcontinue" | "rc" => {
+                if !self.recording() {
+                    return Err("Not recording, use :record first".to_string());
+                }
+                let breakpoints = self.breakpoints.clone();
+                let opcode_breakpoints = self.opcode_breakpoints.clone();
+                let watchpoints = self.watchpoints.clone();
+                let step = self.run_back(|step| {
+                    breakpoints.contains(&step.ip) ||
+                        opcode_breakpoints.contains(&step.opcode) ||
+                        step.write.is_some_and(|write| watchpoints.contains(&write.addr))
+                });
+                self.print_back(step);
+            },
+            "whowrote" => {
+                let addr = addr(0)?;
+                if self.program.tracer().last_write(addr).is_none() {
+                    return Err(format!("No recorded write to {}", addr));
+                }
+                let step = self.run_back(|step| step.write.is_some_and(|write| write.addr == addr)).unwrap();
+                let write = step.write.unwrap();
+                eprintln!("[{} = {} was {}] {}", addr, write.new, write.old, self.current_instruction());
+            },
+            "regs" | "r" => {
+                eprintln!("ip={} rb={}", self.program.ip(), self.program.relative_base());
+                eprintln!("{}", self.current_instruction());
+            },
+            "dump" | "d" => {
+                let start = addr(0)?;
+                let end = if args.len() > 1 { addr(1)? } else { start };
+                for row in (start..=end).step_by(8) {
+                    let vals = (row..=end.min(row + 7)).map(|a| self.program.mem[a].to_string()).collect::<Vec<_>>();
+                    eprintln!("{:04}: {}", row, vals.join(" "));
+                }
+            },
+            "poke" => {
+                let start = addr(0)?;
+                if start + args.len() - 1 > self.program.mem.limit() {
+                    return Err(format!("Address beyond memory limit {}", self.program.mem.limit()));
+                }
+                for i in 1..args.len() {
+                    self.program.mem[start + i - 1] = num(i)?;
+                }
+            },
+            "save" => {
+                let file_name = args.first().ok_or("Missing file name for :save")?;
+                self.program.save_snapshot(file_name).map_err(|err| err.to_string())?;
+            },
+            "load" => {
+                let file_name = args.first().ok_or("Missing file name for :load")?;
+                let capacity = self.program.tracer().capacity();
+                self.program = Program::load_snapshot(file_name).map_err(|err| err.to_string())?
+                    .with_tracer(History::new(capacity));
+                eprintln!("{}", self.current_instruction());
+            },
+            "io" => {
+                let name = args.first().ok_or("Missing mode for :io")?;
+                self.io = IoMode::parse(name).ok_or_else(|| format!("Unknown I/O mode {}", name))?;
+            },
+            "help" | "h" => eprintln!("{}", HELP),
+            _ => return Err(format!("Unknown command :{}, try :help", name)),
+        }
+        Ok(None)
+    }
+}
+
+/// Everything that passed between the user and the program. In a file, each input line is written
+/// as `< line` and each chunk of output as `> chunk`, with backslashes, newlines and carriage
+/// returns escaped.
+#[derive(Debug, PartialEq, Eq, Clone)]
+enum Entry {
+    Input(String),
+    Output(String),
+}
+
+impl Entry {
+    fn to_line(&self) -> String {
+        let (prefix, text) = match self {
+            Entry::Input(text) => ('<', text),
+            Entry::Output(text) => ('>', text),
+        };
+        format!("{} {}", prefix, text.replace('\\', "\\\\").replace('\n', "\\n").replace('\r', "\\r"))
+    }
+
+    fn from_line(line: &str) -> Option<Entry> {
+        let mut text = String::new();
+        let mut chars = line.get(2..)?.chars();
+        while let Some(c) = chars.next() {
+            text.push(match c {
+                '\\' => match chars.next()? {
+                    'n' => '\n',
+                    'r' => '\r',
+                    '\\' => '\\',
+                    _ => return None,
+                },
+                c => c,
+            });
+        }
+        match line.get(..2)? {
+            "< " => Some(Entry::Input(text)),
+            "> " => Some(Entry::Output(text)),
+            _ => None,
+        }
+    }
+}
+
+fn load_transcript(file_name: &str) -> Vec<Entry> {
+    std::fs::read_to_string(file_name).expect("Could not read transcript")
+        .lines()
+        .enumerate()
+        .map(|(i, line)| Entry::from_line(line).unwrap_or_else(|| panic!("Invalid transcript line {}: {}", i + 1, line)))
+        .collect()
+}
+
+/// Feeds lines to the debugger, from a script first and then, if interactive, from stdin. Keeps a
+/// transcript of the session, which is also written to a file if requested.
+struct Session {
+    script: VecDeque<String>,
+    interactive: bool,
+    quiet: bool,
+    transcript: Vec<Entry>,
+    file: Option<std::fs::File>,
+}
+
+impl Session {
+    fn record(&mut self, entry: Entry) {
+        if let Some(file) = &mut self.file {
+            writeln!(file, "{}", entry.to_line()).expect("Could not write transcript");
+        }
+        self.transcript.push(entry);
+    }
+
+    fn output(&mut self, chunk: Vec<u8>, out: &mut impl Write) {
+        if chunk.is_empty() {
+            return;
+        }
+        if !self.quiet {
+            out.write_all(&chunk).unwrap();
+        }
+        self.record(Entry::Output(String::from_utf8(chunk).unwrap()));
+    }
+
+    fn next_line(&mut self, stdin: &mut impl BufRead, out: &mut impl Write) -> Option<String> {
+        let line = match self.script.pop_front() {
+            Some(line) => {
+                if !self.quiet {
+                    writeln!(out, "{}", line).unwrap();
+                }
+                line
+            },
+            None if self.interactive => {
+                out.flush().unwrap();
+                let mut line = String::new();
+                if stdin.read_line(&mut line).unwrap() == 0 {
+                    return None;
+                }
+                line.trim_end_matches('\n').to_string()
+            },
+            None => return None,
+        };
+        self.record(Entry::Input(line.clone()));
+        Some(line)
+    }
+}
+
+fn run(debugger: &mut Debugger, session: &mut Session, stdin: &mut impl BufRead, stdout: &mut impl Write) {
+    let mut resume = RunMode::Continue;
+    loop {
+        let mut chunk = vec![];
+        let stop = debugger.run(resume, &mut chunk);
+        session.output(chunk, stdout);
+        stdout.flush().unwrap();
+        match &stop {
+            Stop::Input => {},
+            Stop::Halted if !debugger.recording() => return,
+            Stop::Halted => eprintln!("[halted]"),
+            Stop::Breakpoint => eprintln!("[breakpoint] {}", debugger.current_instruction()),
+            Stop::Watchpoint(addr) => eprintln!("[watchpoint {} = {}] {}", addr, debugger.program.mem[*addr], debugger.current_instruction()),
+            Stop::Steps | Stop::Output => eprintln!("{}", debugger.current_instruction()),
+            Stop::Fault(err) => eprintln!("[fault] {}", err),
+        }
+        let mut mode = None;
+        while mode.is_none() {
+            let line = match session.next_line(stdin, stdout) {
+                Some(line) => line,
+                None => return,
+            };
+            if let Some(command) = line.strip_prefix(':') {
+                match debugger.command(command.trim()) {
+                    Ok(m) => mode = m,
+                    Err(message) => eprintln!("{}", message),
+                }
+            } else if let Err(message) = debugger.send_line(&line) {
+                eprintln!("{}", message);
+            } else if let Stop::Input = stop {
+                mode = Some(RunMode::Continue);
+            }
+        }
+        resume = mode.unwrap();
+    }
+}
+
+/// Compares the output of a replay with the transcript it came from. Returns a description of
+/// the first difference, if any.
+fn verify(expected: &[Entry], actual: &[Entry]) -> Option<String> {
+    let outputs = |entries: &[Entry]| entries.iter()
+        .filter_map(|entry| match entry {
+            Entry::Output(text) => Some(text.clone()),
+            Entry::Input(_) => None,
+        })
+        .collect::<Vec<_>>();
+    let (expected, actual) = (outputs(expected), outputs(actual));
+    for i in 0..expected.len().max(actual.len()) {
+        let (e, a) = (expected.get(i), actual.get(i));
+        if e != a {
+            let show = |chunk: Option<&String>| chunk.map_or("nothing".to_string(), |text| format!("{:?}", text));
+            return Some(format!("Output chunk {} differs\nexpected: {}\nactual:   {}", i + 1, show(e), show(a)));
+        }
+    }
+    None
+}
+
+const USAGE: &str = "\
+Usage: intcode_repl FILE [OPTIONS]
+  --record              keep the last instructions for going back, see :record
+  --transcript FILE     write all input lines and output to FILE
+  --replay FILE         send the input lines of a transcript
+  --verify FILE         replay a transcript silently and check that the output is the same
+  --script FILE         send the lines of FILE
+  --interactive         read from the terminal after a replay or script
+  --io MODE             convert input and output as ascii (default), numeric or auto
+  --input VALUES        queue integers, separated by commas, as input before anything else";
+
 fn main() {
-    let mut args = std::env::args();
-    args.next().unwrap();
-    let file_name = args.next().expect("Give file name of intcode program on the command line");
-    let mut program = Program::parse(&std::fs::read_to_string(&file_name).expect("Could not read intcode program"));
+    let mut args = std::env::args().skip(1);
+    let mut file_name = None;
+    let mut record = false;
+    let mut transcript_file = None;
+    let mut replay = None;
+    let mut verifying = false;
+    let mut script_file = None;
+    let mut interactive = false;
+    let mut io = IoMode::Ascii;
+    let mut input = vec![];
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().unwrap_or_else(|| panic!("Missing value for {}\n{}", arg, USAGE));
+        match arg.as_str() {
+            "--record" => record = true,
+            "--transcript" => transcript_file = Some(value()),
+            "--replay" => replay = Some(value()),
+            "--verify" => { replay = Some(value()); verifying = true; },
+            "--script" => script_file = Some(value()),
+            "--interactive" => interactive = true,
+            "--io" => io = IoMode::parse(&value()).unwrap_or_else(|| panic!("Unknown I/O mode\n{}", USAGE)),
+            "--input" => input.extend(parse_numbers(&value()).unwrap_or_else(|message| panic!("{}\n{}", message, USAGE))),
+            _ if arg.starts_with("--") || file_name.is_some() => panic!("Unexpected argument {}\n{}", arg, USAGE),
+            _ => file_name = Some(arg),
+        }
+    }
+    let file_name = file_name.unwrap_or_else(|| panic!("Give file name of intcode program on the command line\n{}", USAGE));
+    let program = Program::parse(&std::fs::read_to_string(&file_name).expect("Could not read intcode program"));
+    let mut debugger = Debugger::new(program);
+    debugger.io = io;
+    debugger.input.extend(input);
+    if record {
+        debugger.program.tracer_mut().set_capacity(DEFAULT_RECORD_CAPACITY);
+    }
+
+    let expected = replay.as_deref().map(load_transcript).unwrap_or_default();
+    let mut script = expected.iter()
+        .filter_map(|entry| match entry {
+            Entry::Input(line) => Some(line.clone()),
+            Entry::Output(_) => None,
+        })
+        .collect::<VecDeque<_>>();
+    if let Some(script_file) = &script_file {
+        script.extend(std::fs::read_to_string(script_file).expect("Could not read script").lines().map(str::to_string));
+    }
+    let mut session = Session {
+        script,
+        interactive: interactive || (replay.is_none() && script_file.is_none()),
+        quiet: verifying,
+        transcript: vec![],
+        file: transcript_file.map(|file_name| std::fs::File::create(file_name).expect("Could not create transcript")),
+    };
+
     let stdin = std::io::stdin();
-    let mut stdin = stdin.lock();
     let stdout = std::io::stdout();
-    let mut stdout = stdout.lock();
-    loop {
-        match program.run_until_interrupt() {
-            Interrupt::Reading => {
-                let mut line = String::new();
-                stdin.read_line(&mut line).unwrap();
-                program.give_input_ascii(&line);
-            },
-            Interrupt::Writing => {
-                let output = program.take_output_ascii();
-                write!(stdout, "{}", output).unwrap();
-            },
-            Interrupt::Halted => {
-                return;
+    run(&mut debugger, &mut session, &mut stdin.lock(), &mut stdout.lock());
+
+    if verifying {
+        match verify(&expected, &session.transcript) {
+            None => eprintln!("Output matches the transcript"),
+            Some(difference) => {
+                eprintln!("{}", difference);
+                std::process::exit(1);
             },
         }
     }
 }
+
+#[test]
+fn test_transcript_escapes() {
+    let entry = Entry::Output("a\\b\r\n".to_string());
+    let saved = format!("{}\n", entry.to_line());
+    assert_eq!(saved, "> a\\\\b\\r\\n\n");
+    assert_eq!(saved.lines().map(Entry::from_line).collect::<Vec<_>>(), vec![Some(entry)]);
+}
@@ -0,0 +1,15 @@
+use aoc::intcode::*;
+use aoc::intcode::transpile::transpile;
+
+fn main() {
+    let mut args = std::env::args();
+    args.next().unwrap();
+    let file_name = args.next().expect("Give file name of intcode program on the command line");
+    let intcode_path = match args.next().as_deref() {
+        None => "aoc::intcode".to_string(),
+        Some("--path") => args.next().expect("Give the path of the intcode module after --path"),
+        Some(arg) => panic!("Unknown argument {}", arg),
+    };
+    let mem = Memory::parse(&std::fs::read_to_string(&file_name).expect("Could not read intcode program"));
+    print!("{}", transpile(&mem, &intcode_path));
+}
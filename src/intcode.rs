@@ -1,6 +1,35 @@
-use std::collections::HashMap;
-
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+pub mod ascii;
+pub mod asm;
+pub mod cached;
+pub mod cfg;
+pub mod coverage;
+pub mod decompile;
+pub mod device;
+pub mod network;
+pub mod opcodes;
+pub mod pipeline;
+pub mod disasm;
+pub mod history;
+pub mod profile;
+pub mod promote;
+pub mod record;
+pub mod snapshot;
+pub mod symbolic;
+pub mod trace;
+pub mod transpile;
+
+use trace::{MemWrite, NoTracer, Step, Tracer};
+
+/// An Intcode word. With the `wide` feature, this is `i128` instead of `i64`, for programs whose
+/// values do not fit in 64 bits.
+#[cfg(not(feature = "wide"))]
 pub type Number = i64;
+#[cfg(feature = "wide")]
+pub type Number = i128;
 
 pub type Addr = usize;
 
@@ -8,54 +37,167 @@ pub fn to_addr(n: Number) -> Addr {
     n as Addr
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+const PAGE_BITS: usize = 10;
+const PAGE_SIZE: usize = 1 << PAGE_BITS;
+
+/// The default maximum number of words of memory a program can address.
+pub const DEFAULT_MEMORY_LIMIT: Addr = 1 << 24;
+
+/// The largest memory limit that can be set, which bounds the size of the page table.
+pub const MAX_MEMORY_LIMIT: Addr = 1 << 30;
+
+type Page = [Number; PAGE_SIZE];
+
+/// Memory that is allocated in pages as it is written. Cells that were never written are zero.
+/// Pages are shared between clones until one of them writes to it, so cloning is cheap.
+#[derive(Debug, Clone)]
 pub struct Memory {
-    low: Vec<Number>,
-    // TODO see if just a growing Vec is faster
-    high: HashMap<usize, Number>,
+    pages: Vec<Option<Arc<Page>>>,
+    limit: Addr,
 }
 
 impl Memory {
-    pub fn parse(input: &str) -> Self {
-        let mut low: Vec<Number> = input.trim().split(",").map(|s| s.parse::<Number>().unwrap()).collect();
-        low.resize(low.len() * 2, 0);
+    pub fn new() -> Self {
         Memory {
-            low,
-            high: HashMap::new(),
+            pages: vec![],
+            limit: DEFAULT_MEMORY_LIMIT,
         }
     }
+
+    pub fn parse(input: &str) -> Self {
+        Self::from_words(input.trim().split(",").map(|s| s.parse::<Number>().unwrap()).collect())
+    }
+
+    pub fn from_words(words: Vec<Number>) -> Self {
+        let mut mem = Self::new();
+        assert!(words.len() <= mem.limit, "Program of {} words exceeds memory limit {}", words.len(), mem.limit);
+        mem.pages = words.chunks(PAGE_SIZE)
+            .map(|chunk| {
+                let mut page = [0; PAGE_SIZE];
+                page[..chunk.len()].copy_from_slice(chunk);
+                Some(Arc::new(page))
+            })
+            .collect();
+        mem
+    }
+
+    /// Sets the maximum number of words that can be addressed. Panics if memory is already in use
+    /// beyond the new limit, or if it exceeds `MAX_MEMORY_LIMIT`.
+    pub fn with_limit(mut self, limit: Addr) -> Self {
+        assert!(limit <= MAX_MEMORY_LIMIT, "Memory limit {} exceeds maximum {}", limit, MAX_MEMORY_LIMIT);
+        assert!(self.end() <= limit, "Memory in use up to {} exceeds limit {}", self.end(), limit);
+        self.pages.truncate(limit.div_ceil(PAGE_SIZE));
+        self.limit = limit;
+        self
+    }
+
+    pub fn limit(&self) -> Addr {
+        self.limit
+    }
+
+    /// One past the highest nonzero address, or 0 if all memory is zero.
+    pub fn end(&self) -> Addr {
+        self.pages.iter().enumerate().rev()
+            .filter_map(|(i, page)| page.as_ref().map(|page| (i, page)))
+            .find_map(|(i, page)| page.iter().rposition(|&n| n != 0).map(|j| (i << PAGE_BITS) + j + 1))
+            .unwrap_or(0)
+    }
+
+    /// Iterates over all nonzero cells at or after `start`, in order of address.
+    pub fn nonzero_from(&self, start: Addr) -> impl Iterator<Item = (Addr, Number)> + '_ {
+        self.pages.iter().enumerate().skip(start >> PAGE_BITS)
+            .filter_map(|(i, page)| page.as_ref().map(|page| (i << PAGE_BITS, page)))
+            .flat_map(|(base, page)| page.iter().enumerate().map(move |(j, &n)| (base + j, n)))
+            .filter(move |&(addr, n)| addr >= start && n != 0)
+    }
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Memories compare by contents, where cells that were never written are zero.
+impl PartialEq for Memory {
+    fn eq(&self, other: &Self) -> bool {
+        self.nonzero_from(0).eq(other.nonzero_from(0))
+    }
 }
 
+impl Eq for Memory {}
+
 impl std::ops::Index<usize> for Memory {
     type Output = Number;
     fn index(&self, index: usize) -> &Self::Output {
-        if index < self.low.len() {
-            &self.low[index]
-        } else {
-            self.high.get(&index).unwrap_or(&0)
+        match self.pages.get(index >> PAGE_BITS) {
+            Some(Some(page)) => &page[index & (PAGE_SIZE - 1)],
+            _ => &0,
         }
     }
 }
 
 impl std::ops::IndexMut<usize> for Memory {
     fn index_mut(&mut self, index: usize) -> &mut Self::Output {
-        // TODO allow resizing low, up to a point
-        if index < self.low.len() {
-            &mut self.low[index]
-        } else {
-            self.high.entry(index).or_default()
+        assert!(index < self.limit, "Address {} exceeds memory limit {}", index, self.limit);
+        let page_index = index >> PAGE_BITS;
+        if page_index >= self.pages.len() {
+            self.pages.resize(page_index + 1, None);
         }
+        let page = self.pages[page_index].get_or_insert_with(|| Arc::new([0; PAGE_SIZE]));
+        &mut Arc::make_mut(page)[index & (PAGE_SIZE - 1)]
     }
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
-pub struct Program {
+pub struct Program<T = NoTracer> {
     pub mem: Memory,
     interrupt: Option<Interrupt>,
+    fault: Option<IntcodeError>,
     ip: Addr,
     relative_base: Number,
     cur_ip: Addr,
     cur_op: Number,
+    overflow: Overflow,
+    fuel: Option<u64>,
+    deadline: Option<Instant>,
+    deadline_countdown: u32,
+    tracer: T,
+    step: Step,
+    instruction_set: Option<Arc<opcodes::InstructionSet>>,
+    /// Values that did not fit in a `Number` under `Overflow::Promote`.
+    promoted: BTreeMap<Addr, i128>,
+}
+
+/// Number of instructions executed between checks of the deadline, since reading the clock is
+/// relatively slow.
+const DEADLINE_CHECK_INTERVAL: u32 = 1 << 12;
+
+/// What happens when the result of an arithmetic instruction does not fit in a `Number`.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Overflow {
+    /// Fault with `IntcodeError::Overflow`.
+    Trap,
+    /// Wrap around in two's complement.
+    Wrap,
+    /// Compute in `i128`, keeping results that do not fit. See the `promote` module.
+    Promote,
+}
+
+impl Overflow {
+    fn add(self, a: Number, b: Number) -> Option<Number> {
+        match self {
+            Overflow::Trap | Overflow::Promote => a.checked_add(b),
+            Overflow::Wrap => Some(a.wrapping_add(b)),
+        }
+    }
+
+    fn mul(self, a: Number, b: Number) -> Option<Number> {
+        match self {
+            Overflow::Trap | Overflow::Promote => a.checked_mul(b),
+            Overflow::Wrap => Some(a.wrapping_mul(b)),
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
@@ -63,72 +205,392 @@ pub enum Interrupt {
     Reading,
     Writing,
     Halted,
+    /// The instruction budget set by `set_fuel` is used up. Add fuel to resume.
+    OutOfFuel,
+    /// The deadline set by `set_deadline` has passed. Move the deadline to resume.
+    Timeout,
+    /// Raised by a custom instruction. See `opcodes::Effect::Raise`.
+    Custom(Number),
 }
 
-#[derive(Debug, PartialEq, Eq, Clone)]
-enum ArgMode {
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum ArgMode {
     Position,
     Immediate,
     Relative,
 }
 
+impl ArgMode {
+    pub fn from_number(mode: Number) -> Option<ArgMode> {
+        match mode {
+            0 => Some(ArgMode::Position),
+            1 => Some(ArgMode::Immediate),
+            2 => Some(ArgMode::Relative),
+            _ => None,
+        }
+    }
+
+    pub fn to_number(self) -> Number {
+        match self {
+            ArgMode::Position => 0,
+            ArgMode::Immediate => 1,
+            ArgMode::Relative => 2,
+        }
+    }
+}
+
+/// Number of memory cells, starting at the faulting instruction, that are captured in an error.
+const EXCERPT_LEN: usize = 4;
+
+/// An error raised by the fallible `try_*` methods on `Program`.
+///
+/// Errors that originate from executing an instruction leave the program with its instruction
+/// pointer at the faulting instruction, and every subsequent `try_*` call returns the same error.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum IntcodeError {
+    InvalidOpcode { ip: Addr, opcode: Number, excerpt: Vec<Number> },
+    InvalidMode { ip: Addr, opcode: Number, mode: Number, excerpt: Vec<Number> },
+    ImmediateLvalue { ip: Addr, opcode: Number, excerpt: Vec<Number> },
+    UnexpectedInterrupt { expected: Interrupt, actual: Interrupt },
+    InputExhausted { ip: Addr },
+    NegativeAddress { ip: Addr, addr: Number, excerpt: Vec<Number> },
+    AddressOutOfRange { ip: Addr, addr: Number, limit: Addr, excerpt: Vec<Number> },
+    Overflow { ip: Addr, opcode: Number, excerpt: Vec<Number> },
+    /// Raised by a custom instruction. See `opcodes::Machine::error`.
+    Custom { ip: Addr, opcode: Number, message: String, excerpt: Vec<Number> },
+}
+
+impl std::fmt::Display for IntcodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fn fmt_excerpt(excerpt: &[Number]) -> String {
+            excerpt.iter().map(|n| n.to_string()).collect::<Vec<_>>().join(",")
+        }
+        match self {
+            IntcodeError::InvalidOpcode { ip, opcode, excerpt } =>
+                write!(f, "Invalid opcode {} at address {} (memory: {})", opcode, ip, fmt_excerpt(excerpt)),
+            IntcodeError::InvalidMode { ip, opcode, mode, excerpt } =>
+                write!(f, "Invalid parameter mode {} for opcode {} at address {} (memory: {})", mode, opcode, ip, fmt_excerpt(excerpt)),
+            IntcodeError::ImmediateLvalue { ip, opcode, excerpt } =>
+                write!(f, "Parameter mode Immediate for opcode {} at address {} not supported for lvalues (memory: {})", opcode, ip, fmt_excerpt(excerpt)),
+            IntcodeError::UnexpectedInterrupt { expected, actual } =>
+                write!(f, "Expected interrupt state {:?} but was {:?}", expected, actual),
+            IntcodeError::InputExhausted { ip } =>
+                write!(f, "Attempted to read from empty input at address {}", ip),
+            IntcodeError::NegativeAddress { ip, addr, excerpt } =>
+                write!(f, "Negative address {} used at address {} (memory: {})", addr, ip, fmt_excerpt(excerpt)),
+            IntcodeError::AddressOutOfRange { ip, addr, limit, excerpt } =>
+                write!(f, "Address {} beyond memory limit {} used at address {} (memory: {})", addr, limit, ip, fmt_excerpt(excerpt)),
+            IntcodeError::Overflow { ip, opcode, excerpt } =>
+                write!(f, "Arithmetic overflow in opcode {} at address {} (memory: {})", opcode, ip, fmt_excerpt(excerpt)),
+            IntcodeError::Custom { ip, opcode, message, excerpt } =>
+                write!(f, "{} in opcode {} at address {} (memory: {})", message, opcode, ip, fmt_excerpt(excerpt)),
+        }
+    }
+}
+
+impl std::error::Error for IntcodeError {}
+
 impl Program {
     pub fn new(mem: Memory) -> Self {
         Program {
             mem: mem,
             interrupt: None,
+            fault: None,
             ip: 0,
             relative_base: 0,
             cur_ip: 0,
             cur_op: 0,
+            overflow: Overflow::Trap,
+            fuel: None,
+            deadline: None,
+            deadline_countdown: 0,
+            tracer: NoTracer,
+            step: Step::new(0, 0),
+            instruction_set: None,
+            promoted: BTreeMap::new(),
         }
     }
 
     pub fn parse(input: &str) -> Self {
         Self::new(Memory::parse(input))
     }
+}
+
+impl<T: Tracer> Program<T> {
+    /// Attaches a tracer, which is called for every instruction executed from now on.
+    pub fn with_tracer<U: Tracer>(self, tracer: U) -> Program<U> {
+        Program {
+            mem: self.mem,
+            interrupt: self.interrupt,
+            fault: self.fault,
+            ip: self.ip,
+            relative_base: self.relative_base,
+            cur_ip: self.cur_ip,
+            cur_op: self.cur_op,
+            overflow: self.overflow,
+            fuel: self.fuel,
+            deadline: self.deadline,
+            deadline_countdown: self.deadline_countdown,
+            tracer,
+            step: self.step,
+            instruction_set: self.instruction_set,
+            promoted: self.promoted,
+        }
+    }
+
+    /// Sets the overflow policy, which is `Overflow::Trap` by default.
+    pub fn with_overflow(mut self, overflow: Overflow) -> Self {
+        self.overflow = overflow;
+        self
+    }
+
+    pub fn overflow(&self) -> Overflow {
+        self.overflow
+    }
+
+    /// Adds custom opcodes, which run alongside the standard ones.
+    pub fn with_instruction_set(mut self, instruction_set: opcodes::InstructionSet) -> Self {
+        self.instruction_set = Some(Arc::new(instruction_set));
+        self
+    }
+
+    pub fn instruction_set(&self) -> Option<&opcodes::InstructionSet> {
+        self.instruction_set.as_deref()
+    }
+
+    /// Limits the number of instructions that the program executes before it stops with
+    /// `Interrupt::OutOfFuel`.
+    pub fn with_fuel(mut self, fuel: u64) -> Self {
+        self.set_fuel(Some(fuel));
+        self
+    }
+
+    /// Sets the remaining number of instructions, or removes the limit.
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel;
+    }
+
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    /// Makes the program stop with `Interrupt::Timeout` once it has run for the given time.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.set_deadline(Some(Instant::now() + timeout));
+        self
+    }
+
+    /// Sets the time after which the program stops with `Interrupt::Timeout`, or removes it. The
+    /// deadline is only checked every few thousand instructions, so the program may overrun it
+    /// slightly.
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+        self.deadline_countdown = 0;
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    pub fn tracer(&self) -> &T {
+        &self.tracer
+    }
+
+    pub fn tracer_mut(&mut self) -> &mut T {
+        &mut self.tracer
+    }
+
+    pub fn into_tracer(self) -> T {
+        self.tracer
+    }
 
     pub fn run_until_interrupt(&mut self) -> Interrupt {
+        self.try_run_until_interrupt().unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    pub fn try_run_until_interrupt(&mut self) -> Result<Interrupt, IntcodeError> {
+        if let Some(fault) = &self.fault {
+            return Err(fault.clone());
+        }
         loop {
-            if let Some(interrupt) = self.interrupt {
-                return interrupt;
+            if let Some(interrupt) = self.interrupt.or_else(|| self.use_budget()) {
+                return Ok(interrupt);
             }
-            self.cur_ip = self.ip;
-            self.cur_op = self.mem[self.ip];
-            self.ip += 1;
-            let opcode = self.cur_op % 100;
-            self.cur_op /= 100;
-            match opcode {
-                1 => { self.bin_op(|a, b| a + b); },
-                2 => { self.bin_op(|a, b| a * b); },
-                3 => { self.input(); },
-                4 => { self.output(); },
-                5 => { self.cond_jump(|a| a != 0); },
-                6 => { self.cond_jump(|a| a == 0); },
-                7 => { self.bin_op(|a, b| if a < b { 1 } else { 0 }); },
-                8 => { self.bin_op(|a, b| if a == b { 1 } else { 0 }); },
-                9 => { self.rel_base(); },
-                99 => { self.halt(); }
-                _ => { panic!("Invalid opcode {} at address {}", opcode, self.cur_ip); }
+            match self.execute() {
+                Ok(None) => {},
+                Ok(Some(interrupt)) => return Ok(interrupt),
+                Err(err) => return Err(self.set_fault(err)),
             }
         }
     }
 
-    pub fn give_input(&mut self, val: Number) {
-        if self.run_until_interrupt() != Interrupt::Reading {
-            panic!("Attempted to give input in interrupt state {:?}", self.interrupt);
+    /// Executes a single instruction, unless the program is already at an interrupt. Returns the
+    /// interrupt, if any. Input and output instructions stop at their interrupt, and are completed
+    /// by `give_input` and `take_output` as usual.
+    pub fn step(&mut self) -> Option<Interrupt> {
+        self.try_step().unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    pub fn try_step(&mut self) -> Result<Option<Interrupt>, IntcodeError> {
+        if let Some(fault) = &self.fault {
+            return Err(fault.clone());
         }
+        if self.interrupt.is_none() {
+            if let Some(interrupt) = self.use_budget() {
+                return Ok(Some(interrupt));
+            }
+            match self.execute() {
+                Ok(None) => {},
+                Ok(Some(interrupt)) => return Ok(Some(interrupt)),
+                Err(err) => return Err(self.set_fault(err)),
+            }
+        }
+        Ok(self.interrupt)
+    }
+
+    /// Accounts for executing one instruction. Returns `OutOfFuel` or `Timeout` if it may not be
+    /// executed.
+    fn use_budget(&mut self) -> Option<Interrupt> {
+        if let Some(fuel) = &mut self.fuel {
+            if *fuel == 0 {
+                return Some(Interrupt::OutOfFuel);
+            }
+            *fuel -= 1;
+        }
+        if let Some(deadline) = self.deadline {
+            if self.deadline_countdown == 0 {
+                if Instant::now() >= deadline {
+                    return Some(Interrupt::Timeout);
+                }
+                self.deadline_countdown = DEADLINE_CHECK_INTERVAL;
+            }
+            self.deadline_countdown -= 1;
+        }
+        None
+    }
+
+    /// Returns the error that stopped this program, if any.
+    pub fn fault(&self) -> Option<&IntcodeError> {
+        self.fault.as_ref()
+    }
+
+    /// The address of the instruction that executes next. While the program is at an interrupt,
+    /// this is the address of the interrupting instruction.
+    pub fn ip(&self) -> Addr {
+        if self.interrupt.is_some() { self.cur_ip } else { self.ip }
+    }
+
+    pub fn relative_base(&self) -> Number {
+        self.relative_base
+    }
+
+    /// Executes one instruction. Interrupts raised by custom instructions are returned; all others
+    /// are kept in `self.interrupt`.
+    fn execute(&mut self) -> Result<Option<Interrupt>, IntcodeError> {
+        self.cur_ip = self.ip;
+        self.cur_op = self.mem[self.ip];
+        self.ip += 1;
+        let opcode = self.cur_op % 100;
+        self.cur_op /= 100;
+        if T::ENABLED {
+            self.step = Step::new(self.cur_ip, opcode);
+        }
+        match opcode {
+            _ if self.overflow == Overflow::Promote && self.execute_promoted(opcode)? => {},
+            1 => { let overflow = self.overflow; self.bin_op(|a, b| overflow.add(a, b))?; },
+            2 => { let overflow = self.overflow; self.bin_op(|a, b| overflow.mul(a, b))?; },
+            3 => { self.input(); },
+            4 => { self.output(); },
+            5 => { self.cond_jump(|a| a != 0)?; },
+            6 => { self.cond_jump(|a| a == 0)?; },
+            7 => { self.bin_op(|a, b| Some(if a < b { 1 } else { 0 }))?; },
+            8 => { self.bin_op(|a, b| Some(if a == b { 1 } else { 0 }))?; },
+            9 => { self.rel_base()?; },
+            99 => { self.halt(); }
+            _ => { return self.execute_custom(opcode); }
+        }
+        if opcode != 3 && opcode != 4 {
+            // Input and output are traced when they complete.
+            self.trace();
+        }
+        Ok(None)
+    }
+
+    fn execute_custom(&mut self, opcode: Number) -> Result<Option<Interrupt>, IntcodeError> {
+        let handler = match self.instruction_set.as_ref().and_then(|set| set.handler(opcode)) {
+            Some(handler) => handler.clone(),
+            None => return Err(IntcodeError::InvalidOpcode { ip: self.cur_ip, opcode, excerpt: self.excerpt() }),
+        };
+        let effect = handler(self)?;
+        self.trace();
+        Ok(match effect {
+            opcodes::Effect::Continue => None,
+            opcodes::Effect::Halt => { self.halt(); None },
+            opcodes::Effect::Raise(code) => Some(Interrupt::Custom(code)),
+        })
+    }
+
+    fn trace(&mut self) {
+        if T::ENABLED {
+            self.tracer.step(&self.step);
+        }
+    }
+
+    fn write(&mut self, addr: Addr, val: Number) {
+        if T::ENABLED {
+            self.step.write = Some(MemWrite { addr, old: self.mem[addr], new: val });
+        }
+        if !self.promoted.is_empty() {
+            self.promoted.remove(&addr);
+        }
+        self.mem[addr] = val;
+    }
+
+    /// Records a fault and rewinds to the faulting instruction, so that the program can still be
+    /// inspected afterwards.
+    fn set_fault(&mut self, err: IntcodeError) -> IntcodeError {
+        self.ip = self.cur_ip;
         self.interrupt = None;
-        let dest = self.eval_addr();
-        self.mem[dest] = val;
+        self.fault = Some(err.clone());
+        err
     }
 
-    pub fn take_output(&mut self) -> Number {
-        if self.run_until_interrupt() != Interrupt::Writing {
-            panic!("Attempted to take output in interrupt state {:?}", self);
+    fn excerpt(&self) -> Vec<Number> {
+        (self.cur_ip..self.cur_ip + EXCERPT_LEN).map(|addr| self.mem[addr]).collect()
+    }
+
+    fn expect_interrupt(&mut self, expected: Interrupt) -> Result<(), IntcodeError> {
+        let actual = self.try_run_until_interrupt()?;
+        if actual != expected {
+            return Err(IntcodeError::UnexpectedInterrupt { expected, actual });
         }
+        Ok(())
+    }
+
+    pub fn give_input(&mut self, val: Number) {
+        self.try_give_input(val).unwrap_or_else(|err| panic!("Attempted to give input: {}", err))
+    }
+
+    pub fn try_give_input(&mut self, val: Number) -> Result<(), IntcodeError> {
+        self.expect_interrupt(Interrupt::Reading)?;
+        self.interrupt = None;
+        let dest = self.eval_addr().map_err(|err| self.set_fault(err))?;
+        self.write(dest, val);
+        self.trace();
+        Ok(())
+    }
+
+    pub fn take_output(&mut self) -> Number {
+        self.try_take_output().unwrap_or_else(|err| panic!("Attempted to take output: {}", err))
+    }
+
+    pub fn try_take_output(&mut self) -> Result<Number, IntcodeError> {
+        self.expect_interrupt(Interrupt::Writing)?;
         self.interrupt = None;
-        self.eval_arg()
+        let val = if self.overflow == Overflow::Promote { self.take_output_narrowed() } else { self.eval_arg() };
+        let val = val.map_err(|err| self.set_fault(err))?;
+        self.trace();
+        Ok(val)
     }
 
     pub fn give_input_ascii(&mut self, ascii: &str) {
@@ -137,6 +599,13 @@ impl Program {
         }
     }
 
+    pub fn try_give_input_ascii(&mut self, ascii: &str) -> Result<(), IntcodeError> {
+        for &c in ascii.as_bytes() {
+            self.try_give_input(c as Number)?;
+        }
+        Ok(())
+    }
+
     pub fn take_output_ascii(&mut self) -> String {
         let mut ascii = String::new();
         while self.run_until_interrupt() == Interrupt::Writing {
@@ -145,82 +614,113 @@ impl Program {
         ascii
     }
 
+    pub fn try_take_output_ascii(&mut self) -> Result<String, IntcodeError> {
+        let mut ascii = String::new();
+        while self.try_run_until_interrupt()? == Interrupt::Writing {
+            ascii.push(self.try_take_output()? as u8 as char);
+        }
+        Ok(ascii)
+    }
+
     pub fn is_halted(&mut self) -> bool {
         self.run_until_interrupt() == Interrupt::Halted
     }
 
-    pub fn run_without_io(mut self) -> Program {
+    pub fn run_without_io(mut self) -> Self {
         match self.run_until_interrupt() {
             Interrupt::Reading => panic!("This implementation cannot read input"),
             Interrupt::Writing => panic!("This implementation cannot write output"),
             Interrupt::Halted => self,
+            interrupt => panic!("Program stopped with {:?}", interrupt),
         }
     }
 
     pub fn run_with_io(&mut self, input: Vec<Number>) -> Vec<Number> {
-        let mut input_iter = input.into_iter();
+        self.try_run_with_io(input).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    pub fn try_run_with_io(&mut self, input: Vec<Number>) -> Result<Vec<Number>, IntcodeError> {
         let mut output = vec![];
-        loop {
-            match self.run_until_interrupt() {
-                Interrupt::Reading => {
-                    let val = input_iter.next().expect("Attempted to read from empty input");
-                    self.give_input(val);
-                },
-                Interrupt::Writing => {
-                    let val = self.take_output();
-                    output.push(val);
-                },
-                Interrupt::Halted => {
-                    return output;
-                },
-            };
-        }
-    }
-
-    fn arg_mode(&mut self) -> ArgMode {
+        match self.try_run_with_devices(&mut device::IterInput(input.into_iter()), &mut output)? {
+            device::Stop::InputPaused => Err(IntcodeError::InputExhausted { ip: self.cur_ip }),
+            device::Stop::OutOfFuel => Err(IntcodeError::UnexpectedInterrupt { expected: Interrupt::Halted, actual: Interrupt::OutOfFuel }),
+            device::Stop::Timeout => Err(IntcodeError::UnexpectedInterrupt { expected: Interrupt::Halted, actual: Interrupt::Timeout }),
+            device::Stop::Custom(code) => Err(IntcodeError::UnexpectedInterrupt { expected: Interrupt::Halted, actual: Interrupt::Custom(code) }),
+            _ => Ok(output),
+        }
+    }
+
+    fn arg_mode(&mut self) -> Result<ArgMode, IntcodeError> {
         let mode = self.cur_op % 10;
         self.cur_op /= 10;
-        match mode {
-            0 => ArgMode::Position,
-            1 => ArgMode::Immediate,
-            2 => ArgMode::Relative,
-            _ => panic!("Invalid parameter mode {:?} at address {}", mode, self.cur_ip),
+        ArgMode::from_number(mode)
+            .ok_or_else(|| IntcodeError::InvalidMode { ip: self.cur_ip, opcode: self.mem[self.cur_ip] % 100, mode, excerpt: self.excerpt() })
+    }
+
+    /// Converts a number to an address, which must be within the memory limit.
+    fn mem_addr(&self, n: Number) -> Result<Addr, IntcodeError> {
+        if n < 0 {
+            Err(IntcodeError::NegativeAddress { ip: self.cur_ip, addr: n, excerpt: self.excerpt() })
+        } else if to_addr(n) >= self.mem.limit() {
+            Err(IntcodeError::AddressOutOfRange { ip: self.cur_ip, addr: n, limit: self.mem.limit(), excerpt: self.excerpt() })
+        } else {
+            Ok(to_addr(n))
         }
     }
 
+    /// Overflow of an address always traps, because a wrapped address is meaningless.
+    fn relative_addr(&self, offset: Number) -> Result<Addr, IntcodeError> {
+        let addr = offset.checked_add(self.relative_base).ok_or_else(|| self.overflow_error())?;
+        self.mem_addr(addr)
+    }
+
+    fn overflow_error(&self) -> IntcodeError {
+        IntcodeError::Overflow { ip: self.cur_ip, opcode: self.mem[self.cur_ip] % 100, excerpt: self.excerpt() }
+    }
+
     fn arg(&mut self) -> Number {
         let val = self.mem[self.ip];
         self.ip += 1;
         val
     }
 
-    fn eval_arg(&mut self) -> Number {
+    fn eval_arg(&mut self) -> Result<Number, IntcodeError> {
         let val = self.arg();
-        let mode = self.arg_mode();
-        match mode {
-            ArgMode::Position => self.mem[to_addr(val)],
+        let mode = self.arg_mode()?;
+        let val = match mode {
+            ArgMode::Position => self.mem[self.mem_addr(val)?],
             ArgMode::Immediate => val,
-            ArgMode::Relative => self.mem[to_addr(val + self.relative_base)],
+            ArgMode::Relative => self.mem[self.relative_addr(val)?],
+        };
+        if T::ENABLED {
+            self.step.push_operand(mode, val);
         }
+        Ok(val)
     }
 
-    fn eval_addr(&mut self) -> Addr {
+    fn eval_addr(&mut self) -> Result<Addr, IntcodeError> {
         let val = self.arg();
-        let mode = self.arg_mode();
-        match mode {
-            ArgMode::Position => to_addr(val),
-            ArgMode::Relative => to_addr(val + self.relative_base),
-            _ => panic!("Parameter mode {:?} at address {} not supported for lvalues", mode, self.cur_ip)
+        let mode = self.arg_mode()?;
+        let addr = match mode {
+            ArgMode::Position => self.mem_addr(val)?,
+            ArgMode::Relative => self.relative_addr(val)?,
+            ArgMode::Immediate => return Err(IntcodeError::ImmediateLvalue { ip: self.cur_ip, opcode: self.mem[self.cur_ip] % 100, excerpt: self.excerpt() }),
+        };
+        if T::ENABLED {
+            self.step.push_operand(mode, addr as Number);
         }
+        Ok(addr)
     }
 
-    fn bin_op<F>(&mut self, f: F)
-        where F: FnOnce(Number, Number) -> Number
+    fn bin_op<F>(&mut self, f: F) -> Result<(), IntcodeError>
+        where F: FnOnce(Number, Number) -> Option<Number>
     {
-        let a = self.eval_arg();
-        let b = self.eval_arg();
-        let dest = self.eval_addr();
-        self.mem[dest] = f(a, b);
+        let a = self.eval_arg()?;
+        let b = self.eval_arg()?;
+        let dest = self.eval_addr()?;
+        let val = f(a, b).ok_or_else(|| self.overflow_error())?;
+        self.write(dest, val);
+        Ok(())
     }
 
     fn input(&mut self) {
@@ -235,19 +735,21 @@ impl Program {
         self.interrupt = Some(Interrupt::Halted);
     }
 
-    fn cond_jump<P>(&mut self, pred: P)
+    fn cond_jump<P>(&mut self, pred: P) -> Result<(), IntcodeError>
         where P: FnOnce(Number) -> bool
     {
-        let cond = self.eval_arg();
-        let dest = self.eval_arg();
+        let cond = self.eval_arg()?;
+        let dest = self.eval_arg()?;
         if pred(cond) {
-            self.ip = to_addr(dest);
+            self.ip = self.mem_addr(dest)?;
         }
+        Ok(())
     }
 
-    fn rel_base(&mut self) {
-        let delta = self.eval_arg();
-        self.relative_base += delta;
+    fn rel_base(&mut self) -> Result<(), IntcodeError> {
+        let delta = self.eval_arg()?;
+        self.relative_base = self.overflow.add(self.relative_base, delta).ok_or_else(|| self.overflow_error())?;
+        Ok(())
     }
 }
 
@@ -396,3 +898,108 @@ fn test_relative_mode() {
             .run_with_io(vec![]),
         vec![1125899906842624]);
 }
+
+#[test]
+fn test_errors() {
+    let mut program = Program::parse("1,0,0,0,42,0,0,0");
+    assert_eq!(
+        program.try_run_until_interrupt(),
+        Err(IntcodeError::InvalidOpcode { ip: 4, opcode: 42, excerpt: vec![42, 0, 0, 0] }));
+    assert_eq!(program.mem[0], 2);
+    let fault = program.fault().cloned();
+    assert!(fault.is_some());
+    assert_eq!(program.try_run_until_interrupt().err(), fault);
+
+    assert_eq!(
+        Program::parse("301,0,0,0,99").try_run_until_interrupt(),
+        Err(IntcodeError::InvalidMode { ip: 0, opcode: 1, mode: 3, excerpt: vec![301, 0, 0, 0] }));
+    assert_eq!(
+        Program::parse("10001,0,0,0,99").try_run_until_interrupt(),
+        Err(IntcodeError::ImmediateLvalue { ip: 0, opcode: 1, excerpt: vec![10001, 0, 0, 0] }));
+    assert_eq!(
+        Program::parse("103,0,99").try_give_input(1),
+        Err(IntcodeError::ImmediateLvalue { ip: 0, opcode: 3, excerpt: vec![103, 0, 99, 0] }));
+    assert_eq!(
+        Program::parse("99").try_take_output(),
+        Err(IntcodeError::UnexpectedInterrupt { expected: Interrupt::Writing, actual: Interrupt::Halted }));
+    assert_eq!(
+        Program::parse("3,0,3,0,99").try_run_with_io(vec![1]),
+        Err(IntcodeError::InputExhausted { ip: 2 }));
+    assert_eq!(
+        Program::parse("109,-5,22201,1,2,0,99").try_run_until_interrupt(),
+        Err(IntcodeError::NegativeAddress { ip: 2, addr: -4, excerpt: vec![22201, 1, 2, 0] }));
+    assert_eq!(
+        Program::parse("1106,0,-1").try_run_until_interrupt(),
+        Err(IntcodeError::NegativeAddress { ip: 0, addr: -1, excerpt: vec![1106, 0, -1, 0] }));
+    assert_eq!(
+        Program::new(Memory::parse("1101,1,2,100,99").with_limit(100)).try_run_until_interrupt(),
+        Err(IntcodeError::AddressOutOfRange { ip: 0, addr: 100, limit: 100, excerpt: vec![1101, 1, 2, 100] }));
+}
+
+#[test]
+fn test_overflow() {
+    let add = format!("1101,{},1,0,99", Number::MAX);
+    assert_eq!(
+        Program::parse(&add).try_run_until_interrupt(),
+        Err(IntcodeError::Overflow { ip: 0, opcode: 1, excerpt: vec![1101, Number::MAX, 1, 0] }));
+    assert_eq!(Program::parse(&add).with_overflow(Overflow::Wrap).run_without_io().mem[0], Number::MIN);
+    let mul = format!("1102,{},2,0,99", Number::MAX / 2 + 1);
+    assert!(Program::parse(&mul).try_run_until_interrupt().is_err());
+    assert_eq!(Program::parse(&mul).with_overflow(Overflow::Wrap).run_without_io().mem[0], Number::MIN);
+    let rel_base = format!("109,{},109,1,99", Number::MAX);
+    assert_eq!(
+        Program::parse(&rel_base).try_run_until_interrupt(),
+        Err(IntcodeError::Overflow { ip: 2, opcode: 9, excerpt: vec![109, 1, 99, 0] }));
+    let rel_addr = format!("109,{},204,1,99", Number::MAX);
+    assert_eq!(
+        Program::parse(&rel_addr).with_overflow(Overflow::Wrap).try_run_with_io(vec![]),
+        Err(IntcodeError::Overflow { ip: 2, opcode: 4, excerpt: vec![204, 1, 99, 0] }));
+}
+
+#[test]
+fn test_budget() {
+    // Counts forever.
+    let mut program = Program::parse("1001,100,1,100,1105,1,0").with_fuel(10);
+    assert_eq!(program.run_until_interrupt(), Interrupt::OutOfFuel);
+    assert_eq!((program.mem[100], program.ip(), program.fuel()), (5, 0, Some(0)));
+    assert_eq!(program.step(), Some(Interrupt::OutOfFuel));
+    program.set_fuel(Some(3));
+    assert_eq!(program.run_until_interrupt(), Interrupt::OutOfFuel);
+    assert_eq!((program.mem[100], program.ip(), program.fuel()), (7, 4, Some(0)));
+
+    let mut program = program.with_timeout(Duration::from_millis(10));
+    program.set_fuel(None);
+    assert_eq!(program.run_until_interrupt(), Interrupt::Timeout);
+    assert!(program.fault().is_none());
+    assert_eq!(
+        Program::parse("1105,1,0").with_fuel(5).try_run_with_io(vec![]),
+        Err(IntcodeError::UnexpectedInterrupt { expected: Interrupt::Halted, actual: Interrupt::OutOfFuel }));
+}
+
+#[test]
+fn test_memory() {
+    let mut mem = Memory::parse("1,2,3");
+    let clone = mem.clone();
+    mem[1] = 0;
+    mem[5000] = 7;
+    assert_eq!(clone, Memory::parse("1,2,3"));
+    assert_eq!((mem[0], mem[1], mem[4999], mem[5000], mem[1 << 30]), (1, 0, 0, 7, 0));
+    assert_eq!(mem.end(), 5001);
+    assert_eq!(mem.nonzero_from(1).collect::<Vec<_>>(), vec![(2, 3), (5000, 7)]);
+    assert_eq!(Memory::new().end(), 0);
+}
+
+#[test]
+fn test_step() {
+    let mut program = Program::parse("1101,1,2,5,104,0,99");
+    assert_eq!(program.ip(), 0);
+    assert_eq!(program.step(), None);
+    assert_eq!(program.ip(), 4);
+    assert_eq!(program.step(), Some(Interrupt::Writing));
+    assert_eq!(program.ip(), 4);
+    assert_eq!(program.step(), Some(Interrupt::Writing));
+    assert_eq!(program.take_output(), 3);
+    assert_eq!(program.ip(), 6);
+    assert_eq!(program.step(), Some(Interrupt::Halted));
+    assert_eq!(program.ip(), 6);
+}
@@ -0,0 +1,212 @@
+//! Conversations with Intcode programs that talk ASCII, in the style of `expect`.
+
+use std::fmt;
+
+use regex::Regex;
+
+use super::trace::{NoTracer, Tracer};
+use super::{IntcodeError, Interrupt, Number, Program};
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Cause {
+    Intcode(IntcodeError),
+    /// The program stopped before the session got what it was waiting for.
+    Stopped(Interrupt),
+}
+
+/// An error in an `AsciiSession`, with all input and output up to that point.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct AsciiError {
+    pub cause: Cause,
+    pub transcript: String,
+}
+
+impl fmt::Display for AsciiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.cause {
+            Cause::Intcode(err) => write!(f, "{}", err)?,
+            Cause::Stopped(Interrupt::Reading) => write!(f, "Program is waiting for input")?,
+            Cause::Stopped(Interrupt::Halted) => write!(f, "Program halted")?,
+            Cause::Stopped(interrupt) => write!(f, "Program stopped with {:?}", interrupt)?,
+        }
+        write!(f, "\nTranscript:\n{}", self.transcript)
+    }
+}
+
+impl std::error::Error for AsciiError {}
+
+/// Sends lines of text to a program and reads its replies. Output that has been received but not
+/// read yet is kept for the next read. Output values outside the ASCII range are not text; the
+/// last one is available as `result`, like the answers of days 17 and 21.
+#[derive(Debug, Clone)]
+pub struct AsciiSession<T = NoTracer> {
+    program: Program<T>,
+    unread: String,
+    transcript: String,
+    result: Option<Number>,
+}
+
+fn is_ascii(val: Number) -> bool {
+    (0..128).contains(&val)
+}
+
+impl<T: Tracer> AsciiSession<T> {
+    pub fn new(program: Program<T>) -> Self {
+        AsciiSession { program, unread: String::new(), transcript: String::new(), result: None }
+    }
+
+    pub fn program(&self) -> &Program<T> {
+        &self.program
+    }
+
+    pub fn program_mut(&mut self) -> &mut Program<T> {
+        &mut self.program
+    }
+
+    pub fn into_program(self) -> Program<T> {
+        self.program
+    }
+
+    /// Everything sent and received so far, except non-ASCII output.
+    pub fn transcript(&self) -> &str {
+        &self.transcript
+    }
+
+    /// The last non-ASCII value that the program output.
+    pub fn result(&self) -> Option<Number> {
+        self.result
+    }
+
+    fn error(&self, cause: Cause) -> AsciiError {
+        AsciiError { cause, transcript: self.transcript.clone() }
+    }
+
+    /// Receives a single output value, or returns the interrupt that the program stopped at
+    /// instead.
+    fn receive(&mut self) -> Result<Option<Interrupt>, AsciiError> {
+        let val = match self.program.try_run_until_interrupt() {
+            Ok(Interrupt::Writing) => self.program.try_take_output(),
+            Ok(interrupt) => return Ok(Some(interrupt)),
+            Err(err) => Err(err),
+        }.map_err(|err| self.error(Cause::Intcode(err)))?;
+        if is_ascii(val) {
+            self.unread.push(val as u8 as char);
+            self.transcript.push(val as u8 as char);
+        } else {
+            self.result = Some(val);
+        }
+        Ok(None)
+    }
+
+    pub fn send_line(&mut self, line: &str) {
+        self.try_send_line(line).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Sends `line` followed by a newline. Output that the program writes in the meantime is kept
+    /// for the next read.
+    pub fn try_send_line(&mut self, line: &str) -> Result<(), AsciiError> {
+        for c in line.chars().chain(std::iter::once('\n')) {
+            loop {
+                match self.receive()? {
+                    None => {},
+                    Some(Interrupt::Reading) => break,
+                    Some(interrupt) => return Err(self.error(Cause::Stopped(interrupt))),
+                }
+            }
+            self.program.try_give_input(c as Number).map_err(|err| self.error(Cause::Intcode(err)))?;
+            self.transcript.push(c);
+        }
+        Ok(())
+    }
+
+    /// Reads until the output matches `find`, which returns the end of the match. Returns all text
+    /// up to there.
+    fn try_read_until_match<F>(&mut self, find: F) -> Result<String, AsciiError>
+        where F: Fn(&str) -> Option<usize>
+    {
+        loop {
+            if let Some(end) = find(&self.unread) {
+                return Ok(self.unread.drain(..end).collect());
+            }
+            if let Some(interrupt) = self.receive()? {
+                return Err(self.error(Cause::Stopped(interrupt)));
+            }
+        }
+    }
+
+    pub fn read_until(&mut self, pattern: &str) -> String {
+        self.try_read_until(pattern).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Reads output up to and including the first occurrence of `pattern`.
+    pub fn try_read_until(&mut self, pattern: &str) -> Result<String, AsciiError> {
+        self.try_read_until_match(|text| text.find(pattern).map(|start| start + pattern.len()))
+    }
+
+    pub fn read_until_regex(&mut self, regex: &Regex) -> String {
+        self.try_read_until_regex(regex).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Reads output up to and including the first match of `regex`, which is tried after every
+    /// character received. A pattern like `\d+` therefore matches only the first digit; end it with
+    /// something unambiguous, like `\n`.
+    pub fn try_read_until_regex(&mut self, regex: &Regex) -> Result<String, AsciiError> {
+        self.try_read_until_match(|text| regex.find(text).map(|m| m.end()))
+    }
+
+    pub fn read_output(&mut self) -> String {
+        self.try_read_output().unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Reads all output until the program waits for input or halts.
+    pub fn try_read_output(&mut self) -> Result<String, AsciiError> {
+        loop {
+            match self.receive()? {
+                None => {},
+                Some(Interrupt::Reading) | Some(Interrupt::Halted) => return Ok(std::mem::take(&mut self.unread)),
+                Some(interrupt) => return Err(self.error(Cause::Stopped(interrupt))),
+            }
+        }
+    }
+}
+
+#[test]
+fn test_session() {
+    use super::asm::assemble;
+
+    let program = Program::new(assemble("
+                ARB #prompt
+        print:  JZ [rb+0], #read
+                OUT [rb+0]
+                ARB #1
+                JNZ #1, #print
+        read:   IN -> [c]
+                EQ [c], #10 -> [t]
+                JZ [t], #read
+                OUT #72
+                OUT #105
+                OUT #10
+                OUT #1000
+                HLT
+        c:      .data 0
+        t:      .data 0
+        prompt: .string \"Name? \"
+                .data 0
+    ").unwrap());
+
+    let mut session = AsciiSession::new(program.clone());
+    assert_eq!(session.read_until("? "), "Name? ");
+    session.send_line("Bob");
+    assert_eq!(session.read_until_regex(&Regex::new(r"H.\n").unwrap()), "Hi\n");
+    assert_eq!(session.read_output(), "");
+    assert_eq!(session.result(), Some(1000));
+    assert_eq!(session.transcript(), "Name? Bob\nHi\n");
+
+    let mut session = AsciiSession::new(program);
+    session.send_line("Alice");
+    assert_eq!(session.read_output(), "Name? Hi\n");
+    let err = session.try_send_line("again").unwrap_err();
+    assert_eq!(err.cause, Cause::Stopped(Interrupt::Halted));
+    assert_eq!(err.to_string(), "Program halted\nTranscript:\nName? Alice\nHi\n");
+    assert_eq!(session.try_read_until("Bye").unwrap_err().cause, Cause::Stopped(Interrupt::Halted));
+}
@@ -0,0 +1,490 @@
+//! Assembler for Intcode.
+//!
+//! Each line consists of an optional address, optional labels, an optional statement and an
+//! optional comment:
+//!
+//! ```text
+//! 0000: loop: ADD [counter], #1 -> [counter]  ; comments start with a semicolon
+//! ```
+//!
+//! Operands are written as `#imm` (immediate), `[pos]` (position) or `[rb+n]`/`[rb-n]` (relative).
+//! Immediate and position operands may refer to labels. Outputs may be separated by `->` instead of
+//! a comma. A leading decimal address must not lie before the current position; any gap is filled
+//! with zeros. This makes the output of `disasm::disassemble` valid input.
+//!
+//! Besides the mnemonics of `disasm::Op`, the following are supported:
+//!
+//! * `.data 1, -2, label` (or `DATA`) emits raw words.
+//! * `.string "text\n"` emits the ASCII codes of the string.
+//! * `PUSH a`, `POP -> a`, `CALL target` and `RET` use the relative base as a stack pointer that
+//!   points to the next free cell. `CALL` pushes the return address and jumps; `RET` pops it and
+//!   jumps back.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use super::disasm::Op;
+use super::{Addr, ArgMode, Memory, Number, DEFAULT_MEMORY_LIMIT};
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct AsmError {
+    /// 1-based line number.
+    pub line: usize,
+    /// 1-based column number.
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+/// A word whose value may depend on a label that is only resolved after the first pass.
+#[derive(Debug, Clone)]
+struct Expr {
+    label: Option<(String, usize, usize)>,
+    offset: Number,
+}
+
+impl Expr {
+    fn value(n: Number) -> Expr {
+        Expr { label: None, offset: n }
+    }
+}
+
+struct Operand {
+    mode: ArgMode,
+    expr: Expr,
+    column: usize,
+}
+
+struct Assembler {
+    words: Vec<Expr>,
+    symbols: HashMap<String, Addr>,
+}
+
+struct Cursor<'a> {
+    line: usize,
+    chars: Vec<char>,
+    pos: usize,
+    asm: &'a mut Assembler,
+}
+
+pub fn assemble(source: &str) -> Result<Memory, AsmError> {
+    assemble_with_symbols(source).map(|(mem, _)| mem)
+}
+
+/// Assembles the source, also returning the addresses of all labels.
+pub fn assemble_with_symbols(source: &str) -> Result<(Memory, HashMap<String, Addr>), AsmError> {
+    let mut asm = Assembler { words: vec![], symbols: HashMap::new() };
+    for (i, line) in source.lines().enumerate() {
+        Cursor { line: i + 1, chars: line.chars().collect(), pos: 0, asm: &mut asm }.parse_line()?;
+        if asm.words.len() > DEFAULT_MEMORY_LIMIT {
+            return Err(AsmError { line: i + 1, column: 1, message: format!("program exceeds memory limit {}", DEFAULT_MEMORY_LIMIT) });
+        }
+    }
+    let words = asm.words.iter()
+        .map(|expr| match &expr.label {
+            None => Ok(expr.offset),
+            Some((name, line, column)) => asm.symbols.get(name)
+                .ok_or_else(|| AsmError { line: *line, column: *column, message: format!("undefined label '{}'", name) })
+                .and_then(|&addr| (addr as Number).checked_add(expr.offset).ok_or_else(|| AsmError {
+                    line: *line,
+                    column: *column,
+                    message: format!("label '{}' plus offset {} overflows", name, expr.offset),
+                })),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok((Memory::from_words(words), asm.symbols))
+}
+
+impl<'a> Cursor<'a> {
+    fn error<T>(&self, column: usize, message: String) -> Result<T, AsmError> {
+        Err(AsmError { line: self.line, column, message })
+    }
+
+    fn column(&self) -> usize {
+        self.pos + 1
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).cloned()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.pos += 1;
+        }
+    }
+
+    fn at_end(&mut self) -> bool {
+        self.skip_whitespace();
+        self.peek().is_none_or(|c| c == ';')
+    }
+
+    fn eat(&mut self, s: &str) -> bool {
+        self.skip_whitespace();
+        let len = s.chars().count();
+        if self.chars.len() >= self.pos + len && self.chars[self.pos..self.pos + len].iter().cloned().eq(s.chars()) {
+            self.pos += len;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, s: &str) -> Result<(), AsmError> {
+        if self.eat(s) {
+            Ok(())
+        } else {
+            self.error(self.column(), format!("expected '{}'", s))
+        }
+    }
+
+    fn ident(&mut self) -> Option<String> {
+        self.skip_whitespace();
+        let start = self.pos;
+        if self.peek().is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == '.') {
+            self.pos += 1;
+            while self.peek().is_some_and(|c| c.is_ascii_alphanumeric() || c == '_') {
+                self.pos += 1;
+            }
+        }
+        if self.pos > start {
+            Some(self.chars[start..self.pos].iter().collect())
+        } else {
+            None
+        }
+    }
+
+    fn number(&mut self) -> Result<Number, AsmError> {
+        self.signed_number(false)
+    }
+
+    /// Parses a number, negated if `negate` is set, as after the `-` in `[rb-n]`. The sign is
+    /// parsed together with the digits, so that `Number::MIN` can be written either way.
+    fn signed_number(&mut self, negate: bool) -> Result<Number, AsmError> {
+        self.skip_whitespace();
+        let start = self.pos;
+        let mut negative = negate;
+        if self.peek() == Some('-') {
+            self.pos += 1;
+            negative = !negative;
+        }
+        let digits_start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        let text = self.chars[start..self.pos].iter().collect::<String>();
+        if self.pos == digits_start {
+            return self.error(start + 1, format!("invalid number '{}'", text));
+        }
+        let digits = self.chars[digits_start..self.pos].iter().collect::<String>();
+        let signed = if negative { format!("-{}", digits) } else { digits };
+        signed.parse::<Number>().or_else(|_| self.error(start + 1, format!("number '{}' out of range", text)))
+    }
+
+    fn expr(&mut self) -> Result<Expr, AsmError> {
+        self.skip_whitespace();
+        let column = self.column();
+        match self.ident() {
+            Some(name) => {
+                let mut offset = 0;
+                if self.eat("+") {
+                    offset = self.number()?;
+                } else if self.eat("-") {
+                    offset = self.signed_number(true)?;
+                }
+                Ok(Expr { label: Some((name, self.line, column)), offset })
+            },
+            None => Ok(Expr::value(self.number()?)),
+        }
+    }
+
+    fn operand(&mut self) -> Result<Operand, AsmError> {
+        self.skip_whitespace();
+        let column = self.column();
+        if self.eat("#") {
+            Ok(Operand { mode: ArgMode::Immediate, expr: self.expr()?, column })
+        } else if self.eat("[") {
+            let start = self.pos;
+            let operand = if self.ident().as_deref() == Some("rb") {
+                let expr = if self.eat("+") {
+                    self.expr()?
+                } else if self.eat("-") {
+                    Expr::value(self.signed_number(true)?)
+                } else {
+                    Expr::value(0)
+                };
+                Operand { mode: ArgMode::Relative, expr, column }
+            } else {
+                self.pos = start;
+                Operand { mode: ArgMode::Position, expr: self.expr()?, column }
+            };
+            self.expect("]")?;
+            Ok(operand)
+        } else {
+            self.error(column, "expected operand ('#imm', '[pos]' or '[rb+n]')".to_string())
+        }
+    }
+
+    fn operands(&mut self, mnemonic: &str, count: usize) -> Result<Vec<Operand>, AsmError> {
+        let mut operands = vec![];
+        if !self.at_end() {
+            self.eat("->");
+            operands.push(self.operand()?);
+            while self.eat(",") || self.eat("->") {
+                operands.push(self.operand()?);
+            }
+        }
+        if !self.at_end() {
+            return self.error(self.column(), "expected ',' or end of line".to_string());
+        }
+        if operands.len() != count {
+            return self.error(self.column(), format!("{} takes {} operands, got {}", mnemonic, count, operands.len()));
+        }
+        Ok(operands)
+    }
+
+    fn parse_line(&mut self) -> Result<(), AsmError> {
+        self.skip_whitespace();
+        if self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            let column = self.column();
+            let addr = self.number()?;
+            self.expect(":")?;
+            let cur = self.asm.words.len();
+            if addr < cur as Number {
+                return self.error(column, format!("address {} lies before current address {}", addr, cur));
+            }
+            if addr >= DEFAULT_MEMORY_LIMIT as Number {
+                return self.error(column, format!("address {} exceeds memory limit {}", addr, DEFAULT_MEMORY_LIMIT));
+            }
+            self.asm.words.resize(addr as Addr, Expr::value(0));
+        }
+        loop {
+            if self.at_end() {
+                return Ok(());
+            }
+            let column = self.column();
+            let name = match self.ident() {
+                Some(name) => name,
+                None => return self.error(column, "expected label or statement".to_string()),
+            };
+            if self.eat(":") {
+                let addr = self.asm.words.len();
+                if self.asm.symbols.insert(name.clone(), addr).is_some() {
+                    return self.error(column, format!("duplicate label '{}'", name));
+                }
+                continue;
+            }
+            self.statement(&name, column)?;
+            if !self.at_end() {
+                return self.error(self.column(), "expected end of line".to_string());
+            }
+            return Ok(());
+        }
+    }
+
+    fn statement(&mut self, name: &str, column: usize) -> Result<(), AsmError> {
+        let upper = name.to_ascii_uppercase();
+        match upper.as_str() {
+            ".DATA" | "DATA" => {
+                self.data()
+            },
+            ".STRING" => {
+                self.string()
+            },
+            "PUSH" => {
+                let value = self.operands(&upper, 1)?.remove(0);
+                self.instruction(Op::Add, vec![value, imm(0), rel(0)])?;
+                self.instruction(Op::Arb, vec![imm(1)])
+            },
+            "POP" => {
+                let dest = self.operands(&upper, 1)?.remove(0);
+                self.instruction(Op::Add, vec![rel(-1), imm(0), dest])?;
+                self.instruction(Op::Arb, vec![imm(-1)])
+            },
+            "CALL" => {
+                let target = self.operands(&upper, 1)?.remove(0);
+                let ret = self.asm.words.len() + 4 + 2 + 3;
+                self.instruction(Op::Add, vec![imm(ret as Number), imm(0), rel(0)])?;
+                self.instruction(Op::Arb, vec![imm(1)])?;
+                self.instruction(Op::Jnz, vec![imm(1), target])
+            },
+            "RET" => {
+                self.operands(&upper, 0)?;
+                self.instruction(Op::Arb, vec![imm(-1)])?;
+                self.instruction(Op::Jz, vec![imm(0), rel(0)])
+            },
+            _ => match Op::from_mnemonic(&upper) {
+                Some(op) => {
+                    let operands = self.operands(&upper, op.num_operands())?;
+                    self.instruction(op, operands)
+                },
+                None => self.error(column, format!("unknown mnemonic '{}'", name)),
+            },
+        }
+    }
+
+    fn instruction(&mut self, op: Op, operands: Vec<Operand>) -> Result<(), AsmError> {
+        let mut word = op.opcode();
+        let mut factor = 100;
+        for (i, operand) in operands.iter().enumerate() {
+            if operand.mode == ArgMode::Immediate && i >= op.num_inputs() {
+                return self.error(operand.column, format!("output operand of {} cannot be immediate", op.mnemonic()));
+            }
+            word += operand.mode.to_number() * factor;
+            factor *= 10;
+        }
+        self.asm.words.push(Expr::value(word));
+        self.asm.words.extend(operands.into_iter().map(|operand| operand.expr));
+        Ok(())
+    }
+
+    fn data(&mut self) -> Result<(), AsmError> {
+        loop {
+            let expr = self.expr()?;
+            self.asm.words.push(expr);
+            if !self.eat(",") {
+                return Ok(());
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<(), AsmError> {
+        self.expect("\"")?;
+        loop {
+            let column = self.column();
+            let c = match self.peek() {
+                Some('"') => {
+                    self.pos += 1;
+                    return Ok(());
+                },
+                Some('\\') => {
+                    self.pos += 1;
+                    match self.peek() {
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some('\\') => '\\',
+                        Some('"') => '"',
+                        _ => return self.error(column, "invalid escape sequence".to_string()),
+                    }
+                },
+                Some(c) if c.is_ascii() => c,
+                Some(c) => return self.error(column, format!("non-ASCII character '{}'", c)),
+                None => return self.error(column, "unterminated string".to_string()),
+            };
+            self.pos += 1;
+            self.asm.words.push(Expr::value(c as Number));
+        }
+    }
+}
+
+fn imm(value: Number) -> Operand {
+    Operand { mode: ArgMode::Immediate, expr: Expr::value(value), column: 0 }
+}
+
+fn rel(value: Number) -> Operand {
+    Operand { mode: ArgMode::Relative, expr: Expr::value(value), column: 0 }
+}
+
+#[test]
+fn test_assemble() {
+    use super::Program;
+
+    assert_eq!(
+        assemble("ADD [9], #10 -> [3]\nMUL [3], [11], [0]\nHLT\n.data 30, 40, 50").unwrap(),
+        Memory::parse("1001,9,10,3,2,3,11,0,99,30,40,50"));
+    assert_eq!(
+        Program::new(assemble("
+            ; Echoes input until it reads a zero.
+            ARB #stack
+            loop: IN -> [rb+0]
+                  JZ [rb], #end
+                  PUSH [rb+0]
+                  POP -> [tmp]
+                  CALL #print
+                  JNZ #1, #loop
+            end:  HLT
+            print: OUT [tmp]
+                   RET
+            tmp: .data 0
+            stack:
+        ").unwrap()).run_with_io(vec![3, -1, 0]),
+        vec![3, -1]);
+    assert_eq!(
+        Program::new(assemble(".string \"hi\\n\"").unwrap()).mem,
+        Memory::parse("104,105,10"));
+}
+
+#[test]
+fn test_errors() {
+    assert_eq!(
+        assemble("HLT\n  FOO [1]").unwrap_err().to_string(),
+        "2:3: unknown mnemonic 'FOO'");
+    assert_eq!(
+        assemble("ADD #1, #2 -> #3").unwrap_err().to_string(),
+        "1:15: output operand of ADD cannot be immediate");
+    assert_eq!(
+        assemble("JNZ #1, #nowhere").unwrap_err().to_string(),
+        "1:10: undefined label 'nowhere'");
+    assert_eq!(
+        assemble("OUT [1").unwrap_err().to_string(),
+        "1:7: expected ']'");
+    assert_eq!(
+        assemble("ADD #1, #2").unwrap_err().to_string(),
+        "1:11: ADD takes 3 operands, got 2");
+    assert_eq!(
+        assemble("HLT\n0000: HLT").unwrap_err().to_string(),
+        "2:1: address 0 lies before current address 1");
+    assert_eq!(
+        assemble("HLT\n99999999999: HLT").unwrap_err().to_string(),
+        "2:1: address 99999999999 exceeds memory limit 16777216");
+    assert_eq!(
+        assemble(&format!("OUT [rb-{}]", Number::MIN)).unwrap_err().to_string(),
+        format!("1:9: number '{}' out of range", Number::MIN));
+    assert_eq!(
+        assemble(&format!("x: OUT [x-{}]", Number::MIN)).unwrap_err().to_string(),
+        format!("1:11: number '{}' out of range", Number::MIN));
+    assert_eq!(
+        assemble(&format!("HLT\nx: OUT [x+{}]", Number::MAX)).unwrap_err().to_string(),
+        format!("2:9: label 'x' plus offset {} overflows", Number::MAX));
+}
+
+#[test]
+fn test_listing_roundtrip() {
+    use super::disasm::{disassemble, disassemble_with_symbols};
+
+    let source = "
+        start: IN -> [value]
+               JZ [value], #done
+               OUT [value]
+               JNZ #1, #start
+        done:  HLT
+        value: .data -7
+    ";
+    let (mem, symbols) = assemble_with_symbols(source).unwrap();
+    let listing = disassemble_with_symbols(&mem, &symbols).to_string();
+    assert_eq!(
+        listing,
+        "start:\n\
+         0000: IN -> [value]\n\
+         0002: JZ [value], #done\n\
+         0005: OUT [value]\n\
+         0007: JNZ #1, #start\n\
+         done:\n\
+         0010: HLT\n\
+         value:\n\
+         0011: DATA -7\n");
+    assert_eq!(assemble(&listing).unwrap(), mem);
+
+    let mem = Memory::parse(&format!("204,{},99", Number::MIN));
+    assert_eq!(assemble(&disassemble(&mem).to_string()).unwrap(), mem);
+
+    let mem = Memory::parse("3,21,1008,21,8,20,1005,20,22,107,8,21,20,1006,20,31,1106,0,36,98,0,0,1002,21,125,20,4,20,1105,1,46,104,999,1105,1,46,1101,1000,1,20,4,20,1105,1,46,98,99");
+    assert_eq!(assemble(&disassemble(&mem).to_string()).unwrap(), mem);
+}
@@ -0,0 +1,372 @@
+//! An execution engine that decodes each instruction once and caches the result.
+//!
+//! The plain interpreter decodes the opcode and parameter modes of every instruction each time it
+//! executes it. `CachedProgram` keeps the decoded form of every instruction in the initial
+//! program, indexed by address, so that tight loops only pay for decoding on their first
+//! iteration. Since Intcode programs may modify themselves, every write invalidates the cached
+//! instructions that overlap the written address. Custom instructions may write anywhere, so
+//! the whole cache is dropped after each of them.
+//!
+//! Input, output, halting and faults are delegated to the wrapped `Program`, so the resulting state
+//! is identical to running the interpreter. Tracers are not supported; use the interpreter for that.
+
+use std::time::Instant;
+
+use super::opcodes::STANDARD_OPCODES;
+use super::{to_addr, Addr, ArgMode, IntcodeError, Interrupt, Memory, Number, Overflow, Program};
+
+/// Longest instruction, in words; a write can overlap at most this many instructions.
+const MAX_INSTRUCTION_LEN: Addr = 4;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Operand {
+    Immediate(Number),
+    Position(Addr),
+    Relative(Number),
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Instr {
+    Add(Operand, Operand, Operand),
+    Mul(Operand, Operand, Operand),
+    Lt(Operand, Operand, Operand),
+    Eq(Operand, Operand, Operand),
+    Jnz(Operand, Operand),
+    Jz(Operand, Operand),
+    Arb(Operand),
+    /// Input, output, halt and invalid instructions are executed by the interpreter.
+    Interpret,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+struct Decoded {
+    instr: Instr,
+    len: Addr,
+    /// The value of `Program::cur_op` after the interpreter executes this instruction.
+    cur_op: Number,
+}
+
+const INTERPRET: Decoded = Decoded { instr: Instr::Interpret, len: 0, cur_op: 0 };
+
+fn decode(mem: &Memory, addr: Addr) -> Decoded {
+    let op = mem[addr];
+    let mut modes = op / 100;
+    let mut operand = |i: Addr, lvalue: bool| {
+        let mode = ArgMode::from_number(modes % 10);
+        modes /= 10;
+        let val = mem[addr + i];
+        match mode {
+            Some(ArgMode::Position) if val >= 0 && to_addr(val) < mem.limit() => Some(Operand::Position(to_addr(val))),
+            Some(ArgMode::Immediate) if !lvalue => Some(Operand::Immediate(val)),
+            Some(ArgMode::Relative) => Some(Operand::Relative(val)),
+            _ => None,
+        }
+    };
+    let instr = match op % 100 {
+        opcode @ 1 | opcode @ 2 | opcode @ 7 | opcode @ 8 => {
+            let (a, b, c) = match (operand(1, false), operand(2, false), operand(3, true)) {
+                (Some(a), Some(b), Some(c)) => (a, b, c),
+                _ => return INTERPRET,
+            };
+            match opcode {
+                1 => Instr::Add(a, b, c),
+                2 => Instr::Mul(a, b, c),
+                7 => Instr::Lt(a, b, c),
+                _ => Instr::Eq(a, b, c),
+            }
+        },
+        opcode @ 5 | opcode @ 6 => {
+            let (a, b) = match (operand(1, false), operand(2, false)) {
+                (Some(a), Some(b)) => (a, b),
+                _ => return INTERPRET,
+            };
+            if opcode == 5 { Instr::Jnz(a, b) } else { Instr::Jz(a, b) }
+        },
+        9 => match operand(1, false) {
+            Some(a) => Instr::Arb(a),
+            None => return INTERPRET,
+        },
+        _ => return INTERPRET,
+    };
+    let len = match instr {
+        Instr::Arb(_) => 2,
+        Instr::Jnz(..) | Instr::Jz(..) => 3,
+        _ => 4,
+    };
+    Decoded { instr, len, cur_op: modes }
+}
+
+/// A `Program` that runs with a cache of decoded instructions. Memory can only be modified through
+/// `poke`, so that the cache stays valid.
+#[derive(Debug, Clone)]
+pub struct CachedProgram {
+    program: Program,
+    cache: Vec<Option<Decoded>>,
+}
+
+impl CachedProgram {
+    pub fn new(program: Program) -> Self {
+        let cache = vec![None; program.mem.end()];
+        CachedProgram { program, cache }
+    }
+
+    pub fn parse(input: &str) -> Self {
+        Self::new(Program::parse(input))
+    }
+
+    pub fn program(&self) -> &Program {
+        &self.program
+    }
+
+    pub fn into_program(self) -> Program {
+        self.program
+    }
+
+    pub fn poke(&mut self, addr: Addr, val: Number) {
+        self.write(addr, val);
+    }
+
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.program.set_fuel(fuel);
+    }
+
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.program.set_deadline(deadline);
+    }
+
+    pub fn run_until_interrupt(&mut self) -> Interrupt {
+        self.try_run_until_interrupt().unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    pub fn try_run_until_interrupt(&mut self) -> Result<Interrupt, IntcodeError> {
+        if let Some(fault) = &self.program.fault {
+            return Err(fault.clone());
+        }
+        // Promoted values are not in memory, so everything is left to the interpreter.
+        let promote = self.program.overflow == Overflow::Promote;
+        loop {
+            if let Some(interrupt) = self.program.interrupt.or_else(|| self.program.use_budget()) {
+                return Ok(interrupt);
+            }
+            let ip = self.program.ip;
+            let decoded = match self.cache.get(ip) {
+                Some(Some(decoded)) => *decoded,
+                Some(None) => {
+                    let decoded = decode(&self.program.mem, ip);
+                    self.cache[ip] = Some(decoded);
+                    decoded
+                },
+                // Code outside the initial program is rare enough that it need not be cached.
+                None => decode(&self.program.mem, ip),
+            };
+            if decoded.instr == Instr::Interpret || promote || self.execute(ip, decoded).is_none() {
+                // The interpreter also raises any fault, such as an invalid address.
+                self.program.ip = ip;
+                if self.program.instruction_set.is_some() && !STANDARD_OPCODES.contains(&(self.program.mem[ip] % 100)) {
+                    self.cache.iter_mut().for_each(|entry| *entry = None);
+                }
+                match self.program.execute() {
+                    Ok(None) => {},
+                    Ok(Some(interrupt)) => return Ok(interrupt),
+                    Err(err) => return Err(self.program.set_fault(err)),
+                }
+            }
+        }
+    }
+
+    /// Executes a decoded instruction. Returns `None` without side effects if an address is
+    /// invalid or the arithmetic overflows.
+    fn execute(&mut self, ip: Addr, decoded: Decoded) -> Option<()> {
+        self.program.cur_ip = ip;
+        self.program.cur_op = decoded.cur_op;
+        self.program.ip = ip + decoded.len;
+        let overflow = self.program.overflow;
+        match decoded.instr {
+            Instr::Add(a, b, c) => self.store(c, overflow.add(self.load(a)?, self.load(b)?)?),
+            Instr::Mul(a, b, c) => self.store(c, overflow.mul(self.load(a)?, self.load(b)?)?),
+            Instr::Lt(a, b, c) => self.store(c, if self.load(a)? < self.load(b)? { 1 } else { 0 }),
+            Instr::Eq(a, b, c) => self.store(c, if self.load(a)? == self.load(b)? { 1 } else { 0 }),
+            Instr::Jnz(a, b) => self.jump(self.load(a)? != 0, b),
+            Instr::Jz(a, b) => self.jump(self.load(a)? == 0, b),
+            Instr::Arb(a) => {
+                self.program.relative_base = overflow.add(self.program.relative_base, self.load(a)?)?;
+                Some(())
+            },
+            Instr::Interpret => unreachable!(),
+        }
+    }
+
+    pub fn give_input(&mut self, val: Number) {
+        self.try_give_input(val).unwrap_or_else(|err| panic!("Attempted to give input: {}", err))
+    }
+
+    pub fn try_give_input(&mut self, val: Number) -> Result<(), IntcodeError> {
+        self.try_run_until_interrupt()?;
+        let dest = self.input_dest();
+        self.program.try_give_input(val)?;
+        if let Some(dest) = dest {
+            self.invalidate(dest);
+        }
+        Ok(())
+    }
+
+    pub fn take_output(&mut self) -> Number {
+        self.try_take_output().unwrap_or_else(|err| panic!("Attempted to take output: {}", err))
+    }
+
+    pub fn try_take_output(&mut self) -> Result<Number, IntcodeError> {
+        self.try_run_until_interrupt()?;
+        self.program.try_take_output()
+    }
+
+    pub fn run_with_io(&mut self, input: Vec<Number>) -> Vec<Number> {
+        self.try_run_with_io(input).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    pub fn try_run_with_io(&mut self, input: Vec<Number>) -> Result<Vec<Number>, IntcodeError> {
+        let mut input = input.into_iter();
+        let mut output = vec![];
+        loop {
+            match self.try_run_until_interrupt()? {
+                Interrupt::Reading => match input.next() {
+                    Some(val) => self.try_give_input(val)?,
+                    None => return Err(IntcodeError::InputExhausted { ip: self.program.cur_ip }),
+                },
+                Interrupt::Writing => output.push(self.try_take_output()?),
+                Interrupt::Halted => return Ok(output),
+                actual => return Err(IntcodeError::UnexpectedInterrupt { expected: Interrupt::Halted, actual }),
+            }
+        }
+    }
+
+    fn relative_addr(&self, offset: Number) -> Option<Addr> {
+        let addr = offset.checked_add(self.program.relative_base)?;
+        if addr >= 0 && to_addr(addr) < self.program.mem.limit() { Some(to_addr(addr)) } else { None }
+    }
+
+    fn load(&self, operand: Operand) -> Option<Number> {
+        match operand {
+            Operand::Immediate(val) => Some(val),
+            Operand::Position(addr) => Some(self.program.mem[addr]),
+            Operand::Relative(offset) => self.relative_addr(offset).map(|addr| self.program.mem[addr]),
+        }
+    }
+
+    fn store(&mut self, operand: Operand, val: Number) -> Option<()> {
+        let addr = match operand {
+            Operand::Position(addr) => addr,
+            Operand::Relative(offset) => self.relative_addr(offset)?,
+            Operand::Immediate(_) => unreachable!(),
+        };
+        self.write(addr, val);
+        Some(())
+    }
+
+    fn jump(&mut self, cond: bool, dest: Operand) -> Option<()> {
+        let dest = self.load(dest)?;
+        if cond {
+            if dest < 0 || to_addr(dest) >= self.program.mem.limit() {
+                return None;
+            }
+            self.program.ip = to_addr(dest);
+        }
+        Some(())
+    }
+
+    fn write(&mut self, addr: Addr, val: Number) {
+        self.program.write(addr, val);
+        self.invalidate(addr);
+    }
+
+    fn invalidate(&mut self, addr: Addr) {
+        let start = addr.saturating_sub(MAX_INSTRUCTION_LEN - 1);
+        let end = (addr + 1).min(self.cache.len());
+        for entry in self.cache.iter_mut().take(end).skip(start) {
+            *entry = None;
+        }
+    }
+
+    /// The address that the pending input instruction will write to, if it is valid. Otherwise
+    /// the interpreter faults.
+    fn input_dest(&self) -> Option<Addr> {
+        let program = &self.program;
+        if program.interrupt != Some(Interrupt::Reading) {
+            return None;
+        }
+        let arg = program.mem[program.cur_ip + 1];
+        match ArgMode::from_number(program.cur_op % 10) {
+            Some(ArgMode::Position) if arg >= 0 => Some(to_addr(arg)),
+            Some(ArgMode::Relative) => self.relative_addr(arg),
+            _ => None,
+        }
+    }
+}
+
+impl From<Program> for CachedProgram {
+    fn from(program: Program) -> Self {
+        Self::new(program)
+    }
+}
+
+#[cfg(test)]
+fn assert_same_as_interpreter(program: &str, input: Vec<Number>) {
+    let mut interpreted = Program::parse(program);
+    let mut cached = CachedProgram::parse(program);
+    assert_eq!(cached.try_run_with_io(input.clone()), interpreted.try_run_with_io(input));
+    assert_eq!(cached.program(), &interpreted);
+}
+
+#[test]
+fn test_same_as_interpreter() {
+    // Day 9 quine.
+    assert_same_as_interpreter("109,1,204,-1,1001,100,1,100,1008,100,16,101,1006,101,0,99", vec![]);
+    // Day 5 comparison of the input with 8.
+    let compare = "3,21,1008,21,8,20,1005,20,22,107,8,21,20,1006,20,31,1106,0,36,98,0,0,1002,21,125,20,4,20,1105,1,46,104,999,1105,1,46,1101,1000,1,20,4,20,1105,1,46,98,99";
+    for input in 6..=10 {
+        assert_same_as_interpreter(compare, vec![input]);
+    }
+    // Faults and exhausted input.
+    assert_same_as_interpreter("1,0,0,0,42", vec![]);
+    assert_same_as_interpreter("1101,1,1,5,1,0,0,0,99", vec![]);
+    assert_same_as_interpreter("11101,1,1,5,99", vec![]);
+    assert_same_as_interpreter("3,0,3,0,99", vec![1]);
+    assert_same_as_interpreter("109,-5,22201,1,2,0,99", vec![]);
+    assert_same_as_interpreter("1101,0,-1,100,1005,100,-2,99", vec![]);
+    assert_same_as_interpreter("1,-1,0,0,99", vec![]);
+    assert_same_as_interpreter(&format!("1101,{},1,0,99", Number::MAX), vec![]);
+    assert_same_as_interpreter(&format!("109,{},109,1,99", Number::MAX), vec![]);
+    assert_same_as_interpreter(&format!("109,{},203,1,99", Number::MAX), vec![1]);
+    assert_same_as_interpreter("3,-1,99", vec![1]);
+    let mut wrapping = CachedProgram::new(Program::parse(&format!("1101,{},1,0,99", Number::MAX)).with_overflow(Overflow::Wrap));
+    assert_eq!(wrapping.run_until_interrupt(), Interrupt::Halted);
+    assert_eq!(wrapping.program().mem[0], Number::MIN);
+}
+
+#[test]
+fn test_budget() {
+    let count = "1001,100,1,100,1105,1,0";
+    let mut interpreted = Program::parse(count).with_fuel(11);
+    let mut cached = CachedProgram::new(Program::parse(count).with_fuel(11));
+    assert_eq!(cached.run_until_interrupt(), Interrupt::OutOfFuel);
+    assert_eq!(interpreted.run_until_interrupt(), Interrupt::OutOfFuel);
+    assert_eq!(cached.program(), &interpreted);
+}
+
+#[test]
+fn test_self_modifying() {
+    // Counts up by one to 5, then patches the increment of the cached add instruction to 10 and
+    // counts on past 50.
+    let program = "1001,100,1,100,1007,100,5,101,1005,101,0,1101,0,10,2,1007,100,50,101,1005,101,0,4,100,99";
+    assert_eq!(CachedProgram::parse(program).run_with_io(vec![]), vec![55]);
+    assert_same_as_interpreter(program, vec![]);
+    // Reads an input over an operand of an instruction that already ran, and runs it again.
+    let program = "1101,1,0,100,4,100,1005,101,18,3,1,1101,1,0,101,1105,1,0,99";
+    assert_eq!(CachedProgram::parse(program).run_with_io(vec![7]), vec![1, 7]);
+    assert_same_as_interpreter(program, vec![7]);
+
+    // Outputs 1 + 0 forever, until the 1 is poked.
+    let mut cached = CachedProgram::parse("1101,1,0,100,4,100,1105,1,0");
+    assert_eq!(cached.take_output(), 1);
+    cached.poke(1, 5);
+    assert_eq!(cached.take_output(), 5);
+}
@@ -0,0 +1,359 @@
+//! Static control flow analysis: splits memory into basic blocks reachable from address 0, and
+//! recognizes functions by the relative-base calling convention.
+//!
+//! A call stores its return address in a relative-mode cell, then jumps unconditionally to the
+//! function:
+//!
+//! ```text
+//! ADD #ret, #0 -> [rb+1]
+//! JNZ #1, #function
+//! ret: ...
+//! ```
+//!
+//! and the function returns with an unconditional jump to a relative-mode operand, typically after
+//! restoring the relative base:
+//!
+//! ```text
+//! ARB #-2
+//! JZ #0, [rb+1]
+//! ```
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+use super::disasm::{Instruction, Op, Operand};
+use super::{Addr, ArgMode, Memory, Number};
+
+/// Where a jump goes.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Target {
+    /// The destination is an immediate-mode operand.
+    Static(Addr),
+    /// The destination is read from memory, so it is not known statically.
+    Indirect(Operand),
+}
+
+/// How control leaves a basic block.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Terminator {
+    /// Falls through to the block at the given address, because it is a jump target.
+    Fallthrough(Addr),
+    /// Jumps unconditionally.
+    Jump(Target),
+    /// Jumps if the condition holds, and falls through to the given address otherwise.
+    Branch(Target, Addr),
+    /// Calls a function, which returns to the given address.
+    Call(Addr, Addr),
+    /// Returns from a function, to an address read from a relative-mode operand.
+    Return(Operand),
+    Halt,
+    /// The word at the given address does not decode as an instruction; executing it faults.
+    Invalid(Addr),
+}
+
+impl Terminator {
+    /// Blocks that control may go to next, within the same function. Calls continue at their return
+    /// address.
+    pub fn successors(&self) -> Vec<Addr> {
+        match *self {
+            Terminator::Fallthrough(next) | Terminator::Jump(Target::Static(next)) | Terminator::Call(_, next) => vec![next],
+            Terminator::Branch(Target::Static(target), next) => vec![target, next],
+            Terminator::Branch(Target::Indirect(_), next) => vec![next],
+            Terminator::Jump(Target::Indirect(_)) | Terminator::Return(_) | Terminator::Halt | Terminator::Invalid(_) => vec![],
+        }
+    }
+}
+
+/// A sequence of instructions that is only entered at the top, and only left at the bottom.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Block {
+    pub start: Addr,
+    pub instructions: Vec<(Addr, Instruction)>,
+    pub terminator: Terminator,
+}
+
+/// A function, found as the target of a call.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Function {
+    pub entry: Addr,
+    /// The size of the stack frame, if the function starts by adjusting the relative base.
+    pub frame_size: Option<Number>,
+    /// Start addresses of the blocks that are reachable from the entry without following calls.
+    pub blocks: BTreeSet<Addr>,
+}
+
+/// The control flow graph of a program.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Cfg {
+    pub blocks: BTreeMap<Addr, Block>,
+    pub functions: BTreeMap<Addr, Function>,
+}
+
+/// Whether a conditional jump is taken, if the condition is an immediate-mode operand.
+fn always_taken(instr: &Instruction) -> Option<bool> {
+    let cond = instr.operands[0];
+    if cond.mode != ArgMode::Immediate {
+        return None;
+    }
+    Some((cond.value != 0) == (instr.op == Op::Jnz))
+}
+
+/// The value written by an instruction whose inputs are both in immediate mode.
+fn constant_result(instr: &Instruction) -> Option<Number> {
+    if instr.operands[..instr.op.num_inputs()].iter().any(|operand| operand.mode != ArgMode::Immediate) {
+        return None;
+    }
+    match instr.op {
+        Op::Add => instr.operands[0].value.checked_add(instr.operands[1].value),
+        Op::Mul => instr.operands[0].value.checked_mul(instr.operands[1].value),
+        _ => None,
+    }
+}
+
+fn target(operand: Operand) -> Target {
+    if operand.mode == ArgMode::Immediate && operand.value >= 0 {
+        Target::Static(operand.value as Addr)
+    } else {
+        Target::Indirect(operand)
+    }
+}
+
+/// How control leaves the instruction at `addr`, or `None` if it just continues with the next one.
+/// `prev` is the instruction executed right before it, if any, which may store a return address.
+fn flow(addr: Addr, instr: &Instruction, prev: Option<&Instruction>) -> Option<Terminator> {
+    let next = addr + instr.num_words();
+    match instr.op {
+        Op::Hlt => Some(Terminator::Halt),
+        Op::Jnz | Op::Jz => {
+            let dest = instr.operands[1];
+            match always_taken(instr) {
+                Some(false) => None,
+                None => Some(Terminator::Branch(target(dest), next)),
+                Some(true) if dest.mode == ArgMode::Relative => Some(Terminator::Return(dest)),
+                Some(true) => {
+                    let stores_return = prev.is_some_and(|prev| {
+                        prev.op.has_output() &&
+                            prev.operands.last().unwrap().mode == ArgMode::Relative &&
+                            constant_result(prev) == Some(next as Number)
+                    });
+                    match target(dest) {
+                        Target::Static(entry) if stores_return => Some(Terminator::Call(entry, next)),
+                        dest => Some(Terminator::Jump(dest)),
+                    }
+                },
+            }
+        },
+        _ => None,
+    }
+}
+
+/// Builds the control flow graph of the code reachable from address 0. Instructions are decoded
+/// with `Instruction::decode`, so they follow the same rules as `Program::run_until_interrupt`.
+/// Self-modifying code is analyzed as it is in `mem`.
+pub fn analyze(mem: &Memory) -> Cfg {
+    // Find all reachable instructions, and the addresses at which blocks start.
+    let mut instructions = BTreeMap::new();
+    let mut leaders = BTreeSet::new();
+    let mut entries = BTreeSet::new();
+    let mut todo = vec![0];
+    leaders.insert(0);
+    while let Some(mut addr) = todo.pop() {
+        let mut prev = None;
+        while addr < mem.limit() && !instructions.contains_key(&addr) {
+            let instr = match Instruction::decode(mem, addr) {
+                Some(instr) => instr,
+                None => break,
+            };
+            let terminator = flow(addr, &instr, prev.as_ref());
+            let next = addr + instr.num_words();
+            instructions.insert(addr, instr.clone());
+            if let Some(terminator) = terminator {
+                if let Terminator::Call(entry, _) = terminator {
+                    entries.insert(entry);
+                    leaders.insert(entry);
+                    todo.push(entry);
+                }
+                for successor in terminator.successors() {
+                    leaders.insert(successor);
+                    todo.push(successor);
+                }
+                break;
+            }
+            prev = Some(instr);
+            addr = next;
+        }
+    }
+
+    // Split them into blocks.
+    let mut blocks = BTreeMap::new();
+    for &start in &leaders {
+        let mut block = Block { start, instructions: vec![], terminator: Terminator::Invalid(start) };
+        let mut addr = start;
+        loop {
+            let instr = match instructions.get(&addr) {
+                Some(instr) => instr,
+                None => {
+                    block.terminator = Terminator::Invalid(addr);
+                    break;
+                },
+            };
+            let terminator = flow(addr, instr, block.instructions.last().map(|(_, prev)| prev));
+            block.instructions.push((addr, instr.clone()));
+            addr += instr.num_words();
+            if let Some(terminator) = terminator {
+                block.terminator = terminator;
+                break;
+            }
+            if leaders.contains(&addr) {
+                block.terminator = Terminator::Fallthrough(addr);
+                break;
+            }
+        }
+        blocks.insert(start, block);
+    }
+
+    let mut cfg = Cfg { blocks, functions: BTreeMap::new() };
+    for entry in entries {
+        let frame_size = cfg.blocks[&entry].instructions.first()
+            .filter(|(_, instr)| instr.op == Op::Arb && instr.operands[0].mode == ArgMode::Immediate)
+            .map(|(_, instr)| instr.operands[0].value);
+        let blocks = cfg.blocks_from(entry);
+        cfg.functions.insert(entry, Function { entry, frame_size, blocks });
+    }
+    cfg
+}
+
+fn node_name(addr: Addr) -> String {
+    format!("b{:04}", addr)
+}
+
+pub fn function_name(addr: Addr) -> String {
+    format!("F{:04}", addr)
+}
+
+impl Cfg {
+    /// Start addresses of the blocks that are reachable from `entry` without following calls.
+    pub fn blocks_from(&self, entry: Addr) -> BTreeSet<Addr> {
+        let mut reachable = BTreeSet::new();
+        let mut todo = vec![entry];
+        while let Some(start) = todo.pop() {
+            if reachable.insert(start) {
+                todo.extend(self.blocks[&start].terminator.successors());
+            }
+        }
+        reachable
+    }
+
+    /// Formats the graph in Graphviz DOT format. Functions are drawn as clusters; a block that is
+    /// shared by several functions is drawn in the first one. Unresolved indirect jumps go to red
+    /// nodes showing the operand that holds the destination.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::new();
+        writeln!(dot, "digraph cfg {{").unwrap();
+        writeln!(dot, "    node [shape=box, fontname=monospace];").unwrap();
+
+        let mut drawn = BTreeSet::new();
+        for function in self.functions.values() {
+            writeln!(dot, "    subgraph cluster_{} {{", function_name(function.entry)).unwrap();
+            match function.frame_size {
+                Some(size) => writeln!(dot, "        label=\"{} (frame {})\";", function_name(function.entry), size).unwrap(),
+                None => writeln!(dot, "        label=\"{}\";", function_name(function.entry)).unwrap(),
+            }
+            for &start in &function.blocks {
+                if drawn.insert(start) {
+                    writeln!(dot, "        {}", self.node(start)).unwrap();
+                }
+            }
+            writeln!(dot, "    }}").unwrap();
+        }
+        for &start in self.blocks.keys() {
+            if drawn.insert(start) {
+                writeln!(dot, "    {}", self.node(start)).unwrap();
+            }
+        }
+
+        for block in self.blocks.values() {
+            let from = node_name(block.start);
+            let unresolved = format!("u{:04}", block.start);
+            let edges = match block.terminator {
+                Terminator::Fallthrough(next) | Terminator::Jump(Target::Static(next)) => vec![(node_name(next), "")],
+                Terminator::Jump(Target::Indirect(_)) => vec![(unresolved.clone(), " [style=dashed, color=red]")],
+                Terminator::Branch(Target::Static(target), next) => vec![(node_name(target), " [label=\"taken\"]"), (node_name(next), "")],
+                Terminator::Branch(Target::Indirect(_), next) => vec![(unresolved.clone(), " [label=\"taken\", style=dashed, color=red]"), (node_name(next), "")],
+                Terminator::Call(entry, next) => vec![(node_name(entry), " [label=\"call\", style=dashed]"), (node_name(next), " [label=\"return\", style=dotted]")],
+                Terminator::Return(_) | Terminator::Halt | Terminator::Invalid(_) => vec![],
+            };
+            if let Terminator::Jump(Target::Indirect(operand)) | Terminator::Branch(Target::Indirect(operand), _) = block.terminator {
+                writeln!(dot, "    {} [label=\"? {}\", shape=octagon, color=red];", unresolved, operand).unwrap();
+            }
+            for (to, attrs) in edges {
+                writeln!(dot, "    {} -> {}{};", from, to, attrs).unwrap();
+            }
+        }
+        writeln!(dot, "}}").unwrap();
+        dot
+    }
+
+    fn node(&self, start: Addr) -> String {
+        let block = &self.blocks[&start];
+        let mut label = String::new();
+        for (addr, instr) in &block.instructions {
+            write!(label, "{:04}: {}\\l", addr, instr).unwrap();
+        }
+        match block.terminator {
+            Terminator::Return(_) => label.push_str("(return)\\l"),
+            Terminator::Invalid(addr) => write!(label, "{:04}: (invalid)\\l", addr).unwrap(),
+            _ => {},
+        }
+        format!("{} [label=\"{}\"];", node_name(start), label)
+    }
+}
+
+#[test]
+fn test_analyze() {
+    use super::Program;
+
+    // Reads a number, calls a function that doubles it if it is nonzero, and outputs the result.
+    let program = Program::parse("109,100,203,0,21101,11,0,1,1105,1,16,204,0,104,-1,99,\
+                                  109,2,1206,-2,25,21202,-2,2,-2,109,-2,2106,0,1");
+    assert_eq!(program.clone().run_with_io(vec![5]), vec![10, -1]);
+    let mem = program.mem;
+    let cfg = analyze(&mem);
+    assert_eq!(cfg.blocks.keys().copied().collect::<Vec<_>>(), vec![0, 11, 16, 21, 25]);
+    assert_eq!(cfg.blocks[&0].terminator, Terminator::Call(16, 11));
+    assert_eq!(cfg.blocks[&0].instructions.len(), 4);
+    assert_eq!(cfg.blocks[&11].terminator, Terminator::Halt);
+    assert_eq!(cfg.blocks[&16].terminator, Terminator::Branch(Target::Static(25), 21));
+    assert_eq!(cfg.blocks[&21].terminator, Terminator::Fallthrough(25));
+    assert_eq!(cfg.blocks[&25].terminator, Terminator::Return(Operand { mode: ArgMode::Relative, value: 1 }));
+
+    let function = &cfg.functions[&16];
+    assert_eq!(function.frame_size, Some(2));
+    assert_eq!(function.blocks.iter().copied().collect::<Vec<_>>(), vec![16, 21, 25]);
+}
+
+#[test]
+fn test_indirect() {
+    // Jumps to an address read from input, after a jump that is never taken.
+    let mem = Memory::parse("3,9,1106,1,0,5,9,9,42");
+    let cfg = analyze(&mem);
+    assert_eq!(cfg.blocks.keys().copied().collect::<Vec<_>>(), vec![0, 8]);
+    assert_eq!(cfg.blocks[&0].terminator, Terminator::Branch(Target::Indirect(Operand { mode: ArgMode::Position, value: 9 }), 8));
+    assert_eq!(cfg.blocks[&8].terminator, Terminator::Invalid(8));
+    let dot = cfg.to_dot();
+    assert!(dot.contains("u0000 [label=\"? [9]\", shape=octagon, color=red]"));
+    assert!(dot.contains("b0000 -> u0000 [label=\"taken\", style=dashed, color=red];"));
+    assert!(dot.contains("b0000 -> b0008;"));
+}
+
+#[test]
+fn test_stray_mode_digits() {
+    use super::Program;
+
+    // Outputs 7 through an instruction with stray mode digits, which the interpreter ignores.
+    let program = Program::parse("1010104,7,10099");
+    assert_eq!(program.clone().run_with_io(vec![]), vec![7]);
+    let cfg = analyze(&program.mem);
+    assert_eq!(cfg.blocks[&0].instructions.len(), 2);
+    assert_eq!(cfg.blocks[&0].terminator, Terminator::Halt);
+}
@@ -0,0 +1,230 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use super::cfg;
+use super::disasm::{self, Instruction, Line};
+use super::trace::{Step, Tracer};
+use super::{Addr, ArgMode, Memory};
+
+/// How often a conditional jump went each way.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct Branch {
+    pub taken: u64,
+    pub not_taken: u64,
+}
+
+impl Branch {
+    /// Number of directions, out of two, that were seen.
+    pub fn directions_hit(&self) -> usize {
+        (self.taken > 0) as usize + (self.not_taken > 0) as usize
+    }
+}
+
+/// Covered and total numbers of instructions and of branch directions.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Summary {
+    pub instructions: usize,
+    pub instructions_hit: usize,
+    pub branches: usize,
+    pub branches_hit: usize,
+}
+
+/// A tracer that records which instructions were executed, and which way each conditional jump
+/// went. Jumps with an immediate-mode condition always go the same way, so they are not counted
+/// as branches.
+///
+/// Like `Profiler`, it can be shared between clones of a program through `Rc<RefCell<Coverage>>`,
+/// or separate ones can be combined with `merge`.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct Coverage {
+    executed: BTreeMap<Addr, u64>,
+    branches: BTreeMap<Addr, Branch>,
+}
+
+impl Tracer for Coverage {
+    fn step(&mut self, step: &Step) {
+        *self.executed.entry(step.ip).or_default() += 1;
+        if (step.opcode == 5 || step.opcode == 6) && step.modes()[0] != ArgMode::Immediate {
+            let branch = self.branches.entry(step.ip).or_default();
+            if (step.operands()[0] != 0) == (step.opcode == 5) {
+                branch.taken += 1;
+            } else {
+                branch.not_taken += 1;
+            }
+        }
+    }
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn count_at(&self, addr: Addr) -> u64 {
+        self.executed.get(&addr).cloned().unwrap_or(0)
+    }
+
+    pub fn branch_at(&self, addr: Addr) -> Branch {
+        self.branches.get(&addr).cloned().unwrap_or_default()
+    }
+
+    pub fn merge(&mut self, other: &Coverage) {
+        for (&addr, &count) in &other.executed {
+            *self.executed.entry(addr).or_default() += count;
+        }
+        for (&addr, branch) in &other.branches {
+            let merged = self.branches.entry(addr).or_default();
+            merged.taken += branch.taken;
+            merged.not_taken += branch.not_taken;
+        }
+    }
+
+    /// The instructions in `mem` that count as code: those reachable in its control flow graph,
+    /// and those that were executed.
+    fn code(&self, mem: &Memory) -> BTreeMap<Addr, Instruction> {
+        let mut code = cfg::analyze(mem).blocks.into_values()
+            .flat_map(|block| block.instructions)
+            .collect::<BTreeMap<_, _>>();
+        for &addr in self.executed.keys() {
+            if let Some(instr) = Instruction::decode(mem, addr) {
+                code.entry(addr).or_insert(instr);
+            }
+        }
+        code
+    }
+
+    fn is_branch(instr: &Instruction) -> bool {
+        instr.op.is_jump() && instr.operands[0].mode != ArgMode::Immediate
+    }
+
+    /// Counts covered instructions and branch directions. Pass the program as it was loaded,
+    /// because self-modifying code may have changed it since.
+    pub fn summary(&self, mem: &Memory) -> Summary {
+        let code = self.code(mem);
+        let branches = code.iter().filter(|(_, instr)| Self::is_branch(instr)).map(|(&addr, _)| addr).collect::<Vec<_>>();
+        Summary {
+            instructions: code.len(),
+            instructions_hit: code.keys().filter(|&&addr| self.count_at(addr) > 0).count(),
+            branches: 2 * branches.len(),
+            branches_hit: branches.iter().map(|&addr| self.branch_at(addr).directions_hit()).sum(),
+        }
+    }
+
+    /// Renders a listing of `mem` with execution counts. Instructions that were never executed are
+    /// marked with `!`, and branches that only went one way with `?`.
+    pub fn annotate(&self, mem: &Memory) -> String {
+        let code = self.code(mem);
+        let mut out = String::new();
+        for (addr, line) in &disasm::disassemble(mem).lines {
+            match line {
+                Line::Instruction(instr) if code.contains_key(addr) => {
+                    let count = self.count_at(*addr);
+                    if Self::is_branch(instr) {
+                        let branch = self.branch_at(*addr);
+                        let mark = if count == 0 { '!' } else if branch.directions_hit() < 2 { '?' } else { ' ' };
+                        writeln!(out, "{:>12} {}  {:04}: {}  (taken {}, not taken {})",
+                            count, mark, addr, instr, branch.taken, branch.not_taken).unwrap();
+                    } else {
+                        let mark = if count == 0 { '!' } else { ' ' };
+                        writeln!(out, "{:>12} {}  {:04}: {}", count, mark, addr, instr).unwrap();
+                    }
+                },
+                Line::Instruction(instr) => writeln!(out, "{:>14}  {:04}: {}", "", addr, instr).unwrap(),
+                Line::Data(n) => writeln!(out, "{:>14}  {:04}: DATA {}", "", addr, n).unwrap(),
+            }
+        }
+        let summary = self.summary(mem);
+        writeln!(out, "\nInstructions: {}/{}", summary.instructions_hit, summary.instructions).unwrap();
+        writeln!(out, "Branches: {}/{}", summary.branches_hit, summary.branches).unwrap();
+        out
+    }
+
+    /// Renders an lcov tracefile, which tools like `genhtml` can display. Line numbers refer to the
+    /// output of `disasm::disassemble(mem)`, which should be saved as `source_name`. The main
+    /// program and the functions found by `cfg::analyze` are reported as functions.
+    pub fn lcov(&self, mem: &Memory, source_name: &str) -> String {
+        let code = self.code(mem);
+        let listing = disasm::disassemble(mem);
+        let mut line_numbers = BTreeMap::new();
+        let mut line_number = 0;
+        for (addr, _) in &listing.lines {
+            if listing.labels.contains_key(addr) {
+                line_number += 1;
+            }
+            line_number += 1;
+            line_numbers.insert(*addr, line_number);
+        }
+
+        let mut out = String::new();
+        writeln!(out, "TN:\nSF:{}", source_name).unwrap();
+
+        let entries = std::iter::once((0, "main".to_string()))
+            .chain(cfg::analyze(mem).functions.keys().map(|&entry| (entry, cfg::function_name(entry))))
+            .filter(|(entry, _)| line_numbers.contains_key(entry))
+            .collect::<Vec<_>>();
+        for (entry, name) in &entries {
+            writeln!(out, "FN:{},{}", line_numbers[entry], name).unwrap();
+        }
+        for (entry, name) in &entries {
+            writeln!(out, "FNDA:{},{}", self.count_at(*entry), name).unwrap();
+        }
+        writeln!(out, "FNF:{}", entries.len()).unwrap();
+        writeln!(out, "FNH:{}", entries.iter().filter(|(entry, _)| self.count_at(*entry) > 0).count()).unwrap();
+
+        let mut branches = 0;
+        let branch_lines = code.iter()
+            .filter(|(_, instr)| Self::is_branch(instr))
+            .filter_map(|(addr, _)| line_numbers.get(addr).map(|&line| (*addr, line)));
+        for (block, (addr, line)) in branch_lines.enumerate() {
+            let branch = self.branch_at(addr);
+            for (i, count) in [branch.taken, branch.not_taken].iter().enumerate() {
+                if self.count_at(addr) == 0 {
+                    writeln!(out, "BRDA:{},{},{},-", line, block, i).unwrap();
+                } else {
+                    writeln!(out, "BRDA:{},{},{},{}", line, block, i, count).unwrap();
+                }
+            }
+            branches += 1;
+        }
+        let summary = self.summary(mem);
+        writeln!(out, "BRF:{}\nBRH:{}", 2 * branches, summary.branches_hit).unwrap();
+
+        for addr in code.keys() {
+            if let Some(line) = line_numbers.get(addr) {
+                writeln!(out, "DA:{},{}", line, self.count_at(*addr)).unwrap();
+            }
+        }
+        writeln!(out, "LF:{}\nLH:{}", summary.instructions, summary.instructions_hit).unwrap();
+        writeln!(out, "end_of_record").unwrap();
+        out
+    }
+}
+
+#[test]
+fn test_coverage() {
+    use super::Program;
+
+    // Outputs 0 and 1 if the input is zero, and only 1 otherwise.
+    let input = "3,100,1005,100,7,104,0,104,1,99";
+    let mem = Memory::parse(input);
+    let program = Program::parse(input).with_tracer(Coverage::new());
+    let mut nonzero = program.clone();
+    assert_eq!(nonzero.run_with_io(vec![5]), vec![1]);
+    let mut coverage = nonzero.into_tracer();
+    assert_eq!(coverage.branch_at(2), Branch { taken: 1, not_taken: 0 });
+    assert_eq!(coverage.summary(&mem), Summary { instructions: 5, instructions_hit: 4, branches: 2, branches_hit: 1 });
+    let annotated = coverage.annotate(&mem);
+    assert!(annotated.contains("           1 ?  0002: JNZ [100], #7  (taken 1, not taken 0)\n"), "{}", annotated);
+    assert!(annotated.contains("           0 !  0005: OUT #0\n"), "{}", annotated);
+
+    let mut zero = program;
+    assert_eq!(zero.run_with_io(vec![0]), vec![0, 1]);
+    coverage.merge(zero.tracer());
+    assert_eq!(coverage.summary(&mem), Summary { instructions: 5, instructions_hit: 5, branches: 2, branches_hit: 2 });
+    assert_eq!(coverage.count_at(7), 2);
+
+    let lcov = coverage.lcov(&mem, "program.asm");
+    assert!(lcov.starts_with("TN:\nSF:program.asm\nFN:1,main\nFNDA:2,main\nFNF:1\nFNH:1\n"), "{}", lcov);
+    assert!(lcov.contains("BRDA:2,0,0,1\nBRDA:2,0,1,1\nBRF:2\nBRH:2\n"), "{}", lcov);
+    assert!(lcov.contains("DA:3,1\nDA:5,2\nDA:6,2\nLF:5\nLH:5\nend_of_record\n"), "{}", lcov);
+}
@@ -0,0 +1,587 @@
+//! Decompiles Intcode into structured pseudocode, based on the control flow graph from `cfg`.
+//!
+//! Conditional jumps become `if`/`else`, `while` and `loop` where the control flow allows it, and
+//! `goto` otherwise. Functions are recovered from the relative-base calling convention; their
+//! relative-mode cells are shown as `fp[n]`, which is `[rb+n]` with `rb` as it was on entry. Outside
+//! functions they are shown as `rb[n]`. Position-mode cells outside the code are variables named
+//! after their address, like `v0100`; cells inside the code (self-modifying programs) are shown as
+//! `mem[n]`.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{self, Write};
+
+use super::cfg::{self, Cfg, Target, Terminator};
+use super::disasm::{label_name, Op, Operand};
+use super::{Addr, ArgMode, Memory, Number};
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+enum Expr {
+    Num(Number),
+    Var(Addr),
+    Mem(Number),
+    Rb(Number),
+    Fp(Number),
+    Input,
+    Binary(Box<Expr>, &'static str, Box<Expr>),
+    Not(Box<Expr>),
+}
+
+impl Expr {
+    fn binary(lhs: Expr, op: &'static str, rhs: Expr) -> Expr {
+        match (lhs, op, rhs) {
+            (Expr::Num(a), "+", Expr::Num(b)) if a.checked_add(b).is_some() => Expr::Num(a + b),
+            (Expr::Num(a), "*", Expr::Num(b)) if a.checked_mul(b).is_some() => Expr::Num(a * b),
+            (Expr::Num(0), "+", e) | (e, "+", Expr::Num(0)) | (Expr::Num(1), "*", e) | (e, "*", Expr::Num(1)) => e,
+            (e, "+", Expr::Num(b)) if b < 0 && b != Number::MIN => Expr::Binary(Box::new(e), "-", Box::new(Expr::Num(-b))),
+            (lhs, op, rhs) => Expr::Binary(Box::new(lhs), op, Box::new(rhs)),
+        }
+    }
+
+    fn negate(self) -> Expr {
+        match self {
+            Expr::Not(e) => *e,
+            Expr::Binary(lhs, "<", rhs) => Expr::Binary(lhs, ">=", rhs),
+            Expr::Binary(lhs, ">=", rhs) => Expr::Binary(lhs, "<", rhs),
+            Expr::Binary(lhs, "==", rhs) => Expr::Binary(lhs, "!=", rhs),
+            Expr::Binary(lhs, "!=", rhs) => Expr::Binary(lhs, "==", rhs),
+            e => Expr::Not(Box::new(e)),
+        }
+    }
+
+    fn fmt_operand(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expr::Binary(..) => write!(f, "({})", self),
+            _ => write!(f, "{}", self),
+        }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expr::Num(n) => write!(f, "{}", n),
+            Expr::Var(addr) => write!(f, "v{:04}", addr),
+            Expr::Mem(addr) => write!(f, "mem[{}]", addr),
+            Expr::Rb(offset) => write!(f, "rb[{}]", offset),
+            Expr::Fp(offset) => write!(f, "fp[{}]", offset),
+            Expr::Input => write!(f, "input()"),
+            Expr::Binary(lhs, op, rhs) => {
+                lhs.fmt_operand(f)?;
+                write!(f, " {} ", op)?;
+                rhs.fmt_operand(f)
+            },
+            Expr::Not(e) => {
+                write!(f, "!")?;
+                e.fmt_operand(f)
+            },
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+enum Stmt {
+    Label(Addr),
+    Assign(Expr, Expr),
+    Output(Expr),
+    AdjustBase(Expr),
+    Call(Addr),
+    Return,
+    Halt,
+    Invalid(Addr),
+    Goto(Addr),
+    IndirectGoto(Expr),
+    Break,
+    Continue,
+    If(Expr, Vec<Stmt>, Vec<Stmt>),
+    Loop(Vec<Stmt>),
+    While(Expr, Vec<Stmt>),
+    DoWhile(Vec<Stmt>, Expr),
+}
+
+impl Stmt {
+    /// Whether control never continues with the next statement.
+    fn is_jump(&self) -> bool {
+        matches!(self, Stmt::Return | Stmt::Halt | Stmt::Invalid(_) | Stmt::Goto(_) | Stmt::IndirectGoto(_) | Stmt::Break | Stmt::Continue)
+    }
+}
+
+/// The innermost loop that is being structured: its header, and the block after it.
+#[derive(Debug, Clone, Copy)]
+struct Loop {
+    header: Addr,
+    follow: Option<Addr>,
+}
+
+/// Structures the blocks of a single function (or the main program) into statements.
+struct Structurer<'a> {
+    cfg: &'a Cfg,
+    code: &'a BTreeSet<Addr>,
+    entry: Addr,
+    frame_size: Option<Number>,
+    is_function: bool,
+    blocks: BTreeSet<Addr>,
+    ipdom: BTreeMap<Addr, Addr>,
+    loops: BTreeMap<Addr, Option<Addr>>,
+    emitted: BTreeSet<Addr>,
+    gotos: BTreeSet<Addr>,
+}
+
+impl<'a> Structurer<'a> {
+    fn new(cfg: &'a Cfg, code: &'a BTreeSet<Addr>, entry: Addr, function: Option<&cfg::Function>) -> Self {
+        let blocks = match function {
+            Some(function) => function.blocks.clone(),
+            None => cfg.blocks_from(entry),
+        };
+        let mut structurer = Structurer {
+            cfg,
+            code,
+            entry,
+            frame_size: function.and_then(|function| function.frame_size),
+            is_function: function.is_some(),
+            blocks,
+            ipdom: BTreeMap::new(),
+            loops: BTreeMap::new(),
+            emitted: BTreeSet::new(),
+            gotos: BTreeSet::new(),
+        };
+        structurer.find_postdominators();
+        structurer.find_loops();
+        structurer
+    }
+
+    fn successors(&self, addr: Addr) -> Vec<Addr> {
+        self.cfg.blocks[&addr].terminator.successors()
+    }
+
+    fn find_postdominators(&mut self) {
+        let mut pdom = self.blocks.iter()
+            .map(|&addr| (addr, self.blocks.clone()))
+            .collect::<BTreeMap<_, _>>();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &addr in self.blocks.iter().rev() {
+                let mut set = self.successors(addr).iter()
+                    .map(|succ| pdom[succ].clone())
+                    .fold(None, |acc: Option<BTreeSet<Addr>>, set| Some(match acc {
+                        Some(acc) => acc.intersection(&set).copied().collect(),
+                        None => set,
+                    }))
+                    .unwrap_or_default();
+                set.insert(addr);
+                if set != pdom[&addr] {
+                    pdom.insert(addr, set);
+                    changed = true;
+                }
+            }
+        }
+        for (&addr, set) in &pdom {
+            let strict = set.iter().copied().filter(|&d| d != addr).collect::<BTreeSet<_>>();
+            if let Some(&d) = strict.iter().find(|d| pdom[d] == strict) {
+                self.ipdom.insert(addr, d);
+            }
+        }
+    }
+
+    fn find_loops(&mut self) {
+        // Find back edges by a depth-first search.
+        let mut back_edges = vec![];
+        let mut visited = BTreeSet::new();
+        let mut on_stack = BTreeSet::new();
+        let mut stack = vec![(self.entry, 0)];
+        visited.insert(self.entry);
+        on_stack.insert(self.entry);
+        while let Some((addr, i)) = stack.pop() {
+            let successors = self.successors(addr);
+            if i == successors.len() {
+                on_stack.remove(&addr);
+                continue;
+            }
+            stack.push((addr, i + 1));
+            let succ = successors[i];
+            if on_stack.contains(&succ) {
+                back_edges.push((addr, succ));
+            } else if visited.insert(succ) {
+                on_stack.insert(succ);
+                stack.push((succ, 0));
+            }
+        }
+
+        let mut predecessors = BTreeMap::<Addr, Vec<Addr>>::new();
+        for &addr in &self.blocks {
+            for succ in self.successors(addr) {
+                predecessors.entry(succ).or_default().push(addr);
+            }
+        }
+        let mut bodies = BTreeMap::<Addr, BTreeSet<Addr>>::new();
+        for (source, header) in back_edges {
+            let body = bodies.entry(header).or_default();
+            body.insert(header);
+            let mut todo = vec![source];
+            while let Some(addr) = todo.pop() {
+                if body.insert(addr) {
+                    todo.extend(predecessors.get(&addr).into_iter().flatten().copied());
+                }
+            }
+        }
+        for (header, body) in bodies {
+            let follow = self.ipdom.get(&header).copied()
+                .filter(|follow| !body.contains(follow))
+                .or_else(|| body.iter()
+                    .flat_map(|&addr| self.successors(addr))
+                    .filter(|succ| !body.contains(succ))
+                    .min());
+            self.loops.insert(header, follow);
+        }
+    }
+
+    /// Relative operands in a function are shown relative to the frame, shifted by `shift`, unless
+    /// the shift or the resulting offset overflows.
+    fn operand(&self, operand: Operand, shift: Option<Number>) -> Expr {
+        match operand.mode {
+            ArgMode::Immediate => Expr::Num(operand.value),
+            ArgMode::Position if operand.value >= 0 && !self.code.contains(&(operand.value as Addr)) => Expr::Var(operand.value as Addr),
+            ArgMode::Position => Expr::Mem(operand.value),
+            ArgMode::Relative => match shift.filter(|_| self.is_function).and_then(|shift| operand.value.checked_add(shift)) {
+                Some(offset) => Expr::Fp(offset),
+                None => Expr::Rb(operand.value),
+            },
+        }
+    }
+
+    /// How to get to `addr` from the current region, if it is not by continuing there.
+    fn exit(&mut self, addr: Addr, stop: Option<Addr>, innermost: Option<Loop>) -> Option<Option<Stmt>> {
+        if let Some(innermost) = innermost {
+            if addr == innermost.header {
+                return Some(Some(Stmt::Continue));
+            }
+            if Some(addr) == innermost.follow {
+                return Some(Some(Stmt::Break));
+            }
+        }
+        if Some(addr) == stop {
+            return Some(None);
+        }
+        if self.emitted.contains(&addr) {
+            self.gotos.insert(addr);
+            return Some(Some(Stmt::Goto(addr)));
+        }
+        None
+    }
+
+    /// Structures the blocks from `start` up to (not including) `stop`.
+    fn region(&mut self, start: Addr, stop: Option<Addr>, innermost: Option<Loop>) -> Vec<Stmt> {
+        let mut stmts = vec![];
+        let mut addr = start;
+        loop {
+            let next = match self.loops.get(&addr) {
+                Some(&follow) if innermost.map(|innermost| innermost.header) != Some(addr) => {
+                    let body = self.region(addr, None, Some(Loop { header: addr, follow }));
+                    stmts.push(Stmt::Loop(body));
+                    follow
+                },
+                _ => self.block(addr, innermost, &mut stmts),
+            };
+            match next.map(|next| (next, self.exit(next, stop, innermost))) {
+                None | Some((_, Some(None))) => break,
+                Some((_, Some(Some(stmt)))) => {
+                    stmts.push(stmt);
+                    break;
+                },
+                Some((next, None)) => addr = next,
+            }
+        }
+        stmts
+    }
+
+    /// Structures one branch of an `if`.
+    fn arm(&mut self, start: Addr, merge: Option<Addr>, innermost: Option<Loop>) -> Vec<Stmt> {
+        match self.exit(start, merge, innermost) {
+            Some(stmt) => stmt.into_iter().collect(),
+            None => self.region(start, merge, innermost),
+        }
+    }
+
+    /// Emits the statements of a block, and returns where control continues afterwards, if it does.
+    fn block(&mut self, addr: Addr, innermost: Option<Loop>, stmts: &mut Vec<Stmt>) -> Option<Addr> {
+        let block = &self.cfg.blocks[&addr];
+        self.emitted.insert(addr);
+        stmts.push(Stmt::Label(addr));
+
+        let mut shift = Some(if self.is_function && addr != self.entry { self.frame_size.unwrap_or(0) } else { 0 });
+        let len = block.instructions.len();
+        for (i, (_, instr)) in block.instructions.iter().enumerate() {
+            let ops = &instr.operands;
+            match instr.op {
+                Op::Add | Op::Mul | Op::Lt | Op::Eq => {
+                    if i + 2 == len && matches!(block.terminator, Terminator::Call(..)) {
+                        // Stores the return address.
+                        continue;
+                    }
+                    let op = match instr.op {
+                        Op::Add => "+",
+                        Op::Mul => "*",
+                        Op::Lt => "<",
+                        _ => "==",
+                    };
+                    let value = Expr::binary(self.operand(ops[0], shift), op, self.operand(ops[1], shift));
+                    stmts.push(Stmt::Assign(self.operand(ops[2], shift), value));
+                },
+                Op::In => stmts.push(Stmt::Assign(self.operand(ops[0], shift), Expr::Input)),
+                Op::Out => stmts.push(Stmt::Output(self.operand(ops[0], shift))),
+                Op::Arb if ops[0].mode == ArgMode::Immediate && self.is_function => {
+                    let prologue = addr == self.entry && i == 0;
+                    let epilogue = i + 2 == len && matches!(block.terminator, Terminator::Return(_));
+                    if !prologue && !epilogue {
+                        stmts.push(Stmt::AdjustBase(Expr::Num(ops[0].value)));
+                    }
+                    shift = shift.and_then(|shift| shift.checked_add(ops[0].value));
+                },
+                Op::Arb => stmts.push(Stmt::AdjustBase(self.operand(ops[0], shift))),
+                Op::Jnz | Op::Jz | Op::Hlt => {},
+            }
+        }
+
+        let cond = || {
+            let (_, instr) = block.instructions.last().unwrap();
+            let cond = self.operand(instr.operands[0], shift);
+            if instr.op == Op::Jz { cond.negate() } else { cond }
+        };
+        match block.terminator {
+            Terminator::Fallthrough(next) | Terminator::Jump(Target::Static(next)) => Some(next),
+            Terminator::Jump(Target::Indirect(dest)) => {
+                stmts.push(Stmt::IndirectGoto(self.operand(dest, shift)));
+                None
+            },
+            Terminator::Branch(Target::Indirect(dest), next) => {
+                stmts.push(Stmt::If(cond(), vec![Stmt::IndirectGoto(self.operand(dest, shift))], vec![]));
+                Some(next)
+            },
+            Terminator::Branch(Target::Static(target), next) => {
+                let cond = cond();
+                let merge = self.ipdom.get(&addr).copied();
+                let then = self.arm(target, merge, innermost);
+                let els = self.arm(next, merge, innermost);
+                stmts.push(Stmt::If(cond, then, els));
+                let loop_exit = innermost.is_some_and(|innermost| merge == Some(innermost.header) || merge == innermost.follow);
+                if loop_exit { None } else { merge }
+            },
+            Terminator::Call(entry, next) => {
+                stmts.push(Stmt::Call(entry));
+                Some(next)
+            },
+            Terminator::Return(_) => {
+                stmts.push(Stmt::Return);
+                None
+            },
+            Terminator::Halt => {
+                stmts.push(Stmt::Halt);
+                None
+            },
+            Terminator::Invalid(addr) => {
+                stmts.push(Stmt::Invalid(addr));
+                None
+            },
+        }
+    }
+
+    /// Removes labels that are not jumped to, and tidies up the structure.
+    fn simplify(&self, stmts: Vec<Stmt>) -> Vec<Stmt> {
+        let mut out = vec![];
+        for stmt in stmts {
+            match stmt {
+                Stmt::Label(addr) if !self.gotos.contains(&addr) => {},
+                Stmt::If(cond, then, els) => {
+                    let (mut cond, mut then, mut els) = (cond, self.simplify(then), self.simplify(els));
+                    if then.is_empty() {
+                        cond = cond.negate();
+                        std::mem::swap(&mut then, &mut els);
+                    }
+                    if then.is_empty() {
+                        continue;
+                    }
+                    if then.last().is_some_and(Stmt::is_jump) {
+                        out.push(Stmt::If(cond, then, vec![]));
+                        out.extend(els);
+                    } else {
+                        out.push(Stmt::If(cond, then, els));
+                    }
+                },
+                Stmt::Loop(body) => {
+                    let mut body = self.simplify(body);
+                    if body.last() == Some(&Stmt::Continue) {
+                        body.pop();
+                    }
+                    let len = body.len();
+                    match (body.first(), body.get(len.saturating_sub(2)..)) {
+                        (Some(Stmt::If(cond, then, els)), _) if then == &[Stmt::Break] && els.is_empty() => {
+                            let cond = cond.clone().negate();
+                            body.remove(0);
+                            out.push(Stmt::While(cond, body));
+                        },
+                        (_, Some([Stmt::If(cond, then, els), Stmt::Break])) if then == &[Stmt::Continue] && els.is_empty() => {
+                            let cond = cond.clone();
+                            body.truncate(len - 2);
+                            out.push(Stmt::DoWhile(body, cond));
+                        },
+                        _ => out.push(Stmt::Loop(body)),
+                    }
+                },
+                stmt => out.push(stmt),
+            }
+        }
+        out
+    }
+
+    fn structure(&mut self) -> Vec<Stmt> {
+        let stmts = self.region(self.entry, None, None);
+        self.simplify(stmts)
+    }
+}
+
+fn write_stmts(out: &mut String, stmts: &[Stmt], depth: usize) {
+    let indent = "    ".repeat(depth);
+    for stmt in stmts {
+        match stmt {
+            Stmt::Label(addr) => writeln!(out, "{}{}:", "    ".repeat(depth - 1), label_name(*addr)),
+            Stmt::Assign(lhs, rhs) => writeln!(out, "{}{} = {};", indent, lhs, rhs),
+            Stmt::Output(e) => writeln!(out, "{}output({});", indent, e),
+            Stmt::AdjustBase(e) => writeln!(out, "{}rb += {};", indent, e),
+            Stmt::Call(entry) => writeln!(out, "{}{}();", indent, cfg::function_name(*entry)),
+            Stmt::Return => writeln!(out, "{}return;", indent),
+            Stmt::Halt => writeln!(out, "{}halt;", indent),
+            Stmt::Invalid(addr) => writeln!(out, "{}fault; // invalid instruction at {:04}", indent, addr),
+            Stmt::Goto(addr) => writeln!(out, "{}goto {};", indent, label_name(*addr)),
+            Stmt::IndirectGoto(e) => writeln!(out, "{}goto *{};", indent, e),
+            Stmt::Break => writeln!(out, "{}break;", indent),
+            Stmt::Continue => writeln!(out, "{}continue;", indent),
+            Stmt::If(cond, then, els) => {
+                writeln!(out, "{}if ({}) {{", indent, cond).unwrap();
+                write_stmts(out, then, depth + 1);
+                if !els.is_empty() {
+                    writeln!(out, "{}}} else {{", indent).unwrap();
+                    write_stmts(out, els, depth + 1);
+                }
+                writeln!(out, "{}}}", indent)
+            },
+            Stmt::Loop(body) => {
+                writeln!(out, "{}loop {{", indent).unwrap();
+                write_stmts(out, body, depth + 1);
+                writeln!(out, "{}}}", indent)
+            },
+            Stmt::DoWhile(body, cond) => {
+                writeln!(out, "{}do {{", indent).unwrap();
+                write_stmts(out, body, depth + 1);
+                writeln!(out, "{}}} while ({});", indent, cond)
+            },
+            Stmt::While(cond, body) => {
+                writeln!(out, "{}while ({}) {{", indent, cond).unwrap();
+                write_stmts(out, body, depth + 1);
+                writeln!(out, "{}}}", indent)
+            },
+        }.unwrap();
+    }
+}
+
+/// Decompiles the code reachable from address 0 into pseudocode: the initial values of the
+/// variables, followed by `main` and the functions it calls.
+pub fn decompile(mem: &Memory) -> String {
+    let cfg = cfg::analyze(mem);
+    let code = cfg.blocks.values()
+        .flat_map(|block| block.instructions.iter())
+        .flat_map(|(addr, instr)| *addr..*addr + instr.num_words())
+        .collect::<BTreeSet<_>>();
+    let variables = cfg.blocks.values()
+        .flat_map(|block| block.instructions.iter())
+        .flat_map(|(_, instr)| instr.operands.iter())
+        .filter(|operand| operand.mode == ArgMode::Position && operand.value >= 0)
+        .map(|operand| operand.value as Addr)
+        .filter(|addr| !code.contains(addr))
+        .collect::<BTreeSet<_>>();
+
+    let mut out = String::new();
+    for &addr in &variables {
+        writeln!(out, "var {} = {};", Expr::Var(addr), mem[addr]).unwrap();
+    }
+    if !variables.is_empty() {
+        writeln!(out).unwrap();
+    }
+    writeln!(out, "fn main() {{").unwrap();
+    write_stmts(&mut out, &Structurer::new(&cfg, &code, 0, None).structure(), 1);
+    writeln!(out, "}}").unwrap();
+    for function in cfg.functions.values() {
+        writeln!(out).unwrap();
+        writeln!(out, "fn {}() {{", cfg::function_name(function.entry)).unwrap();
+        write_stmts(&mut out, &Structurer::new(&cfg, &code, function.entry, Some(function)).structure(), 1);
+        writeln!(out, "}}").unwrap();
+    }
+    out
+}
+
+#[test]
+fn test_decompile_loop() {
+    // Counts down from the input.
+    assert_eq!(
+        decompile(&Memory::parse("3,100,1006,100,14,4,100,1001,100,-1,100,1106,0,2,99")),
+        "var v0100 = 0;\n\
+         \n\
+         fn main() {\n\
+         \x20   v0100 = input();\n\
+         \x20   while (v0100) {\n\
+         \x20       output(v0100);\n\
+         \x20       v0100 = v0100 - 1;\n\
+         \x20   }\n\
+         \x20   halt;\n\
+         }\n");
+}
+
+#[test]
+fn test_decompile_do_while() {
+    assert_eq!(
+        decompile(&Memory::parse("3,100,4,100,1001,100,-1,100,1005,100,2,99")),
+        "var v0100 = 0;\n\
+         \n\
+         fn main() {\n\
+         \x20   v0100 = input();\n\
+         \x20   do {\n\
+         \x20       output(v0100);\n\
+         \x20       v0100 = v0100 - 1;\n\
+         \x20   } while (v0100);\n\
+         \x20   halt;\n\
+         }\n");
+}
+
+#[test]
+fn test_decompile_function() {
+    // Reads a number, calls a function that doubles it if it is nonzero, and outputs the result.
+    assert_eq!(
+        decompile(&Memory::parse("109,100,203,0,21101,11,0,1,1105,1,16,204,0,104,-1,99,\
+                                  109,2,1206,-2,25,21202,-2,2,-2,109,-2,2106,0,1")),
+        "fn main() {\n\
+         \x20   rb += 100;\n\
+         \x20   rb[0] = input();\n\
+         \x20   F0016();\n\
+         \x20   output(rb[0]);\n\
+         \x20   output(-1);\n\
+         \x20   halt;\n\
+         }\n\
+         \n\
+         fn F0016() {\n\
+         \x20   if (fp[0]) {\n\
+         \x20       fp[0] = fp[0] * 2;\n\
+         \x20   }\n\
+         \x20   return;\n\
+         }\n");
+
+    // The same, with an offset that does not fit in the frame.
+    let listing = decompile(&Memory::parse(&format!("109,100,203,0,21101,11,0,1,1105,1,16,204,0,104,-1,99,\
+                                                     109,2,1206,{},25,21202,-2,2,-2,109,-2,2106,0,1", Number::MAX)));
+    assert!(listing.contains(&format!("if (rb[{}])", Number::MAX)), "{}", listing);
+}
+
+#[test]
+fn test_decompile_goto() {
+    // Two loops that jump into each other's middle; the second entry is reached by a goto.
+    let listing = decompile(&Memory::parse("3,100,1005,100,7,104,1,104,2,1106,0,5"));
+    assert!(listing.contains("goto L"), "{}", listing);
+}
@@ -0,0 +1,286 @@
+use std::collections::VecDeque;
+use std::io;
+
+use super::trace::Tracer;
+use super::{IntcodeError, Interrupt, Number, Program};
+
+/// A source of input values for a `Program`.
+pub trait IntcodeInput {
+    /// Returns the next input value, or `None` to pause the program until more input is available.
+    fn next_input(&mut self) -> Option<Number>;
+}
+
+/// A sink for output values of a `Program`.
+pub trait IntcodeOutput {
+    fn output(&mut self, val: Number) -> Control;
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Control {
+    Continue,
+    Pause,
+}
+
+/// Why `run_with_devices` returned.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Stop {
+    Halted,
+    /// The input device had no input. The program is waiting at `Interrupt::Reading`.
+    InputPaused,
+    /// The output device asked to pause after it received a value.
+    OutputPaused,
+    /// The program ran out of fuel. See `Program::set_fuel`.
+    OutOfFuel,
+    /// The program's deadline passed. See `Program::set_deadline`.
+    Timeout,
+    /// A custom instruction raised an interrupt. See `Program::with_instruction_set`.
+    Custom(Number),
+}
+
+impl<T: Tracer> Program<T> {
+    /// Runs the program, feeding it from `input` and sending its outputs to `output`, until it halts
+    /// or one of the devices pauses it. The program can be resumed by calling this again.
+    pub fn run_with_devices<I, O>(&mut self, input: &mut I, output: &mut O) -> Stop
+        where I: IntcodeInput, O: IntcodeOutput
+    {
+        self.try_run_with_devices(input, output).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    pub fn try_run_with_devices<I, O>(&mut self, input: &mut I, output: &mut O) -> Result<Stop, IntcodeError>
+        where I: IntcodeInput, O: IntcodeOutput
+    {
+        self.try_run_with_device(&mut Split { input, output })
+    }
+
+    /// Like `run_with_devices`, for a device that is both the input and the output, such as a
+    /// robot that reports what its camera sees and moves as the program tells it to.
+    pub fn run_with_device<D>(&mut self, device: &mut D) -> Stop
+        where D: IntcodeInput + IntcodeOutput
+    {
+        self.try_run_with_device(device).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    pub fn try_run_with_device<D>(&mut self, device: &mut D) -> Result<Stop, IntcodeError>
+        where D: IntcodeInput + IntcodeOutput
+    {
+        loop {
+            match self.try_run_until_interrupt()? {
+                Interrupt::Reading => {
+                    match device.next_input() {
+                        Some(val) => self.try_give_input(val)?,
+                        None => return Ok(Stop::InputPaused),
+                    }
+                },
+                Interrupt::Writing => {
+                    let val = self.try_take_output()?;
+                    if device.output(val) == Control::Pause {
+                        return Ok(Stop::OutputPaused);
+                    }
+                },
+                Interrupt::Halted => return Ok(Stop::Halted),
+                Interrupt::OutOfFuel => return Ok(Stop::OutOfFuel),
+                Interrupt::Timeout => return Ok(Stop::Timeout),
+                Interrupt::Custom(code) => return Ok(Stop::Custom(code)),
+            }
+        }
+    }
+}
+
+/// A separate input and output, as one device for `try_run_with_device`.
+struct Split<'a, I, O> {
+    input: &'a mut I,
+    output: &'a mut O,
+}
+
+impl<I: IntcodeInput, O> IntcodeInput for Split<'_, I, O> {
+    fn next_input(&mut self) -> Option<Number> {
+        self.input.next_input()
+    }
+}
+
+impl<I, O: IntcodeOutput> IntcodeOutput for Split<'_, I, O> {
+    fn output(&mut self, val: Number) -> Control {
+        self.output.output(val)
+    }
+}
+
+impl<D: IntcodeInput + ?Sized> IntcodeInput for &mut D {
+    fn next_input(&mut self) -> Option<Number> {
+        (**self).next_input()
+    }
+}
+
+impl<D: IntcodeOutput + ?Sized> IntcodeOutput for &mut D {
+    fn output(&mut self, val: Number) -> Control {
+        (**self).output(val)
+    }
+}
+
+impl IntcodeInput for VecDeque<Number> {
+    fn next_input(&mut self) -> Option<Number> {
+        self.pop_front()
+    }
+}
+
+impl IntcodeOutput for VecDeque<Number> {
+    fn output(&mut self, val: Number) -> Control {
+        self.push_back(val);
+        Control::Continue
+    }
+}
+
+impl IntcodeOutput for Vec<Number> {
+    fn output(&mut self, val: Number) -> Control {
+        self.push(val);
+        Control::Continue
+    }
+}
+
+/// Input from any iterator.
+pub struct IterInput<I>(pub I);
+
+impl<I: Iterator<Item = Number>> IntcodeInput for IterInput<I> {
+    fn next_input(&mut self) -> Option<Number> {
+        self.0.next()
+    }
+}
+
+/// Input from a closure.
+pub struct FnInput<F>(pub F);
+
+impl<F: FnMut() -> Option<Number>> IntcodeInput for FnInput<F> {
+    fn next_input(&mut self) -> Option<Number> {
+        (self.0)()
+    }
+}
+
+/// Output to a closure.
+pub struct FnOutput<F>(pub F);
+
+impl<F: FnMut(Number) -> Control> IntcodeOutput for FnOutput<F> {
+    fn output(&mut self, val: Number) -> Control {
+        (self.0)(val)
+    }
+}
+
+/// Input that reads lines from a reader and feeds them to the program as ASCII, including the
+/// newline. Pauses at end of input.
+pub struct AsciiLineInput<R: io::BufRead> {
+    reader: R,
+    buffer: VecDeque<Number>,
+}
+
+impl<R: io::BufRead> AsciiLineInput<R> {
+    pub fn new(reader: R) -> Self {
+        AsciiLineInput { reader, buffer: VecDeque::new() }
+    }
+}
+
+impl<R: io::BufRead> IntcodeInput for AsciiLineInput<R> {
+    fn next_input(&mut self) -> Option<Number> {
+        if self.buffer.is_empty() {
+            let mut line = String::new();
+            self.reader.read_line(&mut line).expect("Failed to read input");
+            self.buffer.extend(line.bytes().map(|b| b as Number));
+        }
+        self.buffer.pop_front()
+    }
+}
+
+/// Output that writes ASCII values as characters. Values outside the ASCII range, like the final
+/// answers of days 17 and 21, are written as decimal numbers on a line of their own.
+pub struct AsciiWriter<W: io::Write> {
+    writer: W,
+}
+
+impl<W: io::Write> AsciiWriter<W> {
+    pub fn new(writer: W) -> Self {
+        AsciiWriter { writer }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: io::Write> IntcodeOutput for AsciiWriter<W> {
+    fn output(&mut self, val: Number) -> Control {
+        if (0..=127).contains(&val) {
+            self.writer.write_all(&[val as u8])
+        } else {
+            writeln!(self.writer, "{}", val)
+        }.expect("Failed to write output");
+        Control::Continue
+    }
+}
+
+#[test]
+fn test_queues() {
+    // Outputs twice the sum of every pair of inputs.
+    let mut program = Program::parse("3,100,3,101,1,100,101,102,1002,102,2,102,4,102,1105,1,0");
+    let mut input = VecDeque::from(vec![1, 2, 3]);
+    let mut output = vec![];
+    assert_eq!(program.run_with_devices(&mut input, &mut output), Stop::InputPaused);
+    assert_eq!(output, vec![6]);
+    input.push_back(4);
+    assert_eq!(program.run_with_devices(&mut input, &mut output), Stop::InputPaused);
+    assert_eq!(output, vec![6, 14]);
+}
+
+#[test]
+fn test_closures() {
+    let mut program = Program::parse("3,100,4,100,1105,1,0");
+    let mut next = 0;
+    let mut input = FnInput(|| { next += 1; Some(next) });
+    let mut sum = 0;
+    let mut output = FnOutput(|val| {
+        sum += val;
+        if sum >= 10 { Control::Pause } else { Control::Continue }
+    });
+    assert_eq!(program.run_with_devices(&mut input, &mut output), Stop::OutputPaused);
+    assert_eq!(sum, 1 + 2 + 3 + 4);
+}
+
+#[test]
+fn test_iterator_and_halt() {
+    let mut program = Program::parse("3,0,4,0,99");
+    let mut output = VecDeque::new();
+    assert_eq!(program.run_with_devices(&mut IterInput(vec![42].into_iter()), &mut output), Stop::Halted);
+    assert_eq!(output, VecDeque::from(vec![42]));
+}
+
+#[test]
+fn test_ascii() {
+    // Echoes its input, followed by a non-ASCII value.
+    let mut program = Program::parse("3,100,4,100,1008,100,10,101,1006,101,0,104,1000,99");
+    let mut input = AsciiLineInput::new(&b"hi\nthere\n"[..]);
+    let mut output = AsciiWriter::new(vec![]);
+    assert_eq!(program.run_with_devices(&mut input, &mut output), Stop::Halted);
+    assert_eq!(String::from_utf8(output.into_inner()).unwrap(), "hi\n1000\n");
+}
+
+#[test]
+fn test_single_device() {
+    // A counter that reads how far to count, and sums what the program outputs.
+    struct Counter {
+        limit: Number,
+        sum: Number,
+    }
+    impl IntcodeInput for Counter {
+        fn next_input(&mut self) -> Option<Number> {
+            Some(self.limit)
+        }
+    }
+    impl IntcodeOutput for Counter {
+        fn output(&mut self, val: Number) -> Control {
+            self.sum += val;
+            Control::Continue
+        }
+    }
+
+    // Outputs 1 to the input value.
+    let mut program = Program::parse("3,100,1001,101,1,101,4,101,8,101,100,102,1006,102,2,99");
+    let mut counter = Counter { limit: 4, sum: 0 };
+    assert_eq!(program.run_with_device(&mut counter), Stop::Halted);
+    assert_eq!(counter.sum, 1 + 2 + 3 + 4);
+}
@@ -0,0 +1,354 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+
+use super::{Addr, ArgMode, Memory, Number};
+
+/// Runs of at least this many zero words are left out of a listing.
+const MIN_SKIPPED_ZEROS: Addr = 16;
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Hash)]
+pub enum Op {
+    Add,
+    Mul,
+    In,
+    Out,
+    Jnz,
+    Jz,
+    Lt,
+    Eq,
+    Arb,
+    Hlt,
+}
+
+impl Op {
+    pub fn from_opcode(opcode: Number) -> Option<Op> {
+        match opcode {
+            1 => Some(Op::Add),
+            2 => Some(Op::Mul),
+            3 => Some(Op::In),
+            4 => Some(Op::Out),
+            5 => Some(Op::Jnz),
+            6 => Some(Op::Jz),
+            7 => Some(Op::Lt),
+            8 => Some(Op::Eq),
+            9 => Some(Op::Arb),
+            99 => Some(Op::Hlt),
+            _ => None,
+        }
+    }
+
+    pub fn opcode(self) -> Number {
+        match self {
+            Op::Add => 1,
+            Op::Mul => 2,
+            Op::In => 3,
+            Op::Out => 4,
+            Op::Jnz => 5,
+            Op::Jz => 6,
+            Op::Lt => 7,
+            Op::Eq => 8,
+            Op::Arb => 9,
+            Op::Hlt => 99,
+        }
+    }
+
+    pub fn from_mnemonic(mnemonic: &str) -> Option<Op> {
+        match mnemonic {
+            "ADD" => Some(Op::Add),
+            "MUL" => Some(Op::Mul),
+            "IN" => Some(Op::In),
+            "OUT" => Some(Op::Out),
+            "JNZ" => Some(Op::Jnz),
+            "JZ" => Some(Op::Jz),
+            "LT" => Some(Op::Lt),
+            "EQ" => Some(Op::Eq),
+            "ARB" => Some(Op::Arb),
+            "HLT" => Some(Op::Hlt),
+            _ => None,
+        }
+    }
+
+    pub fn mnemonic(self) -> &'static str {
+        match self {
+            Op::Add => "ADD",
+            Op::Mul => "MUL",
+            Op::In => "IN",
+            Op::Out => "OUT",
+            Op::Jnz => "JNZ",
+            Op::Jz => "JZ",
+            Op::Lt => "LT",
+            Op::Eq => "EQ",
+            Op::Arb => "ARB",
+            Op::Hlt => "HLT",
+        }
+    }
+
+    /// Number of operands that are read by this instruction.
+    pub fn num_inputs(self) -> usize {
+        match self {
+            Op::Add | Op::Mul | Op::Lt | Op::Eq | Op::Jnz | Op::Jz => 2,
+            Op::Out | Op::Arb => 1,
+            Op::In | Op::Hlt => 0,
+        }
+    }
+
+    /// Number of operands that are written to by this instruction; always the last ones.
+    pub fn num_outputs(self) -> usize {
+        match self {
+            Op::Add | Op::Mul | Op::Lt | Op::Eq | Op::In => 1,
+            Op::Out | Op::Arb | Op::Jnz | Op::Jz | Op::Hlt => 0,
+        }
+    }
+
+    pub fn has_output(self) -> bool {
+        self.num_outputs() > 0
+    }
+
+    pub fn num_operands(self) -> usize {
+        self.num_inputs() + self.num_outputs()
+    }
+
+    pub fn is_jump(self) -> bool {
+        self == Op::Jnz || self == Op::Jz
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct Operand {
+    pub mode: ArgMode,
+    pub value: Number,
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.mode {
+            ArgMode::Position => write!(f, "[{}]", self.value),
+            ArgMode::Immediate => write!(f, "#{}", self.value),
+            ArgMode::Relative if self.value < 0 => write!(f, "[rb-{}]", self.value.unsigned_abs()),
+            ArgMode::Relative => write!(f, "[rb+{}]", self.value),
+        }
+    }
+}
+
+/// A single decoded instruction. The operands are the inputs, followed by the output if any.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Instruction {
+    pub op: Op,
+    pub operands: Vec<Operand>,
+}
+
+impl Instruction {
+    /// Decodes the instruction at `addr`, following the same opcode and parameter mode rules as
+    /// `Program::run_until_interrupt`. Returns `None` if the word does not encode a valid
+    /// instruction, for example because it has an immediate-mode output. Like the interpreter, it
+    /// ignores mode digits beyond the last operand, so `encode` does not always give back the same
+    /// word; see `is_canonical`.
+    pub fn decode(mem: &Memory, addr: Addr) -> Option<Instruction> {
+        let word = mem[addr];
+        if word < 0 {
+            return None;
+        }
+        let op = Op::from_opcode(word % 100)?;
+        let mut modes = word / 100;
+        let mut operands = Vec::with_capacity(op.num_operands());
+        for i in 0..op.num_operands() {
+            let mode = ArgMode::from_number(modes % 10)?;
+            modes /= 10;
+            if mode == ArgMode::Immediate && i >= op.num_inputs() {
+                return None;
+            }
+            operands.push(Operand { mode, value: mem[addr + 1 + i] });
+        }
+        Some(Instruction { op, operands })
+    }
+
+    /// Whether the instruction at `addr` encodes as the word that is in memory, without stray mode
+    /// digits.
+    pub fn is_canonical(&self, mem: &Memory, addr: Addr) -> bool {
+        self.encode()[0] == mem[addr]
+    }
+
+    pub fn encode(&self) -> Vec<Number> {
+        let mut word = self.op.opcode();
+        let mut factor = 100;
+        for operand in &self.operands {
+            word += operand.mode.to_number() * factor;
+            factor *= 10;
+        }
+        let mut words = vec![word];
+        words.extend(self.operands.iter().map(|operand| operand.value));
+        words
+    }
+
+    /// Number of memory words occupied by this instruction.
+    pub fn num_words(&self) -> usize {
+        1 + self.operands.len()
+    }
+
+    /// The destination of a jump, if it is statically known because it is in immediate mode.
+    pub fn jump_target(&self) -> Option<Addr> {
+        if !self.op.is_jump() {
+            return None;
+        }
+        let dest = self.operands[1];
+        if dest.mode == ArgMode::Immediate && dest.value >= 0 {
+            Some(dest.value as Addr)
+        } else {
+            None
+        }
+    }
+
+    /// Formats the instruction, replacing jump targets and position-mode operands by label names
+    /// where available.
+    fn fmt_with_labels(&self, f: &mut fmt::Formatter, labels: &BTreeMap<Addr, String>) -> fmt::Result {
+        write!(f, "{}", self.op.mnemonic())?;
+        let target = self.jump_target().and_then(|target| labels.get(&target));
+        for (i, operand) in self.operands.iter().enumerate() {
+            let sep = if i >= self.op.num_inputs() { " ->" } else if i == 0 { "" } else { "," };
+            write!(f, "{} ", sep)?;
+            let label = if operand.mode == ArgMode::Position && operand.value >= 0 {
+                labels.get(&(operand.value as Addr))
+            } else {
+                None
+            };
+            match (target, label) {
+                (Some(target), _) if i == 1 => write!(f, "#{}", target)?,
+                (_, Some(label)) => write!(f, "[{}]", label)?,
+                _ => write!(f, "{}", operand)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_with_labels(f, &BTreeMap::new())
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Line {
+    Instruction(Instruction),
+    Data(Number),
+}
+
+/// A disassembled program: a sequence of lines with their start addresses, and names for addresses
+/// that are targeted by jumps or were named in the assembler source.
+///
+/// The `Display` output is valid input for `asm::assemble`, and reassembles to the same memory.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Listing {
+    pub lines: Vec<(Addr, Line)>,
+    pub labels: BTreeMap<Addr, String>,
+}
+
+pub fn label_name(addr: Addr) -> String {
+    format!("L{:04}", addr)
+}
+
+/// Disassembles memory by a linear sweep from address 0 up to the last nonzero word. Words that do
+/// not decode as an instruction are emitted as `DATA`, except for long runs of zeros, which are
+/// skipped.
+pub fn disassemble(mem: &Memory) -> Listing {
+    disassemble_with_symbols(mem, &HashMap::new())
+}
+
+/// Like `disassemble`, but uses the given label names (for example from `asm::assemble_with_symbols`)
+/// instead of generated ones where possible.
+pub fn disassemble_with_symbols(mem: &Memory, symbols: &HashMap<String, Addr>) -> Listing {
+    let end = mem.end();
+    let mut lines = vec![];
+    let mut addr = 0;
+    while addr < end {
+        if mem[addr] == 0 {
+            let next = mem.nonzero_from(addr).next().map_or(end, |(next, _)| next);
+            if next - addr >= MIN_SKIPPED_ZEROS {
+                addr = next;
+                continue;
+            }
+        }
+        // Words with stray mode digits are data, so that the listing assembles to the same program.
+        match Instruction::decode(mem, addr).filter(|instr| instr.is_canonical(mem, addr)) {
+            Some(instr) => {
+                let next = addr + instr.num_words();
+                lines.push((addr, Line::Instruction(instr)));
+                addr = next;
+            },
+            None => {
+                lines.push((addr, Line::Data(mem[addr])));
+                addr += 1;
+            },
+        }
+    }
+
+    let starts = lines.iter().map(|&(addr, _)| addr).collect::<BTreeSet<_>>();
+    let mut labels = BTreeMap::new();
+    for (name, &addr) in symbols {
+        if starts.contains(&addr) {
+            let label = labels.entry(addr).or_insert_with(|| name.clone());
+            if name < label {
+                *label = name.clone();
+            }
+        }
+    }
+    for (_, line) in &lines {
+        if let Line::Instruction(instr) = line {
+            if let Some(target) = instr.jump_target().filter(|target| starts.contains(target)) {
+                labels.entry(target).or_insert_with(|| label_name(target));
+            }
+        }
+    }
+    Listing { lines, labels }
+}
+
+impl fmt::Display for Listing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (addr, line) in &self.lines {
+            if let Some(label) = self.labels.get(addr) {
+                writeln!(f, "{}:", label)?;
+            }
+            write!(f, "{:04}: ", addr)?;
+            match line {
+                Line::Instruction(instr) => instr.fmt_with_labels(f, &self.labels)?,
+                Line::Data(n) => write!(f, "DATA {}", n)?,
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_decode() {
+    let mem = Memory::parse("1001,100,5,3,99");
+    assert_eq!(
+        Instruction::decode(&mem, 0).unwrap().to_string(),
+        "ADD [100], #5 -> [3]");
+    assert_eq!(Instruction::decode(&mem, 0).unwrap().encode(), vec![1001, 100, 5, 3]);
+    assert_eq!(Instruction::decode(&Memory::parse("21101,1,2,-3"), 0).unwrap().to_string(), "ADD #1, #2 -> [rb-3]");
+    assert_eq!(Operand { mode: ArgMode::Relative, value: Number::MIN }.to_string(), format!("[rb-{}]", Number::MIN.unsigned_abs()));
+    assert_eq!(Instruction::decode(&Memory::parse("11101,1,2,3"), 0), None);
+    assert_eq!(Instruction::decode(&Memory::parse("301,1,2,3"), 0), None);
+    let stray = Memory::parse("10099");
+    assert_eq!(Instruction::decode(&stray, 0).unwrap().to_string(), "HLT");
+    assert!(!Instruction::decode(&stray, 0).unwrap().is_canonical(&stray, 0));
+    assert_eq!(Instruction::decode(&Memory::parse("42"), 0), None);
+}
+
+#[test]
+fn test_disassemble() {
+    assert_eq!(
+        disassemble(&Memory::parse("3,12,1005,12,7,104,0,204,-1,99,-5,0")).to_string(),
+        "0000: IN -> [12]\n\
+         0002: JNZ [12], #L0007\n\
+         0005: OUT #0\n\
+         L0007:\n\
+         0007: OUT [rb-1]\n\
+         0009: HLT\n\
+         0010: DATA -5\n");
+    let mut mem = Memory::parse("99");
+    mem[1000] = 42;
+    assert_eq!(disassemble(&mem).to_string(), "0000: HLT\n1000: DATA 42\n");
+    assert_eq!(disassemble(&Memory::parse("10099")).to_string(), "0000: DATA 10099\n");
+}
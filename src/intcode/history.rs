@@ -0,0 +1,190 @@
+//! Reverse debugging: a tracer that keeps an undo log, so that a `Program` can step backwards.
+//!
+//! Every recorded `Step` holds the old value of the memory cell it wrote, and the relative base
+//! can be recovered from the operand of the `ARB` instruction, so a step can be undone without
+//! storing any other state. Custom instructions that write more than one cell therefore fault
+//! while a `History` is attached. Input that was read is put back into memory, but not into
+//! whatever supplied it; output that was taken cannot be retracted.
+
+use std::collections::VecDeque;
+
+use super::trace::{Step, Tracer};
+use super::{Addr, Program};
+
+/// A tracer that keeps the most recent steps, up to a fixed capacity, so that they can be undone.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct History {
+    steps: VecDeque<Step>,
+    capacity: usize,
+    time: u64,
+}
+
+impl History {
+    /// Creates a history that keeps up to `capacity` steps. When it is full, the oldest step is
+    /// forgotten.
+    pub fn new(capacity: usize) -> Self {
+        History { steps: VecDeque::new(), capacity, time: 0 }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn set_capacity(&mut self, capacity: usize) {
+        while self.steps.len() > capacity {
+            self.steps.pop_front();
+        }
+        self.capacity = capacity;
+    }
+
+    /// Number of steps that can be undone.
+    pub fn num_steps(&self) -> usize {
+        self.steps.len()
+    }
+
+    /// Number of steps executed since recording started, minus the ones that were undone.
+    pub fn time(&self) -> u64 {
+        self.time
+    }
+
+    /// Recorded steps, oldest first.
+    pub fn steps(&self) -> impl DoubleEndedIterator<Item = &Step> {
+        self.steps.iter()
+    }
+
+    /// The most recent recorded step that wrote to `addr`, with the time at which it executed.
+    pub fn last_write(&self, addr: Addr) -> Option<(u64, &Step)> {
+        self.steps.iter().rev()
+            .enumerate()
+            .find(|(_, step)| step.write.is_some_and(|write| write.addr == addr))
+            .map(|(i, step)| (self.time - 1 - i as u64, step))
+    }
+}
+
+impl Tracer for History {
+    const SINGLE_WRITE: bool = true;
+
+    fn step(&mut self, step: &Step) {
+        self.time += 1;
+        if self.capacity == 0 {
+            return;
+        }
+        if self.steps.len() == self.capacity {
+            self.steps.pop_front();
+        }
+        self.steps.push_back(*step);
+    }
+}
+
+impl Program<History> {
+    /// Undoes the most recent instruction, and returns it. If the program is at an input or output
+    /// interrupt or a fault, the pending instruction has not completed, and is just rewound; the
+    /// returned step then has no operands. Returns `None` if there is nothing left to undo.
+    pub fn step_back(&mut self) -> Option<Step> {
+        let pending = self.fault.is_some() || self.interrupt.is_some_and(|interrupt| interrupt != super::Interrupt::Halted);
+        if pending {
+            self.fault = None;
+            self.interrupt = None;
+            self.ip = self.cur_ip;
+            return Some(Step::new(self.cur_ip, self.mem[self.cur_ip] % 100));
+        }
+        let step = self.tracer.steps.pop_back()?;
+        self.tracer.time -= 1;
+        if let Some(write) = step.write {
+            self.promoted.remove(&write.addr);
+            self.mem[write.addr] = write.old;
+        }
+        if step.opcode == 9 {
+            self.relative_base = self.relative_base.wrapping_sub(step.operands()[0]);
+        }
+        self.interrupt = None;
+        self.ip = step.ip;
+        self.cur_ip = step.ip;
+        Some(step)
+    }
+
+    /// Steps back until `stop` returns true for an undone step, and returns that step. Returns
+    /// `None` if the history ran out first.
+    pub fn run_back_until<P>(&mut self, mut stop: P) -> Option<Step>
+        where P: FnMut(&Step) -> bool
+    {
+        while let Some(step) = self.step_back() {
+            if stop(&step) {
+                return Some(step);
+            }
+        }
+        None
+    }
+
+    /// Steps back to the instruction that last wrote `addr`, so that it is about to execute again,
+    /// and returns it. Does nothing and returns `None` if no recorded step wrote `addr`.
+    pub fn run_back_to_write(&mut self, addr: Addr) -> Option<Step> {
+        self.tracer.last_write(addr)?;
+        self.run_back_until(|step| step.write.is_some_and(|write| write.addr == addr))
+    }
+}
+
+#[test]
+fn test_step_back() {
+    use super::Interrupt;
+
+    // Reads two numbers, and outputs their sum, using relative mode.
+    let original = Program::parse("109,100,203,0,203,1,22201,0,1,2,204,2,99");
+    let mut program = original.clone().with_tracer(History::new(100));
+    assert_eq!(program.run_with_io(vec![3, 4]), vec![7]);
+    assert_eq!(program.tracer().time(), 6);
+
+    let (time, step) = program.tracer().last_write(102).unwrap();
+    assert_eq!((time, step.ip), (3, 6));
+    assert_eq!(program.run_back_to_write(102).unwrap().ip, 6);
+    assert_eq!((program.ip(), program.mem[102], program.mem[101]), (6, 0, 4));
+    assert_eq!(program.run_back_to_write(102), None);
+
+    assert_eq!(program.step_back().unwrap().opcode, 3);
+    assert_eq!(program.mem[101], 0);
+    assert_eq!(program.run_until_interrupt(), Interrupt::Reading);
+    assert_eq!(program.step_back().unwrap().ip, 4);
+    assert_eq!(program.run_back_until(|step| step.ip == 0).unwrap().opcode, 9);
+    assert_eq!(program.step_back(), None);
+    assert_eq!((program.ip(), program.relative_base(), &program.mem), (0, 0, &original.mem));
+}
+
+#[test]
+fn test_capacity() {
+    let mut program = Program::parse("1001,100,1,100,1105,1,0").with_tracer(History::new(3)).with_fuel(10);
+    program.run_until_interrupt();
+    assert_eq!((program.tracer().time(), program.tracer().num_steps()), (10, 3));
+    assert!(program.run_back_until(|_| false).is_none());
+    assert_eq!((program.mem[100], program.ip(), program.tracer().time()), (4, 4, 7));
+}
+
+#[test]
+fn test_custom_writes() {
+    use super::opcodes::{Effect, InstructionSet};
+    use super::IntcodeError;
+
+    // Opcode 40 writes its value to the address and, with a nonzero flag, to the next one too.
+    let set = InstructionSet::new().with(40, |m| {
+        let val = m.eval_arg()?;
+        let dest = m.eval_addr()?;
+        let both = m.eval_arg()?;
+        m.write(dest, val)?;
+        if both != 0 {
+            m.write(dest + 1, val)?;
+        }
+        Ok(Effect::Continue)
+    });
+    let input = "10140,7,100,0,10140,8,100,1,99";
+    let mut program = Program::parse(input).with_instruction_set(set.clone()).with_tracer(History::new(10));
+    assert_eq!(program.step(), None);
+    assert_eq!(program.mem[100], 7);
+    assert!(matches!(program.try_step(), Err(IntcodeError::Custom { ip: 4, opcode: 40, .. })));
+    assert_eq!(program.mem[101], 0);
+    program.step_back();
+    assert_eq!(program.step_back().unwrap().ip, 0);
+    assert_eq!(program.mem[100], 0);
+
+    let mut untraced = Program::parse(input).with_instruction_set(set);
+    assert_eq!(untraced.run_until_interrupt(), super::Interrupt::Halted);
+    assert_eq!((untraced.mem[100], untraced.mem[101]), (8, 8));
+}
@@ -0,0 +1,520 @@
+//! A runtime for networks of Intcode programs that exchange packets, as in day 23.
+//!
+//! Each program sends a packet by outputting a destination address followed by the payload. It
+//! receives a packet by reading its payload; if no packet is queued, it reads the idle input
+//! instead. Packets to addresses outside the network are routed to a `Nat`, which is also asked
+//! what to do when the network becomes idle.
+//!
+//! The network can run in two modes, which should give the same results for well-behaved programs:
+//!
+//! * `run` is a deterministic single-threaded round-robin scheduler. The network is idle after a
+//!   round in which no program sent or received a packet.
+//! * `run_threaded` runs each program on its own thread, connected through channels. A program is
+//!   idle after it read the idle input `idle_threshold` times in a row; it then blocks until it
+//!   receives a packet. The network is idle when all programs are idle and no packets are in
+//!   flight.
+
+use std::collections::VecDeque;
+use std::sync::mpsc;
+use std::thread;
+
+use super::{IntcodeError, Interrupt, Number, Program};
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Packet {
+    pub dest: Number,
+    pub payload: Vec<Number>,
+}
+
+/// Where a packet should be delivered.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Route {
+    /// The program at the given index. Packets for an index that is out of range go to the NAT.
+    Node(usize),
+    Nat,
+    Drop,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum NatAction {
+    Continue,
+    Send(Packet),
+    Stop,
+}
+
+/// Hook that receives packets routed outside the network, and is notified when the network is idle.
+pub trait Nat {
+    fn receive(&mut self, packet: Packet) -> NatAction;
+
+    /// Called whenever the network is idle. Returning `Continue` keeps feeding idle input to all
+    /// programs, and this will be called again the next time the network is found idle.
+    fn idle(&mut self) -> NatAction;
+}
+
+/// Why the network stopped running.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Outcome {
+    /// The NAT returned `NatAction::Stop`.
+    Stopped,
+    /// All programs halted.
+    Halted,
+    /// The program at the given address faulted.
+    Fault(usize, IntcodeError),
+}
+
+pub struct Network {
+    programs: Vec<Program>,
+    router: Box<dyn Fn(Number) -> Route>,
+    payload_len: usize,
+    idle_input: Number,
+    idle_threshold: usize,
+}
+
+impl Network {
+    /// Creates a network from programs that are ready to exchange packets. By default, addresses
+    /// `0..programs.len()` are routed to the corresponding program and all others to the NAT, and
+    /// the packet format is that of day 23: a payload of two values, and `-1` as idle input.
+    pub fn new(programs: Vec<Program>) -> Self {
+        let len = programs.len();
+        Network {
+            programs,
+            router: Box::new(move |dest| {
+                if dest >= 0 && (dest as usize) < len { Route::Node(dest as usize) } else { Route::Nat }
+            }),
+            payload_len: 2,
+            idle_input: -1,
+            idle_threshold: 2,
+        }
+    }
+
+    /// Creates a network of `n` clones of `program`, each of which is given its address as the
+    /// first input.
+    pub fn boot(program: &Program, n: usize) -> Self {
+        Self::new((0..n)
+            .map(|i| {
+                let mut p = program.clone();
+                p.give_input(i as Number);
+                p
+            })
+            .collect())
+    }
+
+    pub fn with_router<R: Fn(Number) -> Route + 'static>(mut self, router: R) -> Self {
+        self.router = Box::new(router);
+        self
+    }
+
+    pub fn with_payload_len(mut self, payload_len: usize) -> Self {
+        self.payload_len = payload_len;
+        self
+    }
+
+    pub fn with_idle_input(mut self, idle_input: Number) -> Self {
+        self.idle_input = idle_input;
+        self
+    }
+
+    pub fn with_idle_threshold(mut self, idle_threshold: usize) -> Self {
+        self.idle_threshold = idle_threshold;
+        self
+    }
+
+    pub fn programs(&self) -> &[Program] {
+        &self.programs
+    }
+
+    /// Runs the network on the current thread, visiting programs in address order.
+    pub fn run<N: Nat>(&mut self, nat: &mut N) -> Outcome {
+        let n = self.programs.len();
+        let mut queues = vec![VecDeque::<Packet>::new(); n];
+        let mut halted = vec![false; n];
+        if n == 0 {
+            // With no programs, all of them have halted, as `run_threaded` reports too.
+            return Outcome::Halted;
+        }
+        loop {
+            let mut progress = false;
+            for i in 0..n {
+                if halted[i] {
+                    continue;
+                }
+                let program = &mut self.programs[i];
+                let result = match program.try_run_until_interrupt() {
+                    Ok(Interrupt::Reading) => match queues[i].pop_front() {
+                        Some(packet) => {
+                            progress = true;
+                            packet.payload.iter().try_for_each(|&val| program.try_give_input(val))
+                        },
+                        None => program.try_give_input(self.idle_input),
+                    },
+                    Ok(Interrupt::Writing) => {
+                        progress = true;
+                        match read_packet(program, self.payload_len) {
+                            Ok(packet) => {
+                                let action = route(packet, &*self.router, &mut queues, nat);
+                                if let Some(outcome) = self.handle(action, &mut queues, nat) {
+                                    return outcome;
+                                }
+                                Ok(())
+                            },
+                            Err(err) => Err(err),
+                        }
+                    },
+                    Ok(Interrupt::Halted) => {
+                        halted[i] = true;
+                        if halted.iter().all(|&h| h) {
+                            return Outcome::Halted;
+                        }
+                        Ok(())
+                    },
+                    Ok(actual) => Err(IntcodeError::UnexpectedInterrupt { expected: Interrupt::Halted, actual }),
+                    Err(err) => Err(err),
+                };
+                if let Err(err) = result {
+                    return Outcome::Fault(i, err);
+                }
+            }
+            if !progress {
+                let action = nat.idle();
+                if let Some(outcome) = self.handle(action, &mut queues, nat) {
+                    return outcome;
+                }
+            }
+        }
+    }
+
+    fn handle<N: Nat>(&self, mut action: NatAction, queues: &mut [VecDeque<Packet>], nat: &mut N) -> Option<Outcome> {
+        loop {
+            match action {
+                NatAction::Continue => return None,
+                NatAction::Stop => return Some(Outcome::Stopped),
+                NatAction::Send(packet) => action = route(packet, &*self.router, queues, nat),
+            }
+        }
+    }
+
+    /// Runs every program on its own thread. The programs are moved back into the network when it
+    /// stops.
+    pub fn run_threaded<N: Nat>(&mut self, nat: &mut N) -> Outcome {
+        let n = self.programs.len();
+        let (event_tx, event_rx) = mpsc::channel();
+        let mut node_txs = vec![];
+        let mut handles = vec![];
+        for (i, program) in self.programs.drain(..).enumerate() {
+            let (tx, rx) = mpsc::channel();
+            node_txs.push(tx);
+            let node = Node {
+                addr: i,
+                program,
+                messages: rx,
+                events: event_tx.clone(),
+                payload_len: self.payload_len,
+                idle_input: self.idle_input,
+                idle_threshold: self.idle_threshold,
+            };
+            handles.push(thread::spawn(move || node.run()));
+        }
+        drop(event_tx);
+
+        let mut coordinator = Coordinator {
+            node_txs,
+            delivered: vec![0; n],
+            idle: vec![false; n],
+            halted: vec![false; n],
+        };
+        let outcome = loop {
+            let event = match event_rx.recv() {
+                Ok(event) => event,
+                Err(_) => break Outcome::Halted,
+            };
+            let action = match event {
+                Event::Packet(from, packet) => {
+                    coordinator.idle[from] = false;
+                    coordinator.route(packet, &*self.router, nat)
+                },
+                Event::Idle(i, consumed) => {
+                    coordinator.idle[i] = consumed == coordinator.delivered[i];
+                    NatAction::Continue
+                },
+                Event::Halted(i) => {
+                    coordinator.halted[i] = true;
+                    if coordinator.halted.iter().all(|&h| h) {
+                        break Outcome::Halted;
+                    }
+                    NatAction::Continue
+                },
+                Event::Fault(i, err) => break Outcome::Fault(i, err),
+            };
+            if let Some(outcome) = coordinator.handle(action, &*self.router, nat) {
+                break outcome;
+            }
+            if (0..n).all(|i| coordinator.idle[i] || coordinator.halted[i]) {
+                let action = nat.idle();
+                if let Some(outcome) = coordinator.handle(action, &*self.router, nat) {
+                    break outcome;
+                }
+                if (0..n).all(|i| coordinator.idle[i] || coordinator.halted[i]) {
+                    coordinator.wake_all();
+                }
+            }
+        };
+
+        for tx in &coordinator.node_txs {
+            tx.send(Message::Stop).ok();
+        }
+        self.programs = handles.into_iter()
+            .map(|handle| handle.join().expect("Network node panicked"))
+            .collect();
+        outcome
+    }
+}
+
+fn read_packet(program: &mut Program, payload_len: usize) -> Result<Packet, IntcodeError> {
+    let dest = program.try_take_output()?;
+    let payload = (0..payload_len).map(|_| program.try_take_output()).collect::<Result<_, _>>()?;
+    Ok(Packet { dest, payload })
+}
+
+fn route<N: Nat>(packet: Packet, router: &dyn Fn(Number) -> Route, queues: &mut [VecDeque<Packet>], nat: &mut N) -> NatAction {
+    match router(packet.dest) {
+        Route::Node(i) if i < queues.len() => {
+            queues[i].push_back(packet);
+            NatAction::Continue
+        },
+        Route::Node(_) | Route::Nat => nat.receive(packet),
+        Route::Drop => NatAction::Continue,
+    }
+}
+
+enum Message {
+    Packet(Packet),
+    /// Resume feeding idle input, as if the network were not idle.
+    Wake,
+    Stop,
+}
+
+enum Event {
+    Packet(usize, Packet),
+    /// The node is idle, having consumed this many packets in total.
+    Idle(usize, usize),
+    Halted(usize),
+    Fault(usize, IntcodeError),
+}
+
+struct Node {
+    addr: usize,
+    program: Program,
+    messages: mpsc::Receiver<Message>,
+    events: mpsc::Sender<Event>,
+    payload_len: usize,
+    idle_input: Number,
+    idle_threshold: usize,
+}
+
+impl Node {
+    fn run(mut self) -> Program {
+        let mut consumed = 0;
+        let mut idle_reads = 0;
+        let mut reported_idle = false;
+        loop {
+            let result = match self.program.try_run_until_interrupt() {
+                Ok(Interrupt::Reading) => {
+                    let message = if reported_idle {
+                        self.messages.recv().ok()
+                    } else {
+                        match self.messages.try_recv() {
+                            Ok(message) => Some(message),
+                            Err(mpsc::TryRecvError::Empty) => Some(Message::Wake),
+                            Err(mpsc::TryRecvError::Disconnected) => None,
+                        }
+                    };
+                    match message {
+                        Some(Message::Packet(packet)) => {
+                            consumed += 1;
+                            idle_reads = 0;
+                            reported_idle = false;
+                            packet.payload.iter().try_for_each(|&val| self.program.try_give_input(val))
+                        },
+                        Some(Message::Wake) => {
+                            if reported_idle {
+                                idle_reads = 0;
+                                reported_idle = false;
+                            }
+                            idle_reads += 1;
+                            if idle_reads >= self.idle_threshold {
+                                reported_idle = true;
+                                self.events.send(Event::Idle(self.addr, consumed)).ok();
+                            }
+                            self.program.try_give_input(self.idle_input)
+                        },
+                        Some(Message::Stop) | None => return self.program,
+                    }
+                },
+                Ok(Interrupt::Writing) => {
+                    idle_reads = 0;
+                    reported_idle = false;
+                    read_packet(&mut self.program, self.payload_len).map(|packet| {
+                        self.events.send(Event::Packet(self.addr, packet)).ok();
+                    })
+                },
+                Ok(Interrupt::Halted) => {
+                    self.events.send(Event::Halted(self.addr)).ok();
+                    return self.wait_for_stop();
+                },
+                Ok(actual) => Err(IntcodeError::UnexpectedInterrupt { expected: Interrupt::Halted, actual }),
+                Err(err) => Err(err),
+            };
+            if let Err(err) = result {
+                self.events.send(Event::Fault(self.addr, err)).ok();
+                return self.wait_for_stop();
+            }
+        }
+    }
+
+    fn wait_for_stop(self) -> Program {
+        while let Ok(message) = self.messages.recv() {
+            if let Message::Stop = message {
+                break;
+            }
+        }
+        self.program
+    }
+}
+
+struct Coordinator {
+    node_txs: Vec<mpsc::Sender<Message>>,
+    delivered: Vec<usize>,
+    idle: Vec<bool>,
+    halted: Vec<bool>,
+}
+
+impl Coordinator {
+    fn route<N: Nat>(&mut self, packet: Packet, router: &dyn Fn(Number) -> Route, nat: &mut N) -> NatAction {
+        match router(packet.dest) {
+            Route::Node(i) if i < self.node_txs.len() => {
+                self.delivered[i] += 1;
+                self.idle[i] = false;
+                self.node_txs[i].send(Message::Packet(packet)).ok();
+                NatAction::Continue
+            },
+            Route::Node(_) | Route::Nat => nat.receive(packet),
+            Route::Drop => NatAction::Continue,
+        }
+    }
+
+    fn handle<N: Nat>(&mut self, mut action: NatAction, router: &dyn Fn(Number) -> Route, nat: &mut N) -> Option<Outcome> {
+        loop {
+            match action {
+                NatAction::Continue => return None,
+                NatAction::Stop => return Some(Outcome::Stopped),
+                NatAction::Send(packet) => action = self.route(packet, router, nat),
+            }
+        }
+    }
+
+    fn wake_all(&mut self) {
+        for (i, tx) in self.node_txs.iter().enumerate() {
+            if !self.halted[i] {
+                self.idle[i] = false;
+                tx.send(Message::Wake).ok();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+fn ring_program(n: usize) -> Program {
+    // Each node adds its address to the y value of every packet and passes it on to the next node,
+    // except for the last node, which sends it to the NAT.
+    Program::new(super::asm::assemble(&format!("
+                IN -> [addr]
+        loop:   IN -> [x]
+                EQ [x], #-1 -> [tmp]
+                JNZ [tmp], #loop
+                IN -> [y]
+                ADD [x], #1 -> [x]
+                ADD [y], [addr] -> [y]
+                ADD [addr], #1 -> [dest]
+                EQ [dest], #{}, [tmp]
+                JZ [tmp], #send
+                ADD #255, #0 -> [dest]
+        send:   OUT [dest]
+                OUT [x]
+                OUT [y]
+                JNZ #1, #loop
+        addr:   .data 0
+        x:      .data 0
+        y:      .data 0
+        dest:   .data 0
+        tmp:    .data 0
+    ", n)).unwrap())
+}
+
+#[cfg(test)]
+#[derive(Default)]
+struct RingNat {
+    received: Vec<Packet>,
+    idle_count: usize,
+}
+
+#[cfg(test)]
+impl Nat for RingNat {
+    fn receive(&mut self, packet: Packet) -> NatAction {
+        self.received.push(packet);
+        NatAction::Continue
+    }
+
+    fn idle(&mut self) -> NatAction {
+        self.idle_count += 1;
+        match self.received.len() {
+            0 => NatAction::Send(Packet { dest: 0, payload: vec![0, 100] }),
+            1 => NatAction::Send(Packet { dest: 3, payload: vec![0, 200] }),
+            _ => NatAction::Stop,
+        }
+    }
+}
+
+#[test]
+fn test_modes_agree() {
+    let n = 10;
+    let mut single = RingNat::default();
+    assert_eq!(Network::boot(&ring_program(n), n).run(&mut single), Outcome::Stopped);
+    let mut threaded = RingNat::default();
+    assert_eq!(Network::boot(&ring_program(n), n).run_threaded(&mut threaded), Outcome::Stopped);
+    assert_eq!(single.received, vec![
+        Packet { dest: 255, payload: vec![10, 100 + 45] },
+        Packet { dest: 255, payload: vec![7, 200 + 3 + 4 + 5 + 6 + 7 + 8 + 9] },
+    ]);
+    assert_eq!(threaded.received, single.received);
+}
+
+#[test]
+fn test_router_and_halt() {
+    // Sends one packet to address 7 and halts.
+    let program = Program::parse("104,7,104,1,104,2,99");
+    let mut nat = RingNat::default();
+    let mut network = Network::new(vec![program.clone(), program.clone()])
+        .with_router(|dest| if dest == 7 { Route::Nat } else { Route::Drop });
+    assert_eq!(network.run(&mut nat), Outcome::Halted);
+    assert_eq!(nat.received.len(), 2);
+    assert_eq!(network.programs().len(), 2);
+
+    let mut nat = RingNat::default();
+    let mut network = Network::new(vec![program.clone(), program.clone()]);
+    assert_eq!(network.run_threaded(&mut nat), Outcome::Halted);
+    assert_eq!(nat.received.len(), 2);
+    assert_eq!(network.programs().len(), 2);
+
+    // Routes to a node that does not exist.
+    for &threaded in &[false, true] {
+        let mut nat = RingNat::default();
+        let mut network = Network::new(vec![program.clone(), program.clone()]).with_router(|_| Route::Node(2));
+        let outcome = if threaded { network.run_threaded(&mut nat) } else { network.run(&mut nat) };
+        assert_eq!(outcome, Outcome::Halted);
+        assert_eq!(nat.received.len(), 2);
+    }
+
+    // Without programs, there is nothing to wait for.
+    assert_eq!(Network::new(vec![]).run(&mut RingNat::default()), Outcome::Halted);
+    assert_eq!(Network::new(vec![]).run_threaded(&mut RingNat::default()), Outcome::Halted);
+}
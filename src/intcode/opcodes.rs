@@ -0,0 +1,240 @@
+//! Custom instruction sets. The standard opcodes of day 9 are built into the interpreter; an
+//! `InstructionSet` adds handlers for other opcodes, which are only consulted for opcodes that
+//! the interpreter does not know, so standard programs run as fast as before:
+//!
+//! ```text
+//! let set = InstructionSet::new()
+//!     .with(10, |m| { let (a, b, c) = (m.eval_arg()?, m.eval_arg()?, m.eval_addr()?); m.write(c, a / b)?; Ok(Effect::Continue) })
+//!     .with(12, |m| Ok(Effect::Raise(m.eval_arg()?)));
+//! let mut program = Program::parse(input).with_instruction_set(set);
+//! ```
+
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+use super::trace::Tracer;
+use super::{Addr, IntcodeError, Number, Program};
+
+/// The opcodes that are built into the interpreter, which cannot be replaced.
+pub const STANDARD_OPCODES: [Number; 10] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 99];
+
+/// The program as seen by a custom instruction. Arguments are evaluated in order, each consuming
+/// the next parameter mode, just like in the standard instructions.
+pub trait Machine {
+    /// The address of the instruction being executed.
+    fn cur_ip(&self) -> Addr;
+    /// Evaluates the next argument as a value.
+    fn eval_arg(&mut self) -> Result<Number, IntcodeError>;
+    /// Evaluates the next argument as an address to write to. Immediate mode is an error.
+    fn eval_addr(&mut self) -> Result<Addr, IntcodeError>;
+    fn read(&self, addr: Addr) -> Number;
+    /// Writes a memory cell. An address beyond the memory limit is an error, and so is writing a
+    /// second cell if the tracer sets `Tracer::SINGLE_WRITE`.
+    fn write(&mut self, addr: Addr, val: Number) -> Result<(), IntcodeError>;
+    /// Continues execution at `dest` instead of at the next instruction.
+    fn jump(&mut self, dest: Number) -> Result<(), IntcodeError>;
+    fn relative_base(&self) -> Number;
+    /// Returns an error to fault the program at this instruction with.
+    fn error(&self, message: &str) -> IntcodeError;
+}
+
+/// What happens after a custom instruction completes.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Effect {
+    Continue,
+    Halt,
+    /// Stop with `Interrupt::Custom`. Running the program again resumes after this instruction.
+    Raise(Number),
+}
+
+pub type Handler = dyn Fn(&mut dyn Machine) -> Result<Effect, IntcodeError> + Send + Sync;
+
+/// A set of custom opcodes and their handlers. See `Program::with_instruction_set`.
+#[derive(Clone, Default)]
+pub struct InstructionSet {
+    handlers: BTreeMap<Number, Arc<Handler>>,
+}
+
+impl InstructionSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a handler for `opcode`, which must be in the range 0..100 and not one of the
+    /// `STANDARD_OPCODES`. Replaces any handler registered before.
+    pub fn with<F>(mut self, opcode: Number, handler: F) -> Self
+        where F: Fn(&mut dyn Machine) -> Result<Effect, IntcodeError> + Send + Sync + 'static
+    {
+        assert!((0..100).contains(&opcode), "Opcode {} is not in the range 0..100", opcode);
+        assert!(!STANDARD_OPCODES.contains(&opcode), "Opcode {} is a standard opcode", opcode);
+        self.handlers.insert(opcode, Arc::new(handler));
+        self
+    }
+
+    pub fn opcodes(&self) -> impl Iterator<Item = Number> + '_ {
+        self.handlers.keys().cloned()
+    }
+
+    pub fn handler(&self, opcode: Number) -> Option<&Arc<Handler>> {
+        self.handlers.get(&opcode)
+    }
+}
+
+impl std::fmt::Debug for InstructionSet {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_set().entries(self.handlers.keys()).finish()
+    }
+}
+
+/// Two sets are equal if they share the same handlers for the same opcodes.
+impl PartialEq for InstructionSet {
+    fn eq(&self, other: &Self) -> bool {
+        self.handlers.len() == other.handlers.len() &&
+            self.handlers.iter().zip(&other.handlers).all(|((a, f), (b, g))| a == b && Arc::ptr_eq(f, g))
+    }
+}
+
+impl Eq for InstructionSet {}
+
+impl<T: Tracer> Machine for Program<T> {
+    fn cur_ip(&self) -> Addr {
+        self.cur_ip
+    }
+
+    fn eval_arg(&mut self) -> Result<Number, IntcodeError> {
+        Program::eval_arg(self)
+    }
+
+    fn eval_addr(&mut self) -> Result<Addr, IntcodeError> {
+        Program::eval_addr(self)
+    }
+
+    fn read(&self, addr: Addr) -> Number {
+        self.mem[addr]
+    }
+
+    fn write(&mut self, addr: Addr, val: Number) -> Result<(), IntcodeError> {
+        if addr >= self.mem.limit() {
+            let addr = Number::try_from(addr).unwrap_or(Number::MAX);
+            return Err(IntcodeError::AddressOutOfRange { ip: self.cur_ip, addr, limit: self.mem.limit(), excerpt: self.excerpt() });
+        }
+        if T::SINGLE_WRITE && self.step.write.is_some() {
+            return Err(self.error("Cannot write more than one cell in a single instruction with this tracer"));
+        }
+        Program::write(self, addr, val);
+        Ok(())
+    }
+
+    fn jump(&mut self, dest: Number) -> Result<(), IntcodeError> {
+        self.ip = self.mem_addr(dest)?;
+        Ok(())
+    }
+
+    fn relative_base(&self) -> Number {
+        self.relative_base
+    }
+
+    fn error(&self, message: &str) -> IntcodeError {
+        IntcodeError::Custom { ip: self.cur_ip, opcode: self.mem[self.cur_ip] % 100, message: message.to_string(), excerpt: self.excerpt() }
+    }
+}
+
+#[cfg(test)]
+fn div_mod_set() -> InstructionSet {
+    fn div_mod(m: &mut dyn Machine, f: fn(Number, Number) -> Option<Number>) -> Result<Effect, IntcodeError> {
+        let a = m.eval_arg()?;
+        let b = m.eval_arg()?;
+        let dest = m.eval_addr()?;
+        if b == 0 {
+            return Err(m.error("Division by zero"));
+        }
+        let val = f(a, b).ok_or_else(|| m.error("Arithmetic overflow"))?;
+        m.write(dest, val)?;
+        Ok(Effect::Continue)
+    }
+    InstructionSet::new()
+        .with(10, |m| div_mod(m, Number::checked_div))
+        .with(11, |m| div_mod(m, Number::checked_rem))
+        .with(12, |m| Ok(Effect::Raise(m.eval_arg()?)))
+}
+
+#[test]
+fn test_custom_opcodes() {
+    use super::Interrupt;
+
+    // Outputs 17 / 5 and 17 % 5, raising interrupt 42 in between.
+    let input = "10,15,16,17,11,15,16,18,112,42,4,17,4,18,99,17,5,0,0";
+    let mut program = Program::parse(input).with_instruction_set(div_mod_set());
+    assert_eq!(program.run_until_interrupt(), Interrupt::Custom(42));
+    assert_eq!(program.mem[17], 3);
+    assert_eq!(program.ip(), 10);
+    assert_eq!(program.run_with_io(vec![]), vec![3, 2]);
+
+    let mut cached = super::cached::CachedProgram::new(Program::parse(input).with_instruction_set(div_mod_set()));
+    assert_eq!(cached.run_until_interrupt(), Interrupt::Custom(42));
+    assert_eq!(cached.run_with_io(vec![]), vec![3, 2]);
+}
+
+#[test]
+fn test_custom_self_modifying() {
+    let set = InstructionSet::new().with(50, |m| {
+        let val = m.eval_arg()?;
+        let dest = m.eval_addr()?;
+        m.write(dest, val)?;
+        Ok(Effect::Continue)
+    });
+
+    // Outputs 1, then patches the first instruction through opcode 50 to output 7.
+    let input = "1101,1,0,100,4,100,1008,100,1,101,1006,101,21,150,7,1,1105,1,0,0,0,99";
+    let mut program = Program::parse(input).with_instruction_set(set.clone());
+    assert_eq!(program.run_with_io(vec![]), vec![1, 7]);
+
+    let mut cached = super::cached::CachedProgram::new(Program::parse(input).with_instruction_set(set));
+    cached.set_fuel(Some(1000));
+    assert_eq!(cached.run_with_io(vec![]), vec![1, 7]);
+    assert_eq!(cached.program().mem, program.mem);
+}
+
+#[test]
+fn test_custom_traced() {
+    use super::trace::Step;
+
+    // Raises the sum of four immediate operands.
+    let set = InstructionSet::new().with(20, |m| {
+        let sum = m.eval_arg()? + m.eval_arg()? + m.eval_arg()? + m.eval_arg()?;
+        Ok(Effect::Raise(sum))
+    });
+    let mut program = Program::parse("111120,1,2,3,4,99").with_instruction_set(set).with_tracer(Vec::<Step>::new());
+    assert_eq!(program.run_until_interrupt(), super::Interrupt::Custom(10));
+    assert_eq!(program.tracer()[0].operands(), &[1, 2, 3]);
+}
+
+#[test]
+fn test_custom_errors() {
+    let mut program = Program::parse("10,1,6,7,99,0,0,0").with_instruction_set(div_mod_set());
+    match program.try_run_until_interrupt() {
+        Err(IntcodeError::Custom { ip: 0, opcode: 10, message, .. }) => assert_eq!(message, "Division by zero"),
+        other => panic!("Unexpected result {:?}", other),
+    }
+
+    // Clears two cells, the second of which is beyond the memory limit.
+    let set = InstructionSet::new().with(30, |m| {
+        let dest = m.eval_addr()?;
+        m.write(dest, 0)?;
+        m.write(dest + 1, 0)?;
+        Ok(Effect::Continue)
+    });
+    let last = super::DEFAULT_MEMORY_LIMIT - 1;
+    let mut program = Program::parse(&format!("30,{},99", last)).with_instruction_set(set);
+    assert!(matches!(program.try_run_until_interrupt(), Err(IntcodeError::AddressOutOfRange { ip: 0, .. })));
+
+    let mut standard = Program::parse("10,1,6,7,99,0,0,0");
+    assert!(matches!(standard.try_run_until_interrupt(), Err(IntcodeError::InvalidOpcode { ip: 0, opcode: 10, .. })));
+}
+
+#[test]
+#[should_panic(expected = "Opcode 1 is a standard opcode")]
+fn test_standard_opcode() {
+    InstructionSet::new().with(1, |_| Ok(Effect::Halt));
+}
@@ -0,0 +1,142 @@
+use std::collections::VecDeque;
+
+use super::device::Stop;
+use super::{IntcodeError, Interrupt, Number, Program};
+use crate::permute::Permute;
+
+/// A chain of programs, where the outputs of each stage are the inputs of the next, like the
+/// amplifiers of day 7. With feedback, the outputs of the last stage are also fed back into the
+/// first stage.
+#[derive(Debug, Clone)]
+pub struct Pipeline {
+    stages: Vec<Program>,
+    queues: Vec<VecDeque<Number>>,
+    feedback: bool,
+}
+
+impl Pipeline {
+    /// Creates a pipeline of the given stages, of which there must be at least one.
+    pub fn new(stages: Vec<Program>) -> Self {
+        assert!(!stages.is_empty(), "A pipeline needs at least one stage");
+        let queues = vec![VecDeque::new(); stages.len()];
+        Pipeline { stages, queues, feedback: false }
+    }
+
+    /// Creates a stage for each phase setting, which is given as the first input to that stage.
+    pub fn with_phases(program: &Program, phases: &[Number]) -> Self {
+        let mut pipeline = Self::new(vec![program.clone(); phases.len()]);
+        for (queue, &phase) in pipeline.queues.iter_mut().zip(phases) {
+            queue.push_back(phase);
+        }
+        pipeline
+    }
+
+    /// Adds inputs that are given to a stage before any inputs from the previous stage.
+    pub fn with_stage_input(mut self, stage: usize, input: Vec<Number>) -> Self {
+        self.queues[stage].extend(input);
+        self
+    }
+
+    pub fn with_feedback(mut self) -> Self {
+        self.feedback = true;
+        self
+    }
+
+    /// Feeds the input to the first stage and runs until all stages have halted. Returns all
+    /// outputs of the last stage.
+    pub fn run(&mut self, input: Vec<Number>) -> Vec<Number> {
+        self.try_run(input).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Like `run`, but returns an error if a stage faults, or if the pipeline gets stuck because a
+    /// stage is waiting for input that will never come.
+    pub fn try_run(&mut self, input: Vec<Number>) -> Result<Vec<Number>, IntcodeError> {
+        let n = self.stages.len();
+        self.queues[0].extend(input);
+        let mut outputs = vec![];
+        let mut halted = vec![false; n];
+        while halted.iter().any(|&h| !h) {
+            let mut progress = false;
+            for (i, halted) in halted.iter_mut().enumerate() {
+                if *halted {
+                    continue;
+                }
+                let mut input = std::mem::take(&mut self.queues[i]);
+                let input_len = input.len();
+                let mut output = VecDeque::new();
+                let stop = self.stages[i].try_run_with_devices(&mut input, &mut output)?;
+                let actual = match stop {
+                    Stop::OutOfFuel => Some(Interrupt::OutOfFuel),
+                    Stop::Timeout => Some(Interrupt::Timeout),
+                    Stop::Custom(code) => Some(Interrupt::Custom(code)),
+                    _ => None,
+                };
+                if let Some(actual) = actual {
+                    return Err(IntcodeError::UnexpectedInterrupt { expected: Interrupt::Halted, actual });
+                }
+                progress |= input.len() < input_len || !output.is_empty();
+                *halted = stop == Stop::Halted;
+                progress |= *halted;
+                self.queues[i] = input;
+                if i + 1 < n {
+                    self.queues[i + 1].extend(output);
+                } else {
+                    outputs.extend(output.iter().cloned());
+                    if self.feedback {
+                        self.queues[0].extend(output);
+                    }
+                }
+            }
+            if !progress {
+                let stuck = halted.iter().position(|&h| !h).unwrap();
+                return Err(IntcodeError::InputExhausted { ip: self.stages[stuck].ip() });
+            }
+        }
+        Ok(outputs)
+    }
+}
+
+/// Tries every permutation of the phase settings, and returns the highest final output of the last
+/// stage when the first stage is given an input of 0, together with the phases that produced it.
+pub fn search_phases(program: &Program, phases: Vec<Number>, feedback: bool) -> (Number, Vec<Number>) {
+    phases.permute()
+        .map(|phases| {
+            let mut pipeline = Pipeline::with_phases(program, &phases);
+            if feedback {
+                pipeline = pipeline.with_feedback();
+            }
+            let signal = *pipeline.run(vec![0]).last().expect("Pipeline produced no output");
+            (signal, phases)
+        })
+        .max_by_key(|&(signal, _)| signal)
+        .expect("No phases given")
+}
+
+#[test]
+fn test_pipeline() {
+    // Outputs its first input plus ten times its second input.
+    let program = Program::parse("3,15,3,16,1002,16,10,16,1,15,16,16,4,16,99,0,0");
+    assert_eq!(Pipeline::with_phases(&program, &[1, 2, 3]).run(vec![0]), vec![123]);
+    assert_eq!(
+        Pipeline::new(vec![program.clone(), program.clone()]).with_stage_input(1, vec![5]).run(vec![4, 0]),
+        vec![45]);
+    assert_eq!(
+        Pipeline::with_phases(&program, &[1]).try_run(vec![]),
+        Err(IntcodeError::InputExhausted { ip: 2 }));
+}
+
+#[test]
+#[should_panic(expected = "A pipeline needs at least one stage")]
+fn test_empty_pipeline() {
+    Pipeline::new(vec![]);
+}
+
+#[test]
+fn test_search_phases() {
+    assert_eq!(
+        search_phases(&Program::parse("3,15,3,16,1002,16,10,16,1,16,15,15,4,15,99,0,0"), vec![0, 1, 2, 3, 4], false),
+        (43210, vec![4, 3, 2, 1, 0]));
+    assert_eq!(
+        search_phases(&Program::parse("3,26,1001,26,-4,26,3,27,1002,27,2,27,1,27,26,27,4,27,1001,28,-1,28,1005,28,6,99,0,0,5"), vec![5, 6, 7, 8, 9], true),
+        (139629729, vec![9, 8, 7, 6, 5]));
+}
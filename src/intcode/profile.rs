@@ -0,0 +1,158 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use super::disasm::{self, Line, Op};
+use super::trace::{Step, Tracer};
+use super::{Addr, Memory, Number};
+
+/// A tracer that counts how often each address and each opcode is executed, and how often each
+/// backwards jump (a loop back-edge) is taken.
+///
+/// To profile many clones of the same program, either share one profiler through
+/// `Rc<RefCell<Profiler>>`, or `merge` separate ones afterwards.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Profiler {
+    by_addr: Vec<u64>,
+    by_opcode: [u64; 100],
+    back_edges: HashMap<(Addr, Addr), u64>,
+    total: u64,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Profiler {
+            by_addr: vec![],
+            by_opcode: [0; 100],
+            back_edges: HashMap::new(),
+            total: 0,
+        }
+    }
+}
+
+impl Tracer for Profiler {
+    fn step(&mut self, step: &Step) {
+        if step.ip >= self.by_addr.len() {
+            self.by_addr.resize(step.ip + 1, 0);
+        }
+        self.by_addr[step.ip] += 1;
+        self.by_opcode[step.opcode as usize] += 1;
+        self.total += 1;
+        let taken = match step.opcode {
+            5 => step.operands()[0] != 0,
+            6 => step.operands()[0] == 0,
+            _ => false,
+        };
+        let dest = step.operands().get(1).cloned().unwrap_or(0) as Addr;
+        if taken && dest <= step.ip {
+            *self.back_edges.entry((step.ip, dest)).or_default() += 1;
+        }
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    pub fn count_at(&self, addr: Addr) -> u64 {
+        self.by_addr.get(addr).cloned().unwrap_or(0)
+    }
+
+    pub fn count_of(&self, op: Op) -> u64 {
+        self.by_opcode[op.opcode() as usize]
+    }
+
+    /// Taken backwards jumps as `((from, to), count)`, most frequent first.
+    pub fn back_edges(&self) -> Vec<((Addr, Addr), u64)> {
+        let mut edges = self.back_edges.iter().map(|(&edge, &count)| (edge, count)).collect::<Vec<_>>();
+        edges.sort_by_key(|&(edge, count)| (std::cmp::Reverse(count), edge));
+        edges
+    }
+
+    pub fn merge(&mut self, other: &Profiler) {
+        if other.by_addr.len() > self.by_addr.len() {
+            self.by_addr.resize(other.by_addr.len(), 0);
+        }
+        for (count, other) in self.by_addr.iter_mut().zip(&other.by_addr) {
+            *count += other;
+        }
+        for (count, other) in self.by_opcode.iter_mut().zip(other.by_opcode.iter()) {
+            *count += other;
+        }
+        for (&edge, &count) in &other.back_edges {
+            *self.back_edges.entry(edge).or_default() += count;
+        }
+        self.total += other.total;
+    }
+
+    fn percentage(&self, count: u64) -> f64 {
+        if self.total == 0 { 0.0 } else { 100.0 * count as f64 / self.total as f64 }
+    }
+
+    /// Renders a report with counts by opcode, loop back-edges, and a listing of `mem` annotated
+    /// with execution counts. Pass the program as it was loaded, because self-modifying code may
+    /// have changed it since.
+    pub fn report(&self, mem: &Memory) -> String {
+        let mut out = String::new();
+        writeln!(out, "Executed {} instructions", self.total).unwrap();
+
+        writeln!(out, "\nBy opcode:\n{:>12} {:>7}  op", "count", "%").unwrap();
+        let mut opcodes = (0..100).filter(|&opcode| self.by_opcode[opcode] > 0).collect::<Vec<_>>();
+        opcodes.sort_by_key(|&opcode| std::cmp::Reverse(self.by_opcode[opcode]));
+        for opcode in opcodes {
+            let count = self.by_opcode[opcode];
+            let name = Op::from_opcode(opcode as Number).map_or_else(|| format!("OP{}", opcode), |op| op.mnemonic().to_string());
+            writeln!(out, "{:>12} {:>7.2}  {}", count, self.percentage(count), name).unwrap();
+        }
+
+        writeln!(out, "\nLoop back-edges:\n{:>12}  from -> to", "count").unwrap();
+        for ((from, to), count) in self.back_edges() {
+            writeln!(out, "{:>12}  {:04} -> {:04}", count, from, to).unwrap();
+        }
+
+        writeln!(out, "\nListing:\n{:>12} {:>7}", "count", "%").unwrap();
+        let listing = disasm::disassemble(mem);
+        for (addr, line) in &listing.lines {
+            let count = self.count_at(*addr);
+            let columns = if count > 0 {
+                format!("{:>12} {:>7.2}", count, self.percentage(count))
+            } else {
+                format!("{:>20}", "")
+            };
+            let text = match line {
+                Line::Instruction(instr) => instr.to_string(),
+                Line::Data(n) => format!("DATA {}", n),
+            };
+            writeln!(out, "{}  {:04}: {}", columns, addr, text).unwrap();
+        }
+        out
+    }
+}
+
+#[test]
+fn test_profiler() {
+    use super::Program;
+
+    // Counts down from the input to zero, outputting each value.
+    let input = "3,100,4,100,1001,100,-1,100,1005,100,2,99";
+    let mut program = Program::parse(input).with_tracer(Profiler::new());
+    assert_eq!(program.run_with_io(vec![3]), vec![3, 2, 1]);
+    let mut profiler = program.into_tracer();
+    assert_eq!(profiler.total(), 1 + 3 * 3 + 1);
+    assert_eq!(profiler.count_at(0), 1);
+    assert_eq!(profiler.count_at(2), 3);
+    assert_eq!(profiler.count_of(Op::Jnz), 3);
+    assert_eq!(profiler.back_edges(), vec![((8, 2), 2)]);
+
+    let report = profiler.report(&Memory::parse(input));
+    assert!(report.contains("           3   27.27  0002: OUT [100]\n"));
+    assert!(report.contains("           2  0008 -> 0002\n"));
+
+    profiler.merge(&profiler.clone());
+    assert_eq!(profiler.total(), 22);
+    assert_eq!(profiler.back_edges(), vec![((8, 2), 4)]);
+}
@@ -0,0 +1,166 @@
+//! Execution under `Overflow::Promote`, where arithmetic is done in `i128`.
+//!
+//! Memory holds `Number`s, so results that do not fit are kept in a table of promoted cells on the
+//! side, while the memory cell itself holds the result wrapped to a `Number`. Instructions that
+//! read memory look up the table, so a promoted value can be used like any other, as long as it
+//! is not output, used as an address, added to the relative base, or executed as part of a
+//! standard instruction; those fault with `IntcodeError::Overflow`. `take_output_promoted` outputs
+//! a value of any width.
+//!
+//! Tracers, custom instructions and direct access to `mem` only see the wrapped values. Undoing a
+//! step with `History` forgets the promoted value that it overwrote.
+
+use std::convert::TryFrom;
+
+use super::disasm::Op;
+use super::trace::Tracer;
+use super::{Addr, ArgMode, IntcodeError, Interrupt, Number, Overflow, Program};
+
+/// Converts a `Number` to `i128`, which does nothing with the `wide` feature.
+#[allow(clippy::useless_conversion)]
+fn widen(n: Number) -> i128 {
+    i128::from(n)
+}
+
+/// Converts a promoted value back to a `Number`, or saturates if it does not fit, which makes any
+/// address out of range.
+fn saturate(val: i128) -> Number {
+    Number::try_from(val).unwrap_or(if val < 0 { Number::MIN } else { Number::MAX })
+}
+
+impl<T: Tracer> Program<T> {
+    /// The value of a memory cell, including any promoted value.
+    pub fn read_promoted(&self, addr: Addr) -> i128 {
+        match self.promoted.get(&addr) {
+            Some(&val) => val,
+            None => widen(self.mem[addr]),
+        }
+    }
+
+    fn write_promoted(&mut self, addr: Addr, val: i128) {
+        match Number::try_from(val) {
+            Ok(val) => self.write(addr, val),
+            Err(_) => {
+                self.write(addr, val as Number);
+                self.promoted.insert(addr, val);
+            },
+        }
+    }
+
+    fn eval_promoted(&mut self) -> Result<i128, IntcodeError> {
+        let val = self.arg();
+        let mode = self.arg_mode()?;
+        let val = match mode {
+            ArgMode::Position => self.read_promoted(self.mem_addr(val)?),
+            ArgMode::Immediate => widen(val),
+            ArgMode::Relative => self.read_promoted(self.relative_addr(val)?),
+        };
+        if T::ENABLED {
+            self.step.push_operand(mode, val as Number);
+        }
+        Ok(val)
+    }
+
+    /// Executes an instruction that reads operands, reading promoted values. Returns false for
+    /// other instructions, which execute as usual.
+    pub(super) fn execute_promoted(&mut self, opcode: Number) -> Result<bool, IntcodeError> {
+        if let Some(op) = Op::from_opcode(opcode) {
+            let words = self.cur_ip..self.cur_ip + 1 + op.num_operands();
+            if self.promoted.range(words).next().is_some() {
+                return Err(self.overflow_error());
+            }
+        }
+        match opcode {
+            1 | 2 | 7 | 8 => {
+                let a = self.eval_promoted()?;
+                let b = self.eval_promoted()?;
+                let dest = self.eval_addr()?;
+                let val = match opcode {
+                    1 => a.checked_add(b),
+                    2 => a.checked_mul(b),
+                    7 => Some(i128::from(a < b)),
+                    _ => Some(i128::from(a == b)),
+                };
+                let val = val.ok_or_else(|| self.overflow_error())?;
+                self.write_promoted(dest, val);
+            },
+            5 | 6 => {
+                let cond = self.eval_promoted()?;
+                let dest = self.eval_promoted()?;
+                if (cond != 0) == (opcode == 5) {
+                    self.ip = self.mem_addr(saturate(dest))?;
+                }
+            },
+            9 => {
+                let delta = self.eval_promoted()?;
+                self.relative_base = widen(self.relative_base).checked_add(delta)
+                    .and_then(|base| Number::try_from(base).ok())
+                    .ok_or_else(|| self.overflow_error())?;
+            },
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    pub fn take_output_promoted(&mut self) -> i128 {
+        self.try_take_output_promoted().unwrap_or_else(|err| panic!("Attempted to take output: {}", err))
+    }
+
+    /// Like `try_take_output`, but returns values that do not fit in a `Number` too.
+    pub fn try_take_output_promoted(&mut self) -> Result<i128, IntcodeError> {
+        self.expect_interrupt(Interrupt::Writing)?;
+        self.interrupt = None;
+        let val = self.eval_promoted().map_err(|err| self.set_fault(err))?;
+        self.trace();
+        Ok(val)
+    }
+
+    /// Takes output under `Overflow::Promote`, which faults if it does not fit in a `Number`.
+    pub(super) fn take_output_narrowed(&mut self) -> Result<Number, IntcodeError> {
+        debug_assert_eq!(self.overflow, Overflow::Promote);
+        let val = self.eval_promoted()?;
+        Number::try_from(val).map_err(|_| self.overflow_error())
+    }
+}
+
+#[test]
+#[cfg(not(feature = "wide"))]
+fn test_promote() {
+    let max = Number::MAX;
+    // Squares the input, adds one, and outputs whether the result exceeds it, then the result.
+    let input = "3,100,2,100,100,101,1001,101,1,101,7,100,101,102,4,102,4,101,99";
+    let mut program = Program::parse(input).with_overflow(Overflow::Promote);
+    program.give_input(max);
+    assert_eq!(program.take_output(), 1);
+    let square = i128::from(max) * i128::from(max) + 1;
+    assert_eq!(program.read_promoted(101), square);
+    assert_eq!(program.mem[101], square as Number);
+    assert_eq!(Program::from_snapshot(&program.to_snapshot()).unwrap(), program);
+    assert_eq!(program.try_take_output_promoted(), Ok(square));
+    assert_eq!(program.run_until_interrupt(), Interrupt::Halted);
+
+    // A promoted value is not a valid output, address or relative base.
+    let mut program = Program::parse(input).with_overflow(Overflow::Promote);
+    assert_eq!(program.run_with_io(vec![3]), vec![1, 10]);
+    assert!(matches!(
+        Program::parse(input).with_overflow(Overflow::Promote).try_run_with_io(vec![max]),
+        Err(IntcodeError::Overflow { ip: 16, opcode: 4, .. })));
+    let jump = format!("1102,{},2,100,105,1,100,99", max);
+    assert!(matches!(
+        Program::parse(&jump).with_overflow(Overflow::Promote).try_run_until_interrupt(),
+        Err(IntcodeError::AddressOutOfRange { ip: 4, .. })));
+    let rel_base = format!("1102,{},2,100,109,-1,9,100,99", max);
+    assert!(matches!(
+        Program::parse(&rel_base).with_overflow(Overflow::Promote).try_run_until_interrupt(),
+        Err(IntcodeError::Overflow { ip: 6, opcode: 9, .. })));
+
+    // Nor is it a valid operand.
+    let operand = format!("1102,{},2,5,104,0,99", max);
+    assert!(matches!(
+        Program::parse(&operand).with_overflow(Overflow::Promote).try_run_with_io(vec![]),
+        Err(IntcodeError::Overflow { ip: 4, opcode: 4, .. })));
+
+    // Overwriting a promoted cell forgets the promoted value.
+    let overwrite = format!("1102,{},2,100,1101,0,5,100,4,100,99", max);
+    assert_eq!(Program::parse(&overwrite).with_overflow(Overflow::Promote).run_with_io(vec![]), vec![5]);
+}
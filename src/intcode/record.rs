@@ -0,0 +1,250 @@
+//! Recording the input and output of a program, so that a run can be reproduced without the code
+//! that drove it. Attach a `Recorder` to the program, or share one through
+//! `Rc<RefCell<Recorder>>`, and save its recording as text. `replay` checks that a program still
+//! behaves the same, which makes a saved recording into a regression test:
+//!
+//! ```text
+//! #[test]
+//! fn test_replay() {
+//!     let recording = include_str!("day07.rec").parse::<Recording>().unwrap();
+//!     replay(Program::parse(PROGRAM), &recording).unwrap_or_else(|err| panic!("{}", err));
+//! }
+//! ```
+
+use std::fmt;
+use std::str::FromStr;
+
+use super::trace::{Step, Tracer};
+use super::{IntcodeError, Interrupt, Number, Program};
+
+/// An input or output value, with the number of instructions executed up to and including the
+/// input or output instruction.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Event {
+    Input { instructions: u64, val: Number },
+    Output { instructions: u64, val: Number },
+}
+
+/// A sequence of events. As text, each event is a line like `in 12 5` or `out 40 7`, giving the
+/// instruction count and the value.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct Recording {
+    pub events: Vec<Event>,
+}
+
+impl Recording {
+    pub fn inputs(&self) -> Vec<Number> {
+        self.events.iter().filter_map(|event| match event {
+            Event::Input { val, .. } => Some(*val),
+            Event::Output { .. } => None,
+        }).collect()
+    }
+
+    pub fn outputs(&self) -> Vec<Number> {
+        self.events.iter().filter_map(|event| match event {
+            Event::Input { .. } => None,
+            Event::Output { val, .. } => Some(*val),
+        }).collect()
+    }
+}
+
+impl fmt::Display for Recording {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for event in &self.events {
+            match event {
+                Event::Input { instructions, val } => writeln!(f, "in {} {}", instructions, val)?,
+                Event::Output { instructions, val } => writeln!(f, "out {} {}", instructions, val)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ParseRecordingError {
+    /// 1-based line number.
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseRecordingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for ParseRecordingError {}
+
+impl FromStr for Recording {
+    type Err = ParseRecordingError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut events = vec![];
+        for (i, line) in s.lines().enumerate() {
+            let error = |message: String| ParseRecordingError { line: i + 1, message };
+            let words = line.split_whitespace().collect::<Vec<_>>();
+            if words.is_empty() {
+                continue;
+            }
+            if words.len() != 3 {
+                return Err(error(format!("expected 3 fields, got {}", words.len())));
+            }
+            let instructions = words[1].parse::<u64>().map_err(|_| error(format!("invalid instruction count '{}'", words[1])))?;
+            let val = words[2].parse::<Number>().map_err(|_| error(format!("invalid value '{}'", words[2])))?;
+            events.push(match words[0] {
+                "in" => Event::Input { instructions, val },
+                "out" => Event::Output { instructions, val },
+                other => return Err(error(format!("invalid event '{}'", other))),
+            });
+        }
+        Ok(Recording { events })
+    }
+}
+
+/// A tracer that counts instructions and records every input and output.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct Recorder {
+    instructions: u64,
+    recording: Recording,
+}
+
+impl Tracer for Recorder {
+    fn step(&mut self, step: &Step) {
+        self.instructions += 1;
+        let instructions = self.instructions;
+        match step.opcode {
+            3 => if let Some(write) = step.write {
+                self.recording.events.push(Event::Input { instructions, val: write.new });
+            },
+            4 => self.recording.events.push(Event::Output { instructions, val: step.operands()[0] }),
+            _ => {},
+        }
+    }
+}
+
+impl Recorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The number of instructions executed since the recorder was attached.
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+
+    pub fn recording(&self) -> &Recording {
+        &self.recording
+    }
+
+    pub fn into_recording(self) -> Recording {
+        self.recording
+    }
+}
+
+/// What the program did when it diverged from the recording.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Actual {
+    /// An input or output that differs from the recorded one.
+    Event(Event),
+    /// Waiting for input, where the recording has an output or ends.
+    Reading { instructions: u64 },
+    Halted { instructions: u64 },
+    Fault(Box<IntcodeError>),
+}
+
+/// The first point where a replayed program did not do what the recording says.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Divergence {
+    /// Index of the event in the recording.
+    pub index: usize,
+    /// The recorded event, or `None` if the recording had ended.
+    pub expected: Option<Event>,
+    pub actual: Actual,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Replay diverged at event {}: expected ", self.index)?;
+        match &self.expected {
+            Some(event) => write!(f, "{:?}", event)?,
+            None => write!(f, "halt")?,
+        }
+        match &self.actual {
+            Actual::Event(event) => write!(f, ", got {:?}", event),
+            Actual::Reading { instructions } => write!(f, ", got input request after {} instructions", instructions),
+            Actual::Halted { instructions } => write!(f, ", got halt after {} instructions", instructions),
+            Actual::Fault(err) => write!(f, ", got fault: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for Divergence {}
+
+/// Runs the program, feeding it the recorded inputs, and checks that every input and output happens
+/// after the same number of instructions as in the recording, that the outputs are the same, and
+/// that the program halts at the end.
+pub fn replay<T: Tracer>(program: Program<T>, recording: &Recording) -> Result<(), Divergence> {
+    let mut program = program.with_tracer(Recorder::new());
+    let mut index = 0;
+    loop {
+        let expected = recording.events.get(index).cloned();
+        let diverged = |actual| Err(Divergence { index, expected, actual });
+        let result = match program.try_run_until_interrupt() {
+            Ok(Interrupt::Reading) => match expected {
+                Some(Event::Input { val, .. }) => program.try_give_input(val),
+                _ => return diverged(Actual::Reading { instructions: program.tracer().instructions() }),
+            },
+            Ok(Interrupt::Writing) => program.try_take_output().map(|_| ()),
+            Ok(Interrupt::Halted) => match expected {
+                None => return Ok(()),
+                Some(_) => return diverged(Actual::Halted { instructions: program.tracer().instructions() }),
+            },
+            Ok(actual) => Err(IntcodeError::UnexpectedInterrupt { expected: Interrupt::Halted, actual }),
+            Err(err) => Err(err),
+        };
+        if let Err(err) = result {
+            return diverged(Actual::Fault(Box::new(err)));
+        }
+        let actual = *program.tracer().recording().events.last().unwrap();
+        if expected != Some(actual) {
+            return diverged(Actual::Event(actual));
+        }
+        index += 1;
+    }
+}
+
+#[test]
+fn test_record_replay() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // Outputs the sum of two inputs, twice.
+    let input = "3,14,3,15,1,14,15,16,4,16,4,16,99,0,0,0,0";
+    let recorder = Rc::new(RefCell::new(Recorder::new()));
+    let mut program = Program::parse(input).with_tracer(recorder.clone());
+    assert_eq!(program.run_with_io(vec![3, 4]), vec![7, 7]);
+    let recording = recorder.borrow().recording().clone();
+    assert_eq!(recording.to_string(), "in 1 3\nin 2 4\nout 4 7\nout 5 7\n");
+    assert_eq!(recording.to_string().parse::<Recording>(), Ok(recording.clone()));
+    assert_eq!(recording.inputs(), vec![3, 4]);
+    assert_eq!(recording.outputs(), vec![7, 7]);
+    assert_eq!(replay(Program::parse(input), &recording), Ok(()));
+
+    // Multiplying instead of adding changes the first output.
+    let changed = input.replacen("1,14", "2,14", 1);
+    assert_eq!(replay(Program::parse(&changed), &recording), Err(Divergence {
+        index: 2,
+        expected: Some(Event::Output { instructions: 4, val: 7 }),
+        actual: Actual::Event(Event::Output { instructions: 4, val: 12 }),
+    }));
+
+    // Reading a third input instead of the second output.
+    let reads_more = input.replacen("4,16,99", "3,16,99", 1);
+    assert_eq!(replay(Program::parse(&reads_more), &recording).unwrap_err(), Divergence {
+        index: 3,
+        expected: Some(Event::Output { instructions: 5, val: 7 }),
+        actual: Actual::Reading { instructions: 4 },
+    });
+
+    assert_eq!("in 1\n".parse::<Recording>().unwrap_err().to_string(), "line 1: expected 3 fields, got 2");
+}
@@ -0,0 +1,381 @@
+//! Saving and restoring the complete state of a `Program`.
+//!
+//! The snapshot format is line-based text, so that snapshots can be attached to bug reports:
+//!
+//! ```text
+//! intcode-snapshot 2
+//! ip 1
+//! relative_base 0
+//! interrupt reading
+//! cur_ip 0
+//! cur_op 0
+//! overflow trap
+//! limit 16777216
+//! memory 0=3,0,4,0,99 1000=5
+//! end
+//! ```
+//!
+//! Memory is stored as runs of words, each preceded by its starting address. Long runs of zeros
+//! are left out.
+//!
+//! Loading is strict: every field must be present exactly once and in this order, so a snapshot
+//! that is truncated or from another version is rejected instead of producing a corrupt program.
+//! Version 1 snapshots, which stored memory as `low` and `high` fields, can still be loaded.
+//! A fault is not stored; since a faulted program is rewound to the faulting instruction, resuming
+//! it raises the same fault again. Tracers and the execution budget are not stored either. The
+//! overflow policy is, with any values promoted under `Overflow::Promote` stored in full; version 1
+//! snapshots load with `Overflow::Trap`.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use super::trace::{NoTracer, Step, Tracer};
+use super::{Addr, Interrupt, Memory, Number, Overflow, Program, DEFAULT_MEMORY_LIMIT, MAX_MEMORY_LIMIT};
+
+const MAGIC: &str = "intcode-snapshot";
+const VERSION: u32 = 2;
+
+/// Runs of fewer zeros than this are stored as part of the surrounding run of memory.
+const MIN_GAP: Addr = 16;
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    UnsupportedVersion(u32),
+    Format { line: usize, message: String },
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SnapshotError::Io(err) => write!(f, "Could not access snapshot: {}", err),
+            SnapshotError::UnsupportedVersion(version) =>
+                write!(f, "Unsupported snapshot version {} (expected {})", version, VERSION),
+            SnapshotError::Format { line, message } => write!(f, "Invalid snapshot at line {}: {}", line, message),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<io::Error> for SnapshotError {
+    fn from(err: io::Error) -> Self {
+        SnapshotError::Io(err)
+    }
+}
+
+impl<T: Tracer> Program<T> {
+    pub fn to_snapshot(&self) -> String {
+        let interrupt = match self.interrupt {
+            None => "none",
+            Some(Interrupt::Reading) => "reading",
+            Some(Interrupt::Writing) => "writing",
+            Some(Interrupt::Halted) => "halted",
+            // Running out of budget and custom interrupts are reported, but never stored as the
+            // program's state.
+            Some(Interrupt::OutOfFuel) | Some(Interrupt::Timeout) | Some(Interrupt::Custom(_)) => "none",
+        };
+        let overflow = match self.overflow {
+            Overflow::Trap => "trap",
+            Overflow::Wrap => "wrap",
+            Overflow::Promote => "promote",
+        };
+        let mut cells = self.mem.nonzero_from(0).map(|(addr, n)| (addr, n.to_string())).collect::<BTreeMap<_, _>>();
+        cells.extend(self.promoted.iter().map(|(&addr, val)| (addr, val.to_string())));
+        let mut runs: Vec<(Addr, Vec<String>)> = vec![];
+        for (addr, n) in cells {
+            match runs.last_mut() {
+                Some((start, words)) if addr - (*start + words.len()) < MIN_GAP => {
+                    words.resize(addr - *start, "0".to_string());
+                    words.push(n);
+                },
+                _ => runs.push((addr, vec![n])),
+            }
+        }
+        let memory = runs.iter()
+            .map(|(start, words)| format!("{}={}", start, words.join(",")))
+            .collect::<Vec<_>>()
+            .join(" ");
+        format!(
+            "{} {}\nip {}\nrelative_base {}\ninterrupt {}\ncur_ip {}\ncur_op {}\noverflow {}\nlimit {}\nmemory {}\nend\n",
+            MAGIC, VERSION, self.ip, self.relative_base, interrupt, self.cur_ip, self.cur_op, overflow, self.mem.limit(),
+            memory)
+    }
+
+    pub fn save_snapshot<P: AsRef<Path>>(&self, path: P) -> Result<(), SnapshotError> {
+        fs::write(path, self.to_snapshot()).map_err(From::from)
+    }
+}
+
+impl Program {
+    pub fn from_snapshot(snapshot: &str) -> Result<Self, SnapshotError> {
+        let mut parser = Parser { lines: snapshot.lines().enumerate(), line: 0 };
+
+        let header = parser.field(MAGIC)?;
+        let version = header.parse::<u32>().or_else(|_| parser.error(format!("invalid version '{}'", header)))?;
+        if version != 1 && version != VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+        let ip = parser.number::<Addr>("ip")?;
+        let ip_line = parser.line;
+        let relative_base = parser.number::<Number>("relative_base")?;
+        let interrupt = match parser.field("interrupt")? {
+            "none" => None,
+            "reading" => Some(Interrupt::Reading),
+            "writing" => Some(Interrupt::Writing),
+            "halted" => Some(Interrupt::Halted),
+            other => return parser.error(format!("invalid interrupt '{}'", other)),
+        };
+        let cur_ip = parser.number::<Addr>("cur_ip")?;
+        let cur_ip_line = parser.line;
+        let cur_op = parser.number::<Number>("cur_op")?;
+        let overflow = if version == 1 {
+            Overflow::Trap
+        } else {
+            match parser.field("overflow")? {
+                "trap" => Overflow::Trap,
+                "wrap" => Overflow::Wrap,
+                "promote" => Overflow::Promote,
+                other => return parser.error(format!("invalid overflow '{}'", other)),
+            }
+        };
+        let (mem, promoted) = if version == 1 { (parser.memory_v1()?, BTreeMap::new()) } else { parser.memory()? };
+        if !promoted.is_empty() && overflow != Overflow::Promote {
+            return parser.error("promoted values require overflow promote".to_string());
+        }
+        for &(key, addr, line) in &[("ip", ip, ip_line), ("cur_ip", cur_ip, cur_ip_line)] {
+            if addr >= mem.limit() {
+                let message = format!("{} {} exceeds limit {}", key, addr, mem.limit());
+                return Err(SnapshotError::Format { line, message });
+            }
+        }
+        parser.field("end")?;
+        if let Some((i, _)) = parser.lines.next() {
+            return Err(SnapshotError::Format { line: i + 1, message: "unexpected data after end".to_string() });
+        }
+
+        Ok(Program {
+            mem,
+            interrupt,
+            fault: None,
+            ip,
+            relative_base,
+            cur_ip,
+            cur_op,
+            overflow,
+            fuel: None,
+            deadline: None,
+            deadline_countdown: 0,
+            tracer: NoTracer,
+            step: Step::new(0, 0),
+            instruction_set: None,
+            promoted,
+        })
+    }
+
+    pub fn load_snapshot<P: AsRef<Path>>(path: P) -> Result<Self, SnapshotError> {
+        Self::from_snapshot(&fs::read_to_string(path)?)
+    }
+}
+
+struct Parser<'a> {
+    lines: std::iter::Enumerate<std::str::Lines<'a>>,
+    line: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error<T>(&self, message: String) -> Result<T, SnapshotError> {
+        Err(SnapshotError::Format { line: self.line, message })
+    }
+
+    /// Reads the next line, which must start with the given key, and returns the rest.
+    fn field(&mut self, key: &str) -> Result<&'a str, SnapshotError> {
+        let (i, line) = match self.lines.next() {
+            Some(next) => next,
+            None => {
+                self.line += 1;
+                return self.error(format!("missing '{}'", key));
+            },
+        };
+        self.line = i + 1;
+        let mut parts = line.splitn(2, ' ');
+        if parts.next() != Some(key) {
+            return self.error(format!("expected '{}'", key));
+        }
+        Ok(parts.next().unwrap_or(""))
+    }
+
+    fn number<N: std::str::FromStr>(&mut self, key: &str) -> Result<N, SnapshotError> {
+        let val = self.field(key)?;
+        val.parse::<N>().or_else(|_| self.error(format!("invalid {} '{}'", key, val)))
+    }
+
+    fn words(&self, words: &str) -> Result<Vec<Number>, SnapshotError> {
+        if words.is_empty() {
+            return Ok(vec![]);
+        }
+        words.split(',')
+            .map(|n| n.parse::<Number>().or_else(|_| self.error(format!("invalid number '{}'", n))))
+            .collect()
+    }
+
+    /// Like `words`, but also accepts values that only fit in an `i128`, which are returned wrapped
+    /// to a `Number` and in full.
+    fn promoted_words(&self, words: &str) -> Result<Vec<(Number, Option<i128>)>, SnapshotError> {
+        if words.is_empty() {
+            return Ok(vec![]);
+        }
+        words.split(',')
+            .map(|n| match (n.parse::<Number>(), n.parse::<i128>()) {
+                (Ok(n), _) => Ok((n, None)),
+                (Err(_), Ok(wide)) => Ok((wide as Number, Some(wide))),
+                _ => self.error(format!("invalid number '{}'", n)),
+            })
+            .collect()
+    }
+
+    /// Reads the `limit` and `memory` fields, and returns the memory and any promoted values.
+    fn memory(&mut self) -> Result<(Memory, BTreeMap<Addr, i128>), SnapshotError> {
+        let limit = self.number::<Addr>("limit")?;
+        if limit > MAX_MEMORY_LIMIT {
+            return self.error(format!("limit {} exceeds maximum {}", limit, MAX_MEMORY_LIMIT));
+        }
+        let mut mem = Memory::new().with_limit(limit);
+        let mut promoted = BTreeMap::new();
+        let runs = self.field("memory")?;
+        for run in runs.split(' ').filter(|run| !run.is_empty()) {
+            let mut parts = run.splitn(2, '=');
+            let start = parts.next().unwrap().parse::<Addr>();
+            let words = parts.next().map(|words| self.promoted_words(words));
+            match (start, words) {
+                (Ok(start), Some(words)) => {
+                    let words = words?;
+                    if start.checked_add(words.len()).is_none_or(|end| end > limit) {
+                        return self.error(format!("memory run at {} exceeds limit {}", start, limit));
+                    }
+                    for (i, (n, wide)) in words.into_iter().enumerate() {
+                        mem[start + i] = n;
+                        if let Some(wide) = wide {
+                            promoted.insert(start + i, wide);
+                        }
+                    }
+                },
+                _ => return self.error(format!("invalid memory run '{}'", run)),
+            }
+        }
+        Ok((mem, promoted))
+    }
+
+    /// Reads the `low` and `high` fields of version 1.
+    fn memory_v1(&mut self) -> Result<Memory, SnapshotError> {
+        let low = self.field("low")?;
+        let low = self.words(low)?;
+        if low.len() > DEFAULT_MEMORY_LIMIT {
+            return self.error(format!("memory exceeds limit {}", DEFAULT_MEMORY_LIMIT));
+        }
+        let low_len = low.len();
+        let mut mem = Memory::from_words(low);
+        let high = self.field("high")?;
+        for cell in high.split(',').filter(|cell| !cell.is_empty()) {
+            let mut parts = cell.splitn(2, '=');
+            let addr = parts.next().unwrap().parse::<Addr>();
+            let val = parts.next().map(str::parse::<Number>);
+            match (addr, val) {
+                (Ok(addr), Some(Ok(val))) if addr >= low_len && addr < DEFAULT_MEMORY_LIMIT => { mem[addr] = val; },
+                _ => return self.error(format!("invalid memory cell '{}'", cell)),
+            }
+        }
+        Ok(mem)
+    }
+}
+
+#[test]
+fn test_snapshot_roundtrip() {
+    let mut program = Program::parse("109,5,3,1000,4,1000,204,-5,99");
+    program.give_input(42);
+    assert_eq!(program.run_until_interrupt(), Interrupt::Writing);
+
+    let snapshot = program.to_snapshot();
+    assert!(snapshot.contains("\ninterrupt writing\n"));
+    assert!(snapshot.contains("\nmemory 0=109,5,3,1000,4,1000,204,-5,99 1000=42\n"));
+    let mut restored = Program::from_snapshot(&snapshot).unwrap();
+    assert_eq!(restored, program);
+    assert_eq!(restored.run_with_io(vec![]), vec![42, 109]);
+}
+
+#[test]
+fn test_snapshot_errors() {
+    let snapshot = Program::parse("3,0,99").to_snapshot();
+    match Program::from_snapshot(&snapshot.replace("intcode-snapshot 2", "intcode-snapshot 3")) {
+        Err(SnapshotError::UnsupportedVersion(3)) => {},
+        other => panic!("Unexpected result {:?}", other),
+    }
+    match Program::from_snapshot(&snapshot.replace("\nend\n", "\n")) {
+        Err(SnapshotError::Format { line: 10, .. }) => {},
+        other => panic!("Unexpected result {:?}", other),
+    }
+    match Program::from_snapshot(&snapshot.replace("interrupt none", "interrupt maybe")) {
+        Err(SnapshotError::Format { line: 4, .. }) => {},
+        other => panic!("Unexpected result {:?}", other),
+    }
+    match Program::from_snapshot(&snapshot.replace("memory 0=3,0,99", "memory 0=3,x,99")) {
+        Err(SnapshotError::Format { line: 9, .. }) => {},
+        other => panic!("Unexpected result {:?}", other),
+    }
+    match Program::from_snapshot(&snapshot.replace("memory 0=3,0,99", "memory 0=3,0,99 16777215=1,2")) {
+        Err(SnapshotError::Format { line: 9, .. }) => {},
+        other => panic!("Unexpected result {:?}", other),
+    }
+    match Program::from_snapshot(&snapshot.replace("limit 16777216", "limit 18446744073709551615")) {
+        Err(SnapshotError::Format { line: 8, .. }) => {},
+        other => panic!("Unexpected result {:?}", other),
+    }
+    match Program::from_snapshot(&snapshot.replace("memory 0=3,0,99", "memory 18446744073709551615=1,2")) {
+        Err(SnapshotError::Format { line: 9, .. }) => {},
+        other => panic!("Unexpected result {:?}", other),
+    }
+    match Program::from_snapshot(&snapshot.replace("\nip 0\n", "\nip 18446744073709551615\n")) {
+        Err(SnapshotError::Format { line: 2, .. }) => {},
+        other => panic!("Unexpected result {:?}", other),
+    }
+    match Program::from_snapshot(&snapshot.replace("cur_ip 0", "cur_ip 16777216")) {
+        Err(SnapshotError::Format { line: 5, .. }) => {},
+        other => panic!("Unexpected result {:?}", other),
+    }
+    match Program::from_snapshot("not a snapshot") {
+        Err(SnapshotError::Format { line: 1, .. }) => {},
+        other => panic!("Unexpected result {:?}", other),
+    }
+}
+
+#[test]
+fn test_snapshot_overflow() {
+    // Adds the largest number to itself.
+    let input = format!("1,5,5,0,99,{}", Number::MAX);
+    let program = Program::parse(&input).with_overflow(Overflow::Wrap);
+    let snapshot = program.to_snapshot();
+    assert!(snapshot.contains("\noverflow wrap\n"));
+    let mut restored = Program::from_snapshot(&snapshot).unwrap();
+    assert_eq!(restored, program);
+    assert_eq!(restored.run_until_interrupt(), Interrupt::Halted);
+    assert_eq!(restored.mem[0], -2);
+
+    let promote = Program::parse(&input).with_overflow(Overflow::Promote);
+    assert_eq!(Program::from_snapshot(&promote.to_snapshot()).unwrap(), promote);
+    match Program::from_snapshot(&snapshot.replace("overflow wrap", "overflow saturate")) {
+        Err(SnapshotError::Format { line: 7, .. }) => {},
+        other => panic!("Unexpected result {:?}", other),
+    }
+}
+
+#[test]
+fn test_snapshot_v1() {
+    let snapshot = "intcode-snapshot 1\nip 1\nrelative_base 0\ninterrupt reading\ncur_ip 0\ncur_op 0\n\
+        low 3,0,4,0,99,0,0,0,0,0\nhigh 1000=5\nend\n";
+    let mut program = Program::from_snapshot(snapshot).unwrap();
+    assert_eq!(program.mem[1000], 5);
+    assert_eq!(program.run_with_io(vec![42]), vec![42]);
+}
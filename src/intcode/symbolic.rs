@@ -0,0 +1,690 @@
+//! Symbolic execution: runs a program with some memory cells or inputs replaced by variables, and
+//! solves for variable values that make the program produce a given result.
+//!
+//! Arithmetic on variables builds expression trees instead of numbers. A conditional jump on an
+//! expression forks the execution into two paths, each with a path condition. When the result is
+//! an affine function of the variables on every path, and every path condition is linear, the
+//! solver enumerates all variables but one and computes the last one directly. Otherwise, for
+//! example if a variable is used as an address or if overflow does not trap, it falls back to
+//! running the program for every combination of values, and reports why.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::rc::Rc;
+
+use super::{Addr, ArgMode, Memory, Number, Overflow, Program};
+
+/// Maximum number of paths that symbolic execution explores.
+const MAX_PATHS: usize = 256;
+
+/// Maximum number of instructions executed, over all paths together, and by each concrete run
+/// when falling back to enumeration.
+const MAX_STEPS: u64 = 1_000_000;
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Expr {
+    Const(Number),
+    /// A variable, by its index in the order in which it was added.
+    Var(usize),
+    Add(Rc<Expr>, Rc<Expr>),
+    Mul(Rc<Expr>, Rc<Expr>),
+    Lt(Rc<Expr>, Rc<Expr>),
+    Eq(Rc<Expr>, Rc<Expr>),
+    /// A read from an address that depends on a variable.
+    Load(Rc<Expr>),
+}
+
+/// An affine function of the variables: a constant plus a coefficient for each variable.
+#[derive(Debug, PartialEq, Eq, Clone)]
+struct Affine {
+    constant: Number,
+    coeffs: Vec<Number>,
+}
+
+impl Affine {
+    fn is_constant(&self) -> bool {
+        self.coeffs.iter().all(|&coeff| coeff == 0)
+    }
+
+    fn add(&self, other: &Affine) -> Option<Affine> {
+        Some(Affine {
+            constant: self.constant.checked_add(other.constant)?,
+            coeffs: self.coeffs.iter().zip(&other.coeffs).map(|(a, b)| a.checked_add(*b)).collect::<Option<_>>()?,
+        })
+    }
+
+    fn scale(&self, factor: Number) -> Option<Affine> {
+        Some(Affine {
+            constant: self.constant.checked_mul(factor)?,
+            coeffs: self.coeffs.iter().map(|a| a.checked_mul(factor)).collect::<Option<_>>()?,
+        })
+    }
+}
+
+impl Expr {
+    /// Applies an instruction to two operands, folding constants. Returns `None` if constant
+    /// operands overflow under the given policy, in which case the instruction would trap.
+    fn bin_op(opcode: Number, a: Rc<Expr>, b: Rc<Expr>, overflow: Overflow) -> Option<Rc<Expr>> {
+        let expr = match (opcode, &*a, &*b) {
+            (1, &Expr::Const(x), &Expr::Const(y)) => Expr::Const(overflow.add(x, y)?),
+            (2, &Expr::Const(x), &Expr::Const(y)) => Expr::Const(overflow.mul(x, y)?),
+            (7, &Expr::Const(x), &Expr::Const(y)) => Expr::Const(if x < y { 1 } else { 0 }),
+            (8, &Expr::Const(x), &Expr::Const(y)) => Expr::Const(if x == y { 1 } else { 0 }),
+            (1, Expr::Const(0), _) | (2, Expr::Const(1), _) => return Some(b),
+            (1, _, Expr::Const(0)) | (2, _, Expr::Const(1)) => return Some(a),
+            (2, Expr::Const(0), _) | (2, _, Expr::Const(0)) => Expr::Const(0),
+            (1, _, _) => Expr::Add(a, b),
+            (2, _, _) => Expr::Mul(a, b),
+            (7, _, _) => Expr::Lt(a, b),
+            _ => Expr::Eq(a, b),
+        };
+        Some(Rc::new(expr))
+    }
+
+    /// Evaluates the expression for the given variable values. Returns `None` if it reads memory or
+    /// overflows.
+    pub fn eval(&self, values: &[Number], overflow: Overflow) -> Option<Number> {
+        match self {
+            Expr::Const(n) => Some(*n),
+            Expr::Var(i) => Some(values[*i]),
+            Expr::Add(a, b) => overflow.add(a.eval(values, overflow)?, b.eval(values, overflow)?),
+            Expr::Mul(a, b) => overflow.mul(a.eval(values, overflow)?, b.eval(values, overflow)?),
+            Expr::Lt(a, b) => Some(if a.eval(values, overflow)? < b.eval(values, overflow)? { 1 } else { 0 }),
+            Expr::Eq(a, b) => Some(if a.eval(values, overflow)? == b.eval(values, overflow)? { 1 } else { 0 }),
+            Expr::Load(_) => None,
+        }
+    }
+
+    fn affine(&self, num_vars: usize) -> Option<Affine> {
+        match self {
+            Expr::Const(n) => Some(Affine { constant: *n, coeffs: vec![0; num_vars] }),
+            Expr::Var(i) => {
+                let mut coeffs = vec![0; num_vars];
+                coeffs[*i] = 1;
+                Some(Affine { constant: 0, coeffs })
+            },
+            Expr::Add(a, b) => a.affine(num_vars)?.add(&b.affine(num_vars)?),
+            Expr::Mul(a, b) => {
+                let (a, b) = (a.affine(num_vars)?, b.affine(num_vars)?);
+                if a.is_constant() {
+                    b.scale(a.constant)
+                } else if b.is_constant() {
+                    a.scale(b.constant)
+                } else {
+                    None
+                }
+            },
+            Expr::Lt(..) | Expr::Eq(..) | Expr::Load(_) => None,
+        }
+    }
+
+    /// Whether a path condition on this expression is a linear equation or inequality.
+    fn is_linear(&self, num_vars: usize) -> bool {
+        match self {
+            Expr::Lt(a, b) | Expr::Eq(a, b) => a.affine(num_vars).is_some() && b.affine(num_vars).is_some(),
+            _ => self.affine(num_vars).is_some(),
+        }
+    }
+
+    /// Formats the expression with the given variable names.
+    pub fn display<'a>(&'a self, names: &'a [String]) -> impl fmt::Display + 'a {
+        Named(self, names)
+    }
+}
+
+struct Named<'a>(&'a Expr, &'a [String]);
+
+impl<'a> fmt::Display for Named<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let Named(expr, names) = *self;
+        let sub = |expr: &'a Rc<Expr>| Named(expr, names);
+        match expr {
+            Expr::Const(n) => write!(f, "{}", n),
+            Expr::Var(i) => write!(f, "{}", names[*i]),
+            Expr::Add(a, b) => write!(f, "({} + {})", sub(a), sub(b)),
+            Expr::Mul(a, b) => write!(f, "({} * {})", sub(a), sub(b)),
+            Expr::Lt(a, b) => write!(f, "({} < {})", sub(a), sub(b)),
+            Expr::Eq(a, b) => write!(f, "({} == {})", sub(a), sub(b)),
+            Expr::Load(addr) => write!(f, "mem[{}]", sub(addr)),
+        }
+    }
+}
+
+/// A reason why symbolic execution gave up.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum SymbolicError {
+    /// An instruction would be executed whose opcode depends on a variable.
+    SymbolicCode { ip: Addr },
+    /// A value would be written to, or the relative base set to, an address that depends on a variable.
+    SymbolicAddress { ip: Addr },
+    /// A jump would go to an address that depends on a variable.
+    SymbolicJump { ip: Addr },
+    /// The program reads more input than was given.
+    InputExhausted { ip: Addr },
+    TooManyPaths,
+    TooManySteps,
+}
+
+impl fmt::Display for SymbolicError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SymbolicError::SymbolicCode { ip } => write!(f, "Symbolic instruction at address {}", ip),
+            SymbolicError::SymbolicAddress { ip } => write!(f, "Symbolic address used at address {}", ip),
+            SymbolicError::SymbolicJump { ip } => write!(f, "Symbolic jump target at address {}", ip),
+            SymbolicError::InputExhausted { ip } => write!(f, "Attempted to read from empty input at address {}", ip),
+            SymbolicError::TooManyPaths => write!(f, "More than {} paths", MAX_PATHS),
+            SymbolicError::TooManySteps => write!(f, "More than {} steps", MAX_STEPS),
+        }
+    }
+}
+
+impl std::error::Error for SymbolicError {}
+
+/// A condition that holds on a path: the expression is nonzero, or zero.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Constraint {
+    pub cond: Rc<Expr>,
+    pub nonzero: bool,
+}
+
+impl Constraint {
+    fn holds(&self, values: &[Number], overflow: Overflow) -> bool {
+        self.cond.eval(values, overflow).map(|val| (val != 0) == self.nonzero) == Some(true)
+    }
+}
+
+/// What the program should produce.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Goal {
+    /// The value of a memory cell when the program halts.
+    Cell(Addr),
+    /// The output with the given index.
+    Output(usize),
+}
+
+/// A path through the program from start to halt.
+#[derive(Debug, Clone)]
+pub struct Path {
+    pub constraints: Vec<Constraint>,
+    pub outputs: Vec<Rc<Expr>>,
+    mem: Memory,
+    symbolic: BTreeMap<Addr, Rc<Expr>>,
+}
+
+impl Path {
+    /// The value of a memory cell when the program halts.
+    pub fn cell(&self, addr: Addr) -> Rc<Expr> {
+        self.symbolic.get(&addr).cloned().unwrap_or_else(|| Rc::new(Expr::Const(self.mem[addr])))
+    }
+
+    pub fn goal(&self, goal: Goal) -> Option<Rc<Expr>> {
+        match goal {
+            Goal::Cell(addr) => Some(self.cell(addr)),
+            Goal::Output(i) => self.outputs.get(i).cloned(),
+        }
+    }
+}
+
+/// How a solution was found.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Method {
+    /// Solved on the affine expressions from symbolic execution.
+    Affine,
+    /// Found by running the program for every combination of values, for the given reason.
+    Enumeration { reason: String },
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Solution {
+    /// Values for the variables, in the order in which they were added, or `None` if there are none
+    /// within their ranges.
+    pub values: Option<Vec<Number>>,
+    pub method: Method,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Input {
+    Value(Number),
+    Variable(usize),
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Source {
+    Cell(Addr),
+    Input,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+struct Variable {
+    name: String,
+    source: Source,
+    range: RangeInclusive<Number>,
+}
+
+/// The state of one path during symbolic execution.
+#[derive(Debug, Clone)]
+struct State {
+    mem: Memory,
+    symbolic: BTreeMap<Addr, Rc<Expr>>,
+    ip: Addr,
+    relative_base: Number,
+    input: usize,
+    outputs: Vec<Rc<Expr>>,
+    constraints: Vec<Constraint>,
+}
+
+/// How control continues after a symbolic step.
+enum Flow {
+    Next,
+    Halt,
+    /// The condition decides whether to jump to the target.
+    Fork(Rc<Expr>, Addr),
+}
+
+/// Why a path ends without halting.
+enum Abort {
+    /// The program faults on this path, so it has no solutions.
+    Fault,
+    Error(SymbolicError),
+}
+
+impl From<SymbolicError> for Abort {
+    fn from(err: SymbolicError) -> Self {
+        Abort::Error(err)
+    }
+}
+
+impl State {
+    fn addr(&self, n: Number) -> Option<Addr> {
+        if n >= 0 && (n as Addr) < self.mem.limit() { Some(n as Addr) } else { None }
+    }
+
+    fn read(&self, addr: Addr) -> Rc<Expr> {
+        self.symbolic.get(&addr).cloned().unwrap_or_else(|| Rc::new(Expr::Const(self.mem[addr])))
+    }
+
+    fn write(&mut self, addr: Addr, val: Rc<Expr>) {
+        match *val {
+            Expr::Const(n) => {
+                self.symbolic.remove(&addr);
+                self.mem[addr] = n;
+            },
+            _ => {
+                self.symbolic.insert(addr, val);
+            },
+        }
+    }
+
+    fn concrete(&self, val: &Expr, err: SymbolicError) -> Result<Number, Abort> {
+        match *val {
+            Expr::Const(n) => Ok(n),
+            _ => Err(Abort::Error(err)),
+        }
+    }
+
+    /// The address of the operand at `ip + 1 + i`, which must not depend on a variable.
+    fn operand_addr(&self, ip: Addr, i: usize, mode: ArgMode) -> Result<Addr, Abort> {
+        let offset = self.concrete(&self.read(ip + 1 + i), SymbolicError::SymbolicAddress { ip })?;
+        match mode {
+            ArgMode::Position => self.addr(offset),
+            ArgMode::Relative => offset.checked_add(self.relative_base).and_then(|n| self.addr(n)),
+            ArgMode::Immediate => None,
+        }.ok_or(Abort::Fault)
+    }
+
+    /// The value of the operand at `ip + 1 + i`.
+    fn operand(&self, ip: Addr, i: usize, mode: ArgMode) -> Result<Rc<Expr>, Abort> {
+        let word = self.read(ip + 1 + i);
+        let addr = match (mode, &*word) {
+            (ArgMode::Immediate, _) => return Ok(word),
+            (ArgMode::Position, &Expr::Const(n)) => self.addr(n),
+            (ArgMode::Relative, &Expr::Const(n)) => n.checked_add(self.relative_base).and_then(|n| self.addr(n)),
+            (ArgMode::Position, _) => return Ok(Rc::new(Expr::Load(word))),
+            (ArgMode::Relative, _) => return Ok(Rc::new(Expr::Load(Rc::new(Expr::Add(Rc::new(Expr::Const(self.relative_base)), word))))),
+        };
+        Ok(self.read(addr.ok_or(Abort::Fault)?))
+    }
+}
+
+/// A program with some of its memory cells or inputs replaced by variables.
+#[derive(Debug, Clone)]
+pub struct Symbolic {
+    program: Program,
+    variables: Vec<Variable>,
+    inputs: Vec<Input>,
+}
+
+impl Symbolic {
+    pub fn new(program: Program) -> Self {
+        Symbolic { program, variables: vec![], inputs: vec![] }
+    }
+
+    /// Replaces a memory cell by a variable that can take values in `range`.
+    pub fn with_cell(mut self, name: &str, addr: Addr, range: RangeInclusive<Number>) -> Self {
+        self.variables.push(Variable { name: name.to_string(), source: Source::Cell(addr), range });
+        self
+    }
+
+    /// Adds an input that is a variable that can take values in `range`.
+    pub fn with_symbolic_input(mut self, name: &str, range: RangeInclusive<Number>) -> Self {
+        self.inputs.push(Input::Variable(self.variables.len()));
+        self.variables.push(Variable { name: name.to_string(), source: Source::Input, range });
+        self
+    }
+
+    /// Adds an input with a known value.
+    pub fn with_input(mut self, value: Number) -> Self {
+        self.inputs.push(Input::Value(value));
+        self
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.variables.iter().map(|var| var.name.clone()).collect()
+    }
+
+    /// Executes the program symbolically, and returns every path that halts. Paths that fault are
+    /// left out.
+    pub fn explore(&self) -> Result<Vec<Path>, SymbolicError> {
+        let mut initial = State {
+            mem: self.program.mem.clone(),
+            symbolic: BTreeMap::new(),
+            ip: self.program.ip(),
+            relative_base: self.program.relative_base(),
+            input: 0,
+            outputs: vec![],
+            constraints: vec![],
+        };
+        for (i, var) in self.variables.iter().enumerate() {
+            if let Source::Cell(addr) = var.source {
+                initial.symbolic.insert(addr, Rc::new(Expr::Var(i)));
+            }
+        }
+
+        let mut paths = vec![];
+        let mut todo = vec![initial];
+        let mut steps = 0;
+        while let Some(mut state) = todo.pop() {
+            loop {
+                steps += 1;
+                if steps > MAX_STEPS {
+                    return Err(SymbolicError::TooManySteps);
+                }
+                match self.step(&mut state) {
+                    Err(Abort::Fault) => break,
+                    Err(Abort::Error(err)) => return Err(err),
+                    Ok(Flow::Next) => {},
+                    Ok(Flow::Halt) => {
+                        paths.push(Path { constraints: state.constraints, outputs: state.outputs, mem: state.mem, symbolic: state.symbolic });
+                        break;
+                    },
+                    Ok(Flow::Fork(cond, target)) => {
+                        if paths.len() + todo.len() + 2 > MAX_PATHS {
+                            return Err(SymbolicError::TooManyPaths);
+                        }
+                        let mut jumped = state.clone();
+                        jumped.ip = target;
+                        jumped.constraints.push(Constraint { cond: cond.clone(), nonzero: true });
+                        todo.push(jumped);
+                        state.constraints.push(Constraint { cond, nonzero: false });
+                    },
+                }
+            }
+        }
+        Ok(paths)
+    }
+
+    /// Executes one instruction, with the same opcode and parameter mode rules as
+    /// `Program::run_until_interrupt`.
+    fn step(&self, state: &mut State) -> Result<Flow, Abort> {
+        let ip = state.ip;
+        let word = state.concrete(&state.read(ip), SymbolicError::SymbolicCode { ip })?;
+        if word < 0 {
+            return Err(Abort::Fault);
+        }
+        let opcode = word % 100;
+        let num_operands = match opcode {
+            1 | 2 | 7 | 8 => 3,
+            3 | 4 | 9 => 1,
+            5 | 6 => 2,
+            99 => 0,
+            _ => return Err(Abort::Fault),
+        };
+        let mut modes = vec![];
+        let mut digits = word / 100;
+        for _ in 0..num_operands {
+            modes.push(ArgMode::from_number(digits % 10).ok_or(Abort::Fault)?);
+            digits /= 10;
+        }
+        let next = ip + 1 + num_operands;
+        if next > state.mem.limit() {
+            return Err(Abort::Fault);
+        }
+
+        state.ip = next;
+        match opcode {
+            1 | 2 | 7 | 8 => {
+                let (a, b) = (state.operand(ip, 0, modes[0])?, state.operand(ip, 1, modes[1])?);
+                let dest = state.operand_addr(ip, 2, modes[2])?;
+                let val = Expr::bin_op(opcode, a, b, self.program.overflow()).ok_or(Abort::Fault)?;
+                state.write(dest, val);
+            },
+            3 => {
+                let dest = state.operand_addr(ip, 0, modes[0])?;
+                let val = match self.inputs.get(state.input) {
+                    Some(Input::Value(n)) => Expr::Const(*n),
+                    Some(Input::Variable(i)) => Expr::Var(*i),
+                    None => return Err(Abort::Error(SymbolicError::InputExhausted { ip })),
+                };
+                state.input += 1;
+                state.write(dest, Rc::new(val));
+            },
+            4 => {
+                let val = state.operand(ip, 0, modes[0])?;
+                state.outputs.push(val);
+            },
+            5 | 6 => {
+                let cond = state.operand(ip, 0, modes[0])?;
+                let target = state.operand(ip, 1, modes[1])?;
+                let target = state.concrete(&target, SymbolicError::SymbolicJump { ip })?;
+                let target = state.addr(target).ok_or(Abort::Fault)?;
+                match *cond {
+                    Expr::Const(n) => if (n != 0) == (opcode == 5) {
+                        state.ip = target;
+                    },
+                    _ if opcode == 6 => return Ok(Flow::Fork(Rc::new(Expr::Eq(cond, Rc::new(Expr::Const(0)))), target)),
+                    _ => return Ok(Flow::Fork(cond, target)),
+                }
+            },
+            9 => {
+                let offset = state.operand(ip, 0, modes[0])?;
+                let offset = state.concrete(&offset, SymbolicError::SymbolicAddress { ip })?;
+                state.relative_base = state.relative_base.checked_add(offset).ok_or(Abort::Fault)?;
+            },
+            _ => return Ok(Flow::Halt),
+        }
+        Ok(Flow::Next)
+    }
+
+    /// Finds values for the variables for which the program halts with the goal equal to `value`.
+    /// If several exist, which one is returned is unspecified. Solving assumes that arithmetic
+    /// overflow traps; under any other policy, all values are enumerated.
+    pub fn solve(&self, goal: Goal, value: Number) -> Solution {
+        let overflow = self.program.overflow();
+        if overflow != Overflow::Trap {
+            return self.enumerate(goal, value, format!("Overflow policy {:?} does not trap", overflow));
+        }
+        let paths = match self.explore() {
+            Ok(paths) => paths,
+            Err(err) => return self.enumerate(goal, value, err.to_string()),
+        };
+        let names = self.names();
+        let num_vars = self.variables.len();
+        let mut affine_paths = vec![];
+        for path in &paths {
+            let expr = match path.goal(goal) {
+                Some(expr) => expr,
+                None => continue,
+            };
+            let affine = match expr.affine(num_vars) {
+                Some(affine) => affine,
+                None => return self.enumerate(goal, value, format!("Result {} is not affine", expr.display(&names))),
+            };
+            if let Some(constraint) = path.constraints.iter().find(|constraint| !constraint.cond.is_linear(num_vars)) {
+                return self.enumerate(goal, value, format!("Path condition {} is not linear", constraint.cond.display(&names)));
+            }
+            affine_paths.push((path, expr, affine));
+        }
+
+        let values = affine_paths.iter()
+            .filter_map(|(path, expr, affine)| self.solve_path(path, expr, affine, value))
+            .min();
+        Solution { values, method: Method::Affine }
+    }
+
+    /// Solves `affine == value` on a single path, by enumerating all variables except the last one
+    /// that the result depends on, and dividing for that one.
+    fn solve_path(&self, path: &Path, expr: &Expr, affine: &Affine, value: Number) -> Option<Vec<Number>> {
+        let overflow = self.program.overflow();
+        let solved = affine.coeffs.iter().rposition(|&coeff| coeff != 0);
+        let mut ranges = self.variables.iter().map(|var| var.range.clone()).collect::<Vec<_>>();
+        if let Some(solved) = solved {
+            ranges[solved] = 0..=0;
+        }
+        find_assignment(&ranges, &mut |values| {
+            if let Some(solved) = solved {
+                let rest = affine.coeffs.iter().zip(values.iter()).enumerate()
+                    .filter(|&(i, _)| i != solved)
+                    .try_fold(affine.constant, |sum, (_, (&coeff, &val))| sum.checked_add(coeff.checked_mul(val)?));
+                let remainder = rest.and_then(|rest| value.checked_sub(rest));
+                let coeff = affine.coeffs[solved];
+                // `Number::MIN / -1` does not fit, so there is no solution then.
+                match remainder.filter(|&remainder| remainder.checked_rem(coeff) == Some(0)) {
+                    Some(remainder) => {
+                        values[solved] = remainder / coeff;
+                        if !self.variables[solved].range.contains(&values[solved]) {
+                            return false;
+                        }
+                    },
+                    None => return false,
+                }
+            }
+            expr.eval(values, overflow) == Some(value) &&
+                path.constraints.iter().all(|constraint| constraint.holds(values, overflow))
+        })
+    }
+
+    /// Runs the program for every combination of values, in order.
+    fn enumerate(&self, goal: Goal, value: Number, reason: String) -> Solution {
+        let ranges = self.variables.iter().map(|var| var.range.clone()).collect::<Vec<_>>();
+        let values = find_assignment(&ranges, &mut |values| self.run_concrete(values, goal) == Some(value));
+        Solution { values, method: Method::Enumeration { reason } }
+    }
+
+    fn run_concrete(&self, values: &[Number], goal: Goal) -> Option<Number> {
+        let mut program = self.program.clone().with_fuel(MAX_STEPS);
+        for (var, &val) in self.variables.iter().zip(values) {
+            if let Source::Cell(addr) = var.source {
+                program.mem[addr] = val;
+            }
+        }
+        let input = self.inputs.iter()
+            .map(|input| match *input {
+                Input::Value(n) => n,
+                Input::Variable(i) => values[i],
+            })
+            .collect();
+        let output = program.try_run_with_io(input).ok()?;
+        match goal {
+            Goal::Cell(addr) => Some(program.mem[addr]),
+            Goal::Output(i) => output.get(i).copied(),
+        }
+    }
+}
+
+/// Calls `f` on every combination of values in the ranges, in lexicographic order, until it
+/// returns true, and returns that combination. `f` may modify the values it is given.
+fn find_assignment<F>(ranges: &[RangeInclusive<Number>], f: &mut F) -> Option<Vec<Number>>
+    where F: FnMut(&mut [Number]) -> bool
+{
+    fn recurse<F>(ranges: &[RangeInclusive<Number>], values: &mut Vec<Number>, f: &mut F) -> bool
+        where F: FnMut(&mut [Number]) -> bool
+    {
+        let i = values.len();
+        if i == ranges.len() {
+            let mut candidate = values.clone();
+            if f(&mut candidate) {
+                *values = candidate;
+                return true;
+            }
+            return false;
+        }
+        for val in ranges[i].clone() {
+            values.push(val);
+            if recurse(ranges, values, f) {
+                return true;
+            }
+            values.pop();
+        }
+        false
+    }
+    let mut values = vec![];
+    if recurse(ranges, &mut values, f) { Some(values) } else { None }
+}
+
+#[test]
+fn test_affine() {
+    // Like day 2: the first instruction reads from the variable addresses, but its result is
+    // overwritten. Computes 100 * noun + verb + 7.
+    let symbolic = Symbolic::new(Program::parse("1,0,0,3,2,1,17,3,1,3,2,0,1,0,18,0,99,100,7"))
+        .with_cell("noun", 1, 0..=99)
+        .with_cell("verb", 2, 0..=99);
+    let paths = symbolic.explore().unwrap();
+    assert_eq!(paths.len(), 1);
+    assert_eq!(paths[0].cell(0).display(&symbolic.names()).to_string(), "(((noun * 100) + verb) + 7)");
+    assert_eq!(symbolic.solve(Goal::Cell(0), 1234), Solution { values: Some(vec![12, 27]), method: Method::Affine });
+    assert_eq!(symbolic.solve(Goal::Cell(0), 6).values, None);
+
+    // Negates x, which can never give `Number::MIN`.
+    let symbolic = Symbolic::new(Program::parse("1002,5,-1,0,99,0")).with_cell("x", 5, 0..=9);
+    assert_eq!(symbolic.solve(Goal::Cell(0), Number::MIN), Solution { values: None, method: Method::Affine });
+}
+
+#[test]
+fn test_path_conditions() {
+    // Outputs x + 100 if x < 5, and x otherwise.
+    let symbolic = Symbolic::new(Program::parse("3,30,7,30,31,32,1005,32,12,4,30,99,101,100,30,30,1105,1,9,\
+                                                 0,0,0,0,0,0,0,0,0,0,0,0,5,0"))
+        .with_symbolic_input("x", 0..=9);
+    assert_eq!(symbolic.explore().unwrap().len(), 2);
+    assert_eq!(symbolic.solve(Goal::Output(0), 103).values, Some(vec![3]));
+    assert_eq!(symbolic.solve(Goal::Output(0), 7).values, Some(vec![7]));
+    assert_eq!(symbolic.solve(Goal::Output(0), 2).values, None);
+}
+
+#[test]
+fn test_enumeration() {
+    let symbolic = Symbolic::new(Program::parse("2,5,6,0,99,0,0"))
+        .with_cell("x", 5, 0..=9)
+        .with_cell("y", 6, 0..=9);
+    assert_eq!(symbolic.solve(Goal::Cell(0), 42), Solution {
+        values: Some(vec![6, 7]),
+        method: Method::Enumeration { reason: "Result (x * y) is not affine".to_string() },
+    });
+
+    // Jumps to x.
+    let symbolic = Symbolic::new(Program::parse("105,1,5,99,0,0,104,1,99")).with_cell("x", 5, 0..=9);
+    assert_eq!(symbolic.solve(Goal::Output(0), 1), Solution {
+        values: Some(vec![6]),
+        method: Method::Enumeration { reason: "Symbolic jump target at address 0".to_string() },
+    });
+
+    // Doubles x, which only gives `Number::MIN` when it wraps around.
+    let half = Number::MIN / -2;
+    let symbolic = Symbolic::new(Program::parse("1002,5,2,0,99,0").with_overflow(Overflow::Wrap))
+        .with_cell("x", 5, half..=half + 2);
+    assert_eq!(symbolic.solve(Goal::Cell(0), Number::MIN), Solution {
+        values: Some(vec![half]),
+        method: Method::Enumeration { reason: "Overflow policy Wrap does not trap".to_string() },
+    });
+}
@@ -0,0 +1,326 @@
+use std::cell::RefCell;
+use std::io;
+use std::rc::Rc;
+
+use super::disasm::Op;
+use super::{Addr, ArgMode, Number};
+
+/// Receives a callback for every instruction that a `Program` executes.
+pub trait Tracer {
+    /// If false, all tracing code is compiled out of the interpreter.
+    const ENABLED: bool = true;
+
+    /// If true, a custom instruction that writes more than one memory cell faults, because the
+    /// tracer needs every write, and `Step::write` holds only one.
+    const SINGLE_WRITE: bool = false;
+
+    /// Called when an instruction has completed. Input and output instructions complete in
+    /// `give_input` and `take_output` respectively.
+    fn step(&mut self, step: &Step);
+}
+
+/// The default tracer, which does nothing and costs nothing.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct NoTracer;
+
+impl Tracer for NoTracer {
+    const ENABLED: bool = false;
+
+    fn step(&mut self, _step: &Step) {}
+}
+
+/// Collects all steps in memory.
+impl Tracer for Vec<Step> {
+    fn step(&mut self, step: &Step) {
+        self.push(*step);
+    }
+}
+
+/// Allows a tracer to be shared between clones of a `Program`.
+impl<T: Tracer> Tracer for Rc<RefCell<T>> {
+    const SINGLE_WRITE: bool = T::SINGLE_WRITE;
+
+    fn step(&mut self, step: &Step) {
+        self.borrow_mut().step(step);
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct MemWrite {
+    pub addr: Addr,
+    pub old: Number,
+    pub new: Number,
+}
+
+/// A single executed instruction. Operands are evaluated: inputs hold the value that was read,
+/// outputs hold the address that was written to.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Step {
+    pub ip: Addr,
+    pub opcode: Number,
+    modes: [ArgMode; 3],
+    operands: [Number; 3],
+    num_operands: usize,
+    /// The memory cell that was written, if any. Custom instructions may write several; this is
+    /// the last one, unless the tracer sets `Tracer::SINGLE_WRITE`.
+    pub write: Option<MemWrite>,
+}
+
+impl Step {
+    pub fn new(ip: Addr, opcode: Number) -> Self {
+        Step {
+            ip,
+            opcode,
+            modes: [ArgMode::Position; 3],
+            operands: [0; 3],
+            num_operands: 0,
+            write: None,
+        }
+    }
+
+    /// Records the next operand. Only the first three are kept, since no standard instruction has
+    /// more; custom instructions may evaluate any number.
+    pub fn push_operand(&mut self, mode: ArgMode, value: Number) {
+        if self.num_operands < self.operands.len() {
+            self.modes[self.num_operands] = mode;
+            self.operands[self.num_operands] = value;
+            self.num_operands += 1;
+        }
+    }
+
+    pub fn modes(&self) -> &[ArgMode] {
+        &self.modes[..self.num_operands]
+    }
+
+    pub fn operands(&self) -> &[Number] {
+        &self.operands[..self.num_operands]
+    }
+}
+
+/// Writes a human-readable line per instruction, like:
+///
+/// ```text
+/// 0004: ADD 3, 5 -> [103]  ; [103] 0 => 8
+/// ```
+pub struct LineTracer<W: io::Write> {
+    out: W,
+}
+
+impl<W: io::Write> LineTracer<W> {
+    pub fn new(out: W) -> Self {
+        LineTracer { out }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+impl<W: io::Write> Tracer for LineTracer<W> {
+    fn step(&mut self, step: &Step) {
+        let op = Op::from_opcode(step.opcode);
+        let mut line = format!("{:04}: {}", step.ip, op.map_or_else(|| format!("OP{}", step.opcode), |op| op.mnemonic().to_string()));
+        let num_inputs = op.map_or(step.num_operands, Op::num_inputs);
+        for (i, &operand) in step.operands().iter().enumerate() {
+            if i < num_inputs {
+                line += &format!("{} {}", if i == 0 { "" } else { "," }, operand);
+            } else {
+                line += &format!(" -> [{}]", operand);
+            }
+        }
+        if let Some(write) = step.write {
+            line += &format!("  ; [{}] {} => {}", write.addr, write.old, write.new);
+        }
+        writeln!(self.out, "{}", line).expect("Failed to write trace");
+    }
+}
+
+const BINARY_MAGIC: &[u8] = b"ICTR\x01";
+
+/// Writes a compact binary trace. After a header, every step is encoded as:
+///
+/// * the instruction pointer as a varint,
+/// * the opcode in the low 7 bits of a byte, with the high bit set if the step wrote to memory,
+/// * the number of operands in the low 2 bits of a byte, and their modes in 2 bits each above that,
+/// * each operand as a zigzag varint,
+/// * if there was a write, its address as a varint and the old and new values as zigzag varints.
+pub struct BinaryTracer<W: io::Write> {
+    out: W,
+}
+
+impl<W: io::Write> BinaryTracer<W> {
+    pub fn new(mut out: W) -> io::Result<Self> {
+        out.write_all(BINARY_MAGIC)?;
+        Ok(BinaryTracer { out })
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+
+    fn write_step(&mut self, step: &Step) -> io::Result<()> {
+        let mut buf = vec![];
+        write_varint(&mut buf, step.ip as Unsigned);
+        buf.push(step.opcode as u8 | if step.write.is_some() { 0x80 } else { 0 });
+        let mut modes = step.num_operands as u8;
+        for (i, mode) in step.modes().iter().enumerate() {
+            modes |= (mode.to_number() as u8) << (2 + 2 * i);
+        }
+        buf.push(modes);
+        for &operand in step.operands() {
+            write_varint(&mut buf, zigzag(operand));
+        }
+        if let Some(write) = step.write {
+            write_varint(&mut buf, write.addr as Unsigned);
+            write_varint(&mut buf, zigzag(write.old));
+            write_varint(&mut buf, zigzag(write.new));
+        }
+        self.out.write_all(&buf)
+    }
+}
+
+impl<W: io::Write> Tracer for BinaryTracer<W> {
+    fn step(&mut self, step: &Step) {
+        self.write_step(step).expect("Failed to write trace");
+    }
+}
+
+/// Reads back a trace written by `BinaryTracer`.
+pub struct BinaryTraceReader<R: io::BufRead> {
+    input: io::Bytes<R>,
+}
+
+impl<R: io::BufRead> BinaryTraceReader<R> {
+    pub fn new(input: R) -> io::Result<Self> {
+        let mut input = input.bytes();
+        for &expected in BINARY_MAGIC {
+            if input.next().transpose()? != Some(expected) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "Not an Intcode binary trace"));
+            }
+        }
+        Ok(BinaryTraceReader { input })
+    }
+
+    fn byte(&mut self) -> io::Result<u8> {
+        self.input.next().unwrap_or_else(|| Err(io::ErrorKind::UnexpectedEof.into()))
+    }
+
+    fn varint(&mut self) -> io::Result<Unsigned> {
+        let mut val = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.byte()?;
+            if shift >= Unsigned::BITS {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "Varint too long in trace"));
+            }
+            val |= ((byte & 0x7f) as Unsigned) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(val);
+            }
+            shift += 7;
+        }
+    }
+
+    fn read_step(&mut self, first: u8) -> io::Result<Step> {
+        let mut ip = (first & 0x7f) as Unsigned;
+        if first & 0x80 != 0 {
+            ip |= self.varint()? << 7;
+        }
+        let opcode = self.byte()?;
+        let mut step = Step::new(ip as Addr, (opcode & 0x7f) as Number);
+        let modes = self.byte()?;
+        for i in 0..(modes & 3) as usize {
+            let mode = ArgMode::from_number(((modes >> (2 + 2 * i)) & 3) as Number)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid mode in trace"))?;
+            let operand = unzigzag(self.varint()?);
+            step.push_operand(mode, operand);
+        }
+        if opcode & 0x80 != 0 {
+            let addr = self.varint()? as Addr;
+            let old = unzigzag(self.varint()?);
+            let new = unzigzag(self.varint()?);
+            step.write = Some(MemWrite { addr, old, new });
+        }
+        Ok(step)
+    }
+}
+
+impl<R: io::BufRead> Iterator for BinaryTraceReader<R> {
+    type Item = io::Result<Step>;
+    fn next(&mut self) -> Option<Self::Item> {
+        let first = match self.input.next()? {
+            Ok(first) => first,
+            Err(err) => return Some(Err(err)),
+        };
+        Some(self.read_step(first))
+    }
+}
+
+/// The unsigned counterpart of `Number`, for varints.
+#[cfg(not(feature = "wide"))]
+type Unsigned = u64;
+#[cfg(feature = "wide")]
+type Unsigned = u128;
+
+fn write_varint(buf: &mut Vec<u8>, mut val: Unsigned) {
+    while val >= 0x80 {
+        buf.push((val & 0x7f) as u8 | 0x80);
+        val >>= 7;
+    }
+    buf.push(val as u8);
+}
+
+fn zigzag(n: Number) -> Unsigned {
+    ((n << 1) ^ (n >> (Number::BITS - 1))) as Unsigned
+}
+
+fn unzigzag(n: Unsigned) -> Number {
+    (n >> 1) as Number ^ -((n & 1) as Number)
+}
+
+#[test]
+fn test_line_tracer() {
+    use super::Program;
+
+    let mut program = Program::parse("3,9,1001,9,-5,10,4,10,99,0,0").with_tracer(LineTracer::new(vec![]));
+    assert_eq!(program.run_with_io(vec![47]), vec![42]);
+    assert_eq!(
+        String::from_utf8(program.into_tracer().into_inner()).unwrap(),
+        "0000: IN -> [9]  ; [9] 0 => 47\n\
+         0002: ADD 47, -5 -> [10]  ; [10] 0 => 42\n\
+         0006: OUT 42\n\
+         0008: HLT\n");
+}
+
+#[test]
+fn test_binary_tracer() {
+    use super::Program;
+
+    let input = "109,-1000,21101,-1,100000,1001,1105,1,12,0,0,0,204,1001,99";
+    let mut steps = Program::parse(input).with_tracer(vec![]);
+    steps.run_with_io(vec![]);
+    let mut binary = Program::parse(input).with_tracer(BinaryTracer::new(vec![]).unwrap());
+    binary.run_with_io(vec![]);
+    let bytes = binary.into_tracer().into_inner();
+    assert_eq!(
+        BinaryTraceReader::new(&bytes[..]).unwrap().collect::<io::Result<Vec<_>>>().unwrap(),
+        steps.into_tracer());
+    assert!(BinaryTraceReader::new(&b"nope"[..]).is_err());
+}
+
+#[test]
+fn test_binary_extremes() {
+    let mut step = Step::new(Addr::MAX, 99);
+    for &val in &[Number::MIN, Number::MAX, -1] {
+        step.push_operand(ArgMode::Immediate, val);
+    }
+    step.write = Some(MemWrite { addr: Addr::MAX, old: Number::MIN, new: -1 });
+    let mut tracer = BinaryTracer::new(vec![]).unwrap();
+    tracer.step(&step);
+    let bytes = tracer.into_inner();
+    assert_eq!(BinaryTraceReader::new(&bytes[..]).unwrap().collect::<io::Result<Vec<_>>>().unwrap(), vec![step]);
+
+    let mut long = BINARY_MAGIC.to_vec();
+    long.extend(vec![0xff; 30]);
+    assert!(BinaryTraceReader::new(&long[..]).unwrap().next().unwrap().is_err());
+}
@@ -0,0 +1,415 @@
+//! Ahead-of-time translation of Intcode programs to Rust. `transpile` emits a module in which every
+//! statically reachable instruction is compiled to a few lines of Rust, dispatched on the
+//! instruction pointer:
+//!
+//! ```text
+//! // 0004: ADD [rb+1], #5 -> [100]
+//! 4 => {
+//!     let a: Number = t!(s.load_rel(1));
+//!     let b: Number = 5;
+//!     t!(s.store(100, t!(a.checked_add(b))));
+//!     s.ip = 8;
+//! },
+//! ```
+//!
+//! The module's `new` function returns a `Native`, which runs like a `Program`. Everything that
+//! is not compiled is left to the interpreter: input, output and halting, code that was not found
+//! statically, and instructions that the program overwrites through a constant address. Compiled
+//! code bails out to the interpreter, without side effects, whenever something unusual happens, like
+//! an invalid address or an overflow, so that faults are reported by the interpreter as usual. If
+//! the program writes to compiled code through any other address, the rest of the run is
+//! interpreted.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::sync::Arc;
+use std::time::Instant;
+
+use super::cfg;
+use super::disasm::{Instruction, Op, Operand};
+use super::trace::{NoTracer, Step, Tracer};
+use super::{to_addr, Addr, ArgMode, IntcodeError, Interrupt, Memory, Number, Program, DEFAULT_MEMORY_LIMIT};
+
+/// Number of words per line in the generated program text.
+const WORDS_PER_LINE: usize = 16;
+
+/// Emits the Rust expression for an input operand, or `None` if it is always invalid.
+fn load(operand: &Operand) -> Option<String> {
+    match operand.mode {
+        ArgMode::Immediate => Some(operand.value.to_string()),
+        ArgMode::Position if operand.value >= 0 => Some(format!("t!(s.load({}))", operand.value)),
+        ArgMode::Position => None,
+        ArgMode::Relative => Some(format!("t!(s.load_rel({}))", operand.value)),
+    }
+}
+
+/// Emits the Rust expression for the address of an output operand.
+fn dest(operand: &Operand) -> Option<String> {
+    match operand.mode {
+        ArgMode::Position if operand.value >= 0 => Some(operand.value.to_string()),
+        ArgMode::Relative => Some(format!("t!(s.rel({}))", operand.value)),
+        _ => None,
+    }
+}
+
+/// Emits the body of the match arm for an instruction, if it can be compiled.
+fn compile(addr: Addr, instr: &Instruction) -> Option<Vec<String>> {
+    let next = addr + instr.num_words();
+    let mut lines = vec![];
+    for (name, operand) in ["a", "b"].iter().zip(&instr.operands).take(instr.op.num_inputs()) {
+        lines.push(format!("let {}: Number = {};", name, load(operand)?));
+    }
+    let store = |val: &str| -> Option<String> {
+        Some(format!("t!(s.store({}, {}));", dest(&instr.operands[2])?, val))
+    };
+    match instr.op {
+        Op::Add => lines.push(store("t!(a.checked_add(b))")?),
+        Op::Mul => lines.push(store("t!(a.checked_mul(b))")?),
+        Op::Lt => lines.push(store("Number::from(a < b)")?),
+        Op::Eq => lines.push(store("Number::from(a == b)")?),
+        Op::Jnz | Op::Jz => {
+            let cond = if instr.op == Op::Jnz { "a != 0" } else { "a == 0" };
+            let target = match instr.jump_target() {
+                Some(target) if target < DEFAULT_MEMORY_LIMIT => {
+                    lines.truncate(1);
+                    target.to_string()
+                },
+                _ => "t!(s.target(b))".to_string(),
+            };
+            lines.push(format!("s.ip = if {} {{ {} }} else {{ {} }};", cond, target, next));
+            return Some(lines);
+        },
+        Op::Arb => lines.push("s.rb = t!(s.rb.checked_add(a));".to_string()),
+        Op::In | Op::Out | Op::Hlt => return None,
+    }
+    lines.push(format!("s.ip = {};", next));
+    Some(lines)
+}
+
+/// Translates a program to the source code of a Rust module. `intcode_path` is the path of this
+/// crate's `intcode` module as seen from the generated module, such as `aoc::intcode`.
+pub fn transpile(mem: &Memory, intcode_path: &str) -> String {
+    let instructions = cfg::analyze(mem).blocks.into_values()
+        .flat_map(|block| block.instructions)
+        .collect::<BTreeMap<_, _>>();
+    let written = instructions.values()
+        .filter(|instr| instr.op.has_output())
+        .map(|instr| instr.operands[instr.op.num_operands() - 1])
+        .filter(|dest| dest.mode == ArgMode::Position && dest.value >= 0)
+        .map(|dest| to_addr(dest.value))
+        .collect::<BTreeSet<_>>();
+    let compiled = instructions.iter()
+        .filter(|(&addr, instr)| written.range(addr..addr + instr.num_words()).next().is_none())
+        .filter_map(|(&addr, instr)| compile(addr, instr).map(|lines| (addr, instr, lines)))
+        .collect::<Vec<_>>();
+
+    let mut out = String::new();
+    writeln!(out, "// Generated by `intcode::transpile`. Do not edit.").unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "use {}::transpile::{{Native, State}};", intcode_path).unwrap();
+    writeln!(out, "use {}::{{Addr, Memory, Number}};", intcode_path).unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "pub const PROGRAM: &str = concat!(").unwrap();
+    let words = (0..mem.end()).map(|addr| mem[addr].to_string()).collect::<Vec<_>>();
+    let lines = words.chunks(WORDS_PER_LINE).map(|chunk| chunk.join(",")).collect::<Vec<_>>();
+    for (i, line) in lines.iter().enumerate() {
+        let separator = if i + 1 < lines.len() { "," } else { "" };
+        writeln!(out, "    \"{}{}\",", line, separator).unwrap();
+    }
+    writeln!(out, ");").unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "/// The address and length of every compiled instruction.").unwrap();
+    writeln!(out, "const COMPILED: &[(Addr, Addr)] = &[").unwrap();
+    for (addr, instr, _) in &compiled {
+        writeln!(out, "    ({}, {}),", addr, instr.num_words()).unwrap();
+    }
+    writeln!(out, "];").unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "pub fn new() -> Native {{").unwrap();
+    writeln!(out, "    Native::new(Memory::parse(PROGRAM), COMPILED, run)").unwrap();
+    writeln!(out, "}}").unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "fn run(s: &mut State) {{").unwrap();
+    writeln!(out, "    macro_rules! t {{").unwrap();
+    writeln!(out, "        ($e:expr) => {{ match $e {{ Some(val) => val, None => return }} }};").unwrap();
+    writeln!(out, "    }}").unwrap();
+    writeln!(out, "    loop {{").unwrap();
+    writeln!(out, "        match s.ip {{").unwrap();
+    for (addr, instr, lines) in &compiled {
+        writeln!(out, "            // {:04}: {}", addr, instr).unwrap();
+        writeln!(out, "            {} => {{", addr).unwrap();
+        for line in lines {
+            writeln!(out, "                {}", line).unwrap();
+        }
+        writeln!(out, "            }},").unwrap();
+    }
+    writeln!(out, "            _ => return,").unwrap();
+    writeln!(out, "        }}").unwrap();
+    writeln!(out, "    }}").unwrap();
+    writeln!(out, "}}").unwrap();
+    out
+}
+
+/// The machine state as seen by generated code. Every method returns `None` where the interpreter
+/// would fault, or where a store would modify compiled code.
+pub struct State<'a> {
+    mem: &'a mut Memory,
+    code: &'a [bool],
+    pub ip: Addr,
+    pub rb: Number,
+}
+
+impl State<'_> {
+    #[inline]
+    fn addr(&self, n: Number) -> Option<Addr> {
+        if n >= 0 && to_addr(n) < self.mem.limit() { Some(to_addr(n)) } else { None }
+    }
+
+    #[inline]
+    pub fn load(&self, addr: Number) -> Option<Number> {
+        self.addr(addr).map(|addr| self.mem[addr])
+    }
+
+    #[inline]
+    pub fn load_rel(&self, offset: Number) -> Option<Number> {
+        self.load(self.rel(offset)?)
+    }
+
+    #[inline]
+    pub fn rel(&self, offset: Number) -> Option<Number> {
+        self.rb.checked_add(offset)
+    }
+
+    #[inline]
+    pub fn target(&self, dest: Number) -> Option<Addr> {
+        self.addr(dest)
+    }
+
+    #[inline]
+    pub fn store(&mut self, addr: Number, val: Number) -> Option<()> {
+        let addr = self.addr(addr)?;
+        if self.code.get(addr) == Some(&true) {
+            return None;
+        }
+        self.mem[addr] = val;
+        Some(())
+    }
+}
+
+/// A tracer on the interpreted instructions, which notices when they write to compiled code.
+#[derive(Debug, Clone)]
+struct CodeGuard {
+    code: Arc<Vec<bool>>,
+    modified: bool,
+}
+
+impl Tracer for CodeGuard {
+    fn step(&mut self, step: &Step) {
+        if let Some(write) = step.write {
+            if self.code.get(write.addr) == Some(&true) {
+                self.modified = true;
+            }
+        }
+    }
+}
+
+/// A program compiled by `transpile`, which runs like a `Program`. Create one with the `new`
+/// function of the generated module. Compiled code is not used while fuel or a deadline is set.
+#[derive(Debug, Clone)]
+pub struct Native {
+    program: Program<CodeGuard>,
+    run: fn(&mut State),
+}
+
+impl Native {
+    /// Called by generated code. `compiled` lists the address and length of every instruction that
+    /// `run` can execute.
+    pub fn new(mem: Memory, compiled: &[(Addr, Addr)], run: fn(&mut State)) -> Self {
+        let mut code = vec![false; compiled.iter().map(|&(addr, len)| addr + len).max().unwrap_or(0)];
+        for &(addr, len) in compiled {
+            for cell in &mut code[addr..addr + len] {
+                *cell = true;
+            }
+        }
+        let program = Program::new(mem).with_tracer(CodeGuard { code: Arc::new(code), modified: false });
+        Native { program, run }
+    }
+
+    /// Whether compiled code is still used, which stops once the program modifies it.
+    pub fn is_native(&self) -> bool {
+        !self.program.tracer().modified
+    }
+
+    pub fn mem(&self) -> &Memory {
+        &self.program.mem
+    }
+
+    pub fn ip(&self) -> Addr {
+        self.program.ip()
+    }
+
+    pub fn relative_base(&self) -> Number {
+        self.program.relative_base()
+    }
+
+    pub fn into_program(self) -> Program {
+        self.program.with_tracer(NoTracer)
+    }
+
+    pub fn poke(&mut self, addr: Addr, val: Number) {
+        self.program.mem[addr] = val;
+        if self.program.tracer().code.get(addr) == Some(&true) {
+            self.program.tracer_mut().modified = true;
+        }
+    }
+
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.program.set_fuel(fuel);
+    }
+
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.program.set_deadline(deadline);
+    }
+
+    pub fn run_until_interrupt(&mut self) -> Interrupt {
+        self.try_run_until_interrupt().unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    pub fn try_run_until_interrupt(&mut self) -> Result<Interrupt, IntcodeError> {
+        let program = &mut self.program;
+        if let Some(fault) = &program.fault {
+            return Err(fault.clone());
+        }
+        loop {
+            if program.interrupt.is_none() && program.fuel.is_none() && program.deadline.is_none() && !program.tracer.modified {
+                let mut state = State { mem: &mut program.mem, code: &program.tracer.code, ip: program.ip, rb: program.relative_base };
+                (self.run)(&mut state);
+                program.ip = state.ip;
+                program.relative_base = state.rb;
+            }
+            // Compiled code stops at anything it cannot handle, which the interpreter executes.
+            if let Some(interrupt) = program.interrupt.or_else(|| program.use_budget()) {
+                return Ok(interrupt);
+            }
+            match program.execute() {
+                Ok(None) => {},
+                Ok(Some(interrupt)) => return Ok(interrupt),
+                Err(err) => return Err(program.set_fault(err)),
+            }
+        }
+    }
+
+    pub fn give_input(&mut self, val: Number) {
+        self.try_give_input(val).unwrap_or_else(|err| panic!("Attempted to give input: {}", err))
+    }
+
+    pub fn try_give_input(&mut self, val: Number) -> Result<(), IntcodeError> {
+        self.try_run_until_interrupt()?;
+        self.program.try_give_input(val)
+    }
+
+    pub fn take_output(&mut self) -> Number {
+        self.try_take_output().unwrap_or_else(|err| panic!("Attempted to take output: {}", err))
+    }
+
+    pub fn try_take_output(&mut self) -> Result<Number, IntcodeError> {
+        self.try_run_until_interrupt()?;
+        self.program.try_take_output()
+    }
+
+    pub fn run_with_io(&mut self, input: Vec<Number>) -> Vec<Number> {
+        self.try_run_with_io(input).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    pub fn try_run_with_io(&mut self, input: Vec<Number>) -> Result<Vec<Number>, IntcodeError> {
+        let mut input = input.into_iter();
+        let mut output = vec![];
+        loop {
+            match self.try_run_until_interrupt()? {
+                Interrupt::Reading => match input.next() {
+                    Some(val) => self.try_give_input(val)?,
+                    None => return Err(IntcodeError::InputExhausted { ip: self.program.cur_ip }),
+                },
+                Interrupt::Writing => output.push(self.try_take_output()?),
+                Interrupt::Halted => return Ok(output),
+                actual => return Err(IntcodeError::UnexpectedInterrupt { expected: Interrupt::Halted, actual }),
+            }
+        }
+    }
+}
+
+/// The program of `example.rs`, which is generated from it by `intcode_transpile --path
+/// crate::intcode`. It reads numbers until a zero, and outputs the Fibonacci number of each,
+/// computed recursively; then the sum of those, and a counter. The sum is accumulated by an
+/// instruction that is patched through a constant address, and a negative number patches the
+/// counter increment through the stack pointer.
+#[cfg(test)]
+const EXAMPLE: &str = "
+        ARB #stack
+loop:   IN -> [n]
+        JZ [n], #end
+        LT [n], #0 -> [tmp]
+        JNZ [tmp], #patch
+bump:   ADD #1, [count] -> [count]
+        PUSH [n]
+        CALL #fib
+        POP -> [result]
+        OUT [result]
+        ADD [result], #0 -> [sum+1]
+sum:    ADD #0, [total] -> [total]
+        JNZ #1, #loop
+patch:  MUL #stack, #-1 -> [delta]
+        ADD [delta], #bump+1 -> [delta]
+        ARB [delta]
+        MUL [n], #-1 -> [rb+0]
+        MUL [delta], #-1 -> [delta]
+        ARB [delta]
+        JNZ #1, #loop
+end:    OUT [total]
+        OUT [count]
+        HLT
+fib:    LT [rb-2], #2 -> [rb+0]
+        JZ [rb+0], #fib_rec
+        RET
+fib_rec: ADD [rb-2], #-1 -> [rb+0]
+        ARB #1
+        CALL #fib
+        ADD [rb-3], #-2 -> [rb+0]
+        ARB #1
+        CALL #fib
+        ADD [rb-2], [rb-1] -> [rb-4]
+        ARB #-2
+        RET
+n:      .data 0
+tmp:    .data 0
+result: .data 0
+delta:  .data 0
+total:  .data 0
+count:  .data 0
+stack:
+";
+
+#[cfg(test)]
+mod example;
+
+#[test]
+fn test_transpile_up_to_date() {
+    let mem = super::asm::assemble(EXAMPLE).unwrap();
+    assert_eq!(Memory::parse(example::PROGRAM), mem);
+    assert_eq!(transpile(&mem, "crate::intcode"), include_str!("transpile/example.rs"));
+}
+
+#[test]
+fn test_same_as_interpreter() {
+    for (input, native) in [(vec![10, 1, 0], true), (vec![7, -3, 12, 0], false), (vec![5], true), (vec![20, 0], true)] {
+        let mut interpreted = Program::parse(example::PROGRAM);
+        let mut compiled = example::new();
+        assert_eq!(compiled.try_run_with_io(input.clone()), interpreted.try_run_with_io(input.clone()), "input {:?}", input);
+        assert_eq!(compiled.is_native(), native, "input {:?}", input);
+        assert_eq!(compiled.ip(), interpreted.ip());
+        assert_eq!(compiled.relative_base(), interpreted.relative_base());
+        assert_eq!(compiled.mem(), &interpreted.mem);
+    }
+    assert_eq!(example::new().run_with_io(vec![10, 1, 0]), vec![55, 1, 56, 2]);
+    assert_eq!(example::new().run_with_io(vec![7, -3, 12, 0]), vec![13, 144, 157, 4]);
+}
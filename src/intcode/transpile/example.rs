@@ -0,0 +1,222 @@
+// Generated by `intcode::transpile`. Do not edit.
+
+use crate::intcode::transpile::{Native, State};
+use crate::intcode::{Addr, Memory, Number};
+
+pub const PROGRAM: &str = concat!(
+    "109,139,3,133,1006,133,75,1007,133,0,134,1005,134,52,101,1,",
+    "138,138,21001,133,0,0,109,1,21101,33,0,0,109,1,1105,1,",
+    "80,1201,-1,0,135,109,-1,4,135,1001,135,0,46,101,0,137,",
+    "137,1105,1,2,1102,139,-1,136,1001,136,15,136,9,136,21002,133,",
+    "-1,0,1002,136,-1,136,9,136,1105,1,2,4,137,4,138,99,",
+    "21207,-2,2,0,1206,0,92,109,-1,2106,0,0,21201,-2,-1,0,",
+    "109,1,21101,107,0,0,109,1,1105,1,80,21201,-3,-2,0,109,",
+    "1,21101,122,0,0,109,1,1105,1,80,22201,-2,-1,-4,109,-2,",
+    "109,-1,2106",
+);
+
+/// The address and length of every compiled instruction.
+const COMPILED: &[(Addr, Addr)] = &[
+    (0, 2),
+    (4, 3),
+    (7, 4),
+    (11, 3),
+    (14, 4),
+    (18, 4),
+    (22, 2),
+    (24, 4),
+    (28, 2),
+    (30, 3),
+    (52, 4),
+    (56, 4),
+    (60, 2),
+    (62, 4),
+    (66, 4),
+    (70, 2),
+    (72, 3),
+    (80, 4),
+    (84, 3),
+    (87, 2),
+    (89, 3),
+    (92, 4),
+    (96, 2),
+    (98, 4),
+    (102, 2),
+    (104, 3),
+];
+
+pub fn new() -> Native {
+    Native::new(Memory::parse(PROGRAM), COMPILED, run)
+}
+
+fn run(s: &mut State) {
+    macro_rules! t {
+        ($e:expr) => { match $e { Some(val) => val, None => return } };
+    }
+    loop {
+        match s.ip {
+            // 0000: ARB #139
+            0 => {
+                let a: Number = 139;
+                s.rb = t!(s.rb.checked_add(a));
+                s.ip = 2;
+            },
+            // 0004: JZ [133], #75
+            4 => {
+                let a: Number = t!(s.load(133));
+                s.ip = if a == 0 { 75 } else { 7 };
+            },
+            // 0007: LT [133], #0 -> [134]
+            7 => {
+                let a: Number = t!(s.load(133));
+                let b: Number = 0;
+                t!(s.store(134, Number::from(a < b)));
+                s.ip = 11;
+            },
+            // 0011: JNZ [134], #52
+            11 => {
+                let a: Number = t!(s.load(134));
+                s.ip = if a != 0 { 52 } else { 14 };
+            },
+            // 0014: ADD #1, [138] -> [138]
+            14 => {
+                let a: Number = 1;
+                let b: Number = t!(s.load(138));
+                t!(s.store(138, t!(a.checked_add(b))));
+                s.ip = 18;
+            },
+            // 0018: ADD [133], #0 -> [rb+0]
+            18 => {
+                let a: Number = t!(s.load(133));
+                let b: Number = 0;
+                t!(s.store(t!(s.rel(0)), t!(a.checked_add(b))));
+                s.ip = 22;
+            },
+            // 0022: ARB #1
+            22 => {
+                let a: Number = 1;
+                s.rb = t!(s.rb.checked_add(a));
+                s.ip = 24;
+            },
+            // 0024: ADD #33, #0 -> [rb+0]
+            24 => {
+                let a: Number = 33;
+                let b: Number = 0;
+                t!(s.store(t!(s.rel(0)), t!(a.checked_add(b))));
+                s.ip = 28;
+            },
+            // 0028: ARB #1
+            28 => {
+                let a: Number = 1;
+                s.rb = t!(s.rb.checked_add(a));
+                s.ip = 30;
+            },
+            // 0030: JNZ #1, #80
+            30 => {
+                let a: Number = 1;
+                s.ip = if a != 0 { 80 } else { 33 };
+            },
+            // 0052: MUL #139, #-1 -> [136]
+            52 => {
+                let a: Number = 139;
+                let b: Number = -1;
+                t!(s.store(136, t!(a.checked_mul(b))));
+                s.ip = 56;
+            },
+            // 0056: ADD [136], #15 -> [136]
+            56 => {
+                let a: Number = t!(s.load(136));
+                let b: Number = 15;
+                t!(s.store(136, t!(a.checked_add(b))));
+                s.ip = 60;
+            },
+            // 0060: ARB [136]
+            60 => {
+                let a: Number = t!(s.load(136));
+                s.rb = t!(s.rb.checked_add(a));
+                s.ip = 62;
+            },
+            // 0062: MUL [133], #-1 -> [rb+0]
+            62 => {
+                let a: Number = t!(s.load(133));
+                let b: Number = -1;
+                t!(s.store(t!(s.rel(0)), t!(a.checked_mul(b))));
+                s.ip = 66;
+            },
+            // 0066: MUL [136], #-1 -> [136]
+            66 => {
+                let a: Number = t!(s.load(136));
+                let b: Number = -1;
+                t!(s.store(136, t!(a.checked_mul(b))));
+                s.ip = 70;
+            },
+            // 0070: ARB [136]
+            70 => {
+                let a: Number = t!(s.load(136));
+                s.rb = t!(s.rb.checked_add(a));
+                s.ip = 72;
+            },
+            // 0072: JNZ #1, #2
+            72 => {
+                let a: Number = 1;
+                s.ip = if a != 0 { 2 } else { 75 };
+            },
+            // 0080: LT [rb-2], #2 -> [rb+0]
+            80 => {
+                let a: Number = t!(s.load_rel(-2));
+                let b: Number = 2;
+                t!(s.store(t!(s.rel(0)), Number::from(a < b)));
+                s.ip = 84;
+            },
+            // 0084: JZ [rb+0], #92
+            84 => {
+                let a: Number = t!(s.load_rel(0));
+                s.ip = if a == 0 { 92 } else { 87 };
+            },
+            // 0087: ARB #-1
+            87 => {
+                let a: Number = -1;
+                s.rb = t!(s.rb.checked_add(a));
+                s.ip = 89;
+            },
+            // 0089: JZ #0, [rb+0]
+            89 => {
+                let a: Number = 0;
+                let b: Number = t!(s.load_rel(0));
+                s.ip = if a == 0 { t!(s.target(b)) } else { 92 };
+            },
+            // 0092: ADD [rb-2], #-1 -> [rb+0]
+            92 => {
+                let a: Number = t!(s.load_rel(-2));
+                let b: Number = -1;
+                t!(s.store(t!(s.rel(0)), t!(a.checked_add(b))));
+                s.ip = 96;
+            },
+            // 0096: ARB #1
+            96 => {
+                let a: Number = 1;
+                s.rb = t!(s.rb.checked_add(a));
+                s.ip = 98;
+            },
+            // 0098: ADD #107, #0 -> [rb+0]
+            98 => {
+                let a: Number = 107;
+                let b: Number = 0;
+                t!(s.store(t!(s.rel(0)), t!(a.checked_add(b))));
+                s.ip = 102;
+            },
+            // 0102: ARB #1
+            102 => {
+                let a: Number = 1;
+                s.rb = t!(s.rb.checked_add(a));
+                s.ip = 104;
+            },
+            // 0104: JNZ #1, #80
+            104 => {
+                let a: Number = 1;
+                s.ip = if a != 0 { 80 } else { 107 };
+            },
+            _ => return,
+        }
+    }
+}